@@ -4,8 +4,8 @@ use std::{env, process::Command, time::SystemTime};
 fn main() {
     /* git attributes */
     let profile = env::var("PROFILE").unwrap();
-    let output = Command::new("git").args(&["rev-parse", "--short=10", "HEAD"]).output().unwrap();
-    let output_full = Command::new("git").args(&["rev-parse", "HEAD"]).output().unwrap();
+    let output = Command::new("git").args(["rev-parse", "--short=10", "HEAD"]).output().unwrap();
+    let output_full = Command::new("git").args(["rev-parse", "HEAD"]).output().unwrap();
 
     println!("cargo:rustc-env=TARGET={}", env::var("TARGET").unwrap());
     println!("cargo:rustc-env=GIT_HASH={}", String::from_utf8(output.stdout).unwrap());
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN login_session text NOT NULL DEFAULT '';
+DROP TABLE sessions;
\ No newline at end of file
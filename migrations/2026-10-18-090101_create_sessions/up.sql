@@ -0,0 +1,12 @@
+CREATE TABLE sessions (
+   id text PRIMARY KEY NOT NULL,
+   user_id integer NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+   created_at TIMESTAMP NOT NULL,
+   last_seen TIMESTAMP NOT NULL,
+   expires_at TIMESTAMP NOT NULL,
+   ip text,
+   user_agent text
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
+ALTER TABLE users DROP COLUMN login_session;
@@ -0,0 +1,8 @@
+CREATE TABLE user_totp (
+  user_id integer PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  secret text NOT NULL,
+  enabled boolean NOT NULL DEFAULT FALSE,
+  last_step bigint NOT NULL DEFAULT 0,
+  recovery_codes text[] NOT NULL DEFAULT '{}',
+  created_at timestamp NOT NULL DEFAULT now()
+);
@@ -0,0 +1,12 @@
+CREATE TABLE webauthn_credentials (
+  id text PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  user_handle text NOT NULL,
+  name text NOT NULL,
+  passkey text NOT NULL,
+  created_at timestamp NOT NULL DEFAULT now(),
+  last_used timestamp
+);
+
+CREATE INDEX webauthn_credentials_user_id_idx ON webauthn_credentials (user_id);
+CREATE INDEX webauthn_credentials_user_handle_idx ON webauthn_credentials (user_handle);
@@ -0,0 +1,6 @@
+CREATE TABLE services (
+  name text PRIMARY KEY,
+  location jsonb NOT NULL,
+  created_at timestamp NOT NULL DEFAULT now(),
+  updated_at timestamp NOT NULL DEFAULT now()
+);
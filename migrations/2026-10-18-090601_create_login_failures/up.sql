@@ -0,0 +1,17 @@
+CREATE TABLE login_failures (
+  id serial PRIMARY KEY,
+  account text NOT NULL,
+  ip text,
+  cleared boolean NOT NULL DEFAULT FALSE,
+  created_at timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX login_failures_account ON login_failures (account, created_at) WHERE NOT cleared;
+CREATE INDEX login_failures_ip ON login_failures (ip, created_at);
+
+CREATE TABLE lockouts (
+  account text PRIMARY KEY,
+  failures integer NOT NULL,
+  locked_until timestamp NOT NULL,
+  created_at timestamp NOT NULL DEFAULT now()
+);
@@ -0,0 +1,8 @@
+CREATE TABLE rate_limits (
+  service text NOT NULL,
+  key text NOT NULL,
+  tokens double precision NOT NULL,
+  allowed boolean NOT NULL DEFAULT TRUE,
+  updated_at timestamp NOT NULL DEFAULT now(),
+  PRIMARY KEY (service, key)
+);
@@ -0,0 +1,8 @@
+DROP INDEX login_history_user;
+ALTER TABLE login_history
+  DROP COLUMN success,
+  DROP COLUMN reason,
+  DROP COLUMN ip,
+  DROP COLUMN user_agent,
+  DROP COLUMN method,
+  DROP COLUMN session_id;
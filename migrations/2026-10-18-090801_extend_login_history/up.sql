@@ -0,0 +1,10 @@
+ALTER TABLE login_history
+  ADD COLUMN success boolean NOT NULL DEFAULT TRUE,
+  ADD COLUMN reason text,
+  ADD COLUMN ip text,
+  ADD COLUMN user_agent text,
+  ADD COLUMN method text NOT NULL DEFAULT 'unknown',
+  ADD COLUMN session_id text;
+
+ALTER TABLE login_history ALTER COLUMN success DROP DEFAULT, ALTER COLUMN method DROP DEFAULT;
+CREATE INDEX login_history_user ON login_history (user_id, login_timestamp);
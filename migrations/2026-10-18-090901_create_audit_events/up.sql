@@ -0,0 +1,11 @@
+CREATE TABLE audit_events (
+  id bigserial PRIMARY KEY,
+  created_at timestamp NOT NULL,
+  actor text,
+  action text NOT NULL,
+  target text,
+  ip text,
+  details jsonb NOT NULL DEFAULT '{}',
+  prev_hash text NOT NULL,
+  hash text NOT NULL
+);
@@ -0,0 +1,5 @@
+DELETE FROM login_history WHERE user_id IS NULL;
+ALTER TABLE login_history
+  DROP CONSTRAINT login_history_user_id_fkey,
+  ADD CONSTRAINT login_history_user_id_fkey FOREIGN KEY (user_id) REFERENCES users(id),
+  ALTER COLUMN user_id SET NOT NULL;
@@ -0,0 +1,4 @@
+ALTER TABLE login_history
+  ALTER COLUMN user_id DROP NOT NULL,
+  DROP CONSTRAINT login_history_user_id_fkey,
+  ADD CONSTRAINT login_history_user_id_fkey FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE SET NULL;
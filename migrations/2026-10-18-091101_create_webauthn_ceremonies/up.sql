@@ -0,0 +1,7 @@
+CREATE TABLE webauthn_ceremonies (
+  id text PRIMARY KEY,
+  ceremony text NOT NULL,
+  expires_at timestamp NOT NULL
+);
+
+CREATE INDEX webauthn_ceremonies_expires_at_idx ON webauthn_ceremonies (expires_at);
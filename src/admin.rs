@@ -0,0 +1,392 @@
+use diesel::result::Error as DieselError;
+use macros_rs::string;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+use crate::{
+    audit,
+    auth::middleware::Admin,
+    config::{
+        db::{Connection, Pool},
+        live::Live,
+        routes::Routes,
+        structs::{Config, Location, Source},
+    },
+    http::{balance::Balancer, errors::JsonError},
+    models::{
+        failure::{Lockout, LoginFailure},
+        history::LoginHistory,
+        service::Service,
+        totp::Totp,
+        user::{SignupError, User, UserDTO, UserUpdateDTO},
+    },
+};
+
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json, Path, Query},
+    HttpRequest, HttpResponse,
+};
+
+#[derive(Deserialize)]
+pub struct NewUser {
+    pub username: String,
+    pub email: String,
+    #[serde(default)]
+    pub password: String,
+    #[serde(default)]
+    pub admin: bool,
+    #[serde(default)]
+    pub services: Vec<String>,
+    #[serde(default = "default_providers")]
+    pub providers: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct Listed<'a> {
+    source: Source,
+    #[serde(flatten)]
+    location: &'a Location,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    pub user_id: Option<i32>,
+    /// Only successful, or only failed, attempts
+    pub success: Option<bool>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct HistoryEntry {
+    #[serde(flatten)]
+    entry: LoginHistory,
+    /// None once the user has been deleted
+    username: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct FailureQuery {
+    pub account: Option<String>,
+    pub limit: Option<i64>,
+}
+
+fn default_providers() -> Vec<String> { vec!["basic".into()] }
+
+fn db_error(err: DieselError) -> JsonError {
+    match err {
+        DieselError::NotFound => JsonError {
+            status: 404,
+            message: "User not found".into(),
+        },
+        err => {
+            tracing::error!(err = err.to_string(), "admin request failed");
+            JsonError {
+                status: 500,
+                message: "Unable to complete the request".into(),
+            }
+        }
+    }
+}
+
+/// The fields an update sets, with the password left out.
+fn changed(changes: &UserUpdateDTO) -> Value {
+    let mut details = json!({
+        "admin": changes.admin,
+        "username": changes.username,
+        "email": changes.email,
+        "password_changed": changes.password.as_ref().map(|_| true),
+        "providers": changes.providers,
+        "services": changes.services,
+        "disabled": changes.disabled,
+    });
+
+    if let Some(fields) = details.as_object_mut() {
+        fields.retain(|_, value| !value.is_null());
+    }
+
+    details
+}
+
+fn not_self(admin: &Admin, user_id: i32, message: &'static str) -> Result<(), JsonError> {
+    match admin.0.id == user_id {
+        true => Err(JsonError { status: 400, message: message.into() }),
+        false => Ok(()),
+    }
+}
+
+pub async fn list_users(req: HttpRequest, _admin: Admin, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+
+    let users = User::all(&mut pool.get().unwrap()).map_err(db_error)?;
+    Ok(HttpResponse::Ok().json(users))
+}
+
+pub async fn get_user(req: HttpRequest, _admin: Admin, user_id: Path<i32>, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+
+    let user = User::find(*user_id, &mut pool.get().unwrap()).map_err(db_error)?;
+    Ok(HttpResponse::Ok().json(user))
+}
+
+pub async fn create_user(req: HttpRequest, admin: Admin, body: Json<NewUser>, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+
+    let body = body.into_inner();
+    if body.username.is_empty() || body.email.is_empty() {
+        return Err(JsonError {
+            status: 400,
+            message: "Username and email are required".into(),
+        });
+    }
+
+    let user_dto = UserDTO {
+        admin: body.admin,
+        username: body.username.to_lowercase(),
+        email: body.email.to_lowercase(),
+        password: body.password,
+        tokens: vec![],
+        services: body.services,
+        providers: body.providers,
+    };
+
+    let conn = &mut pool.get().unwrap();
+    match User::signup(user_dto, conn) {
+        Ok(user) => {
+            let details = json!({ "id": user.id, "email": user.email, "admin": user.admin, "services": user.services, "providers": user.providers });
+            audit::record(&req, Some(&admin.0.username), "user.create", Some(&user.username), details, conn);
+
+            tracing::info!(admin = admin.0.username, user = user.username, "created user");
+            Ok(HttpResponse::build(StatusCode::CREATED).json(user))
+        }
+        Err(SignupError::Database(err)) => {
+            tracing::error!(err = err.to_string(), "unable to create user");
+            Err(JsonError {
+                status: 500,
+                message: "Unable to create user".into(),
+            })
+        }
+        Err(err) => Err(JsonError {
+            status: 409,
+            message: err.to_string().into(),
+        }),
+    }
+}
+
+pub async fn update_user(req: HttpRequest, admin: Admin, user_id: Path<i32>, body: Json<UserUpdateDTO>, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+
+    let changes = body.into_inner();
+    if changes.admin == Some(false) || changes.disabled == Some(true) {
+        not_self(&admin, *user_id, "You cannot remove your own access")?;
+    }
+
+    if changes.password.as_ref().is_some_and(|password| password.is_empty()) {
+        return Err(JsonError {
+            status: 400,
+            message: "Password cannot be empty".into(),
+        });
+    }
+
+    let details = changed(&changes);
+    let conn = &mut pool.get().unwrap();
+    let user = User::update(*user_id, changes, conn).map_err(db_error)?;
+    audit::record(&req, Some(&admin.0.username), "user.update", Some(&user.username), details, conn);
+
+    tracing::info!(admin = admin.0.username, user = user.username, "updated user");
+    Ok(HttpResponse::Ok().json(user))
+}
+
+pub async fn disable_user(req: HttpRequest, admin: Admin, user_id: Path<i32>, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+    not_self(&admin, *user_id, "You cannot disable your own account")?;
+
+    let conn = &mut pool.get().unwrap();
+    let user = User::set_disabled(*user_id, true, conn).map_err(db_error)?;
+    audit::record(&req, Some(&admin.0.username), "user.disable", Some(&user.username), json!({ "id": user.id }), conn);
+
+    tracing::info!(admin = admin.0.username, user = user.username, "disabled user");
+    Ok(HttpResponse::Ok().json(user))
+}
+
+pub async fn enable_user(req: HttpRequest, admin: Admin, user_id: Path<i32>, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+
+    let conn = &mut pool.get().unwrap();
+    let user = User::set_disabled(*user_id, false, conn).map_err(db_error)?;
+    audit::record(&req, Some(&admin.0.username), "user.enable", Some(&user.username), json!({ "id": user.id }), conn);
+
+    tracing::info!(admin = admin.0.username, user = user.username, "enabled user");
+    Ok(HttpResponse::Ok().json(user))
+}
+
+pub async fn delete_user(req: HttpRequest, admin: Admin, user_id: Path<i32>, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+    not_self(&admin, *user_id, "You cannot delete your own account")?;
+
+    let conn = &mut pool.get().unwrap();
+    let user = User::find(*user_id, conn).map_err(db_error)?;
+
+    match User::delete(user.id, conn).map_err(db_error)? {
+        0 => Err(db_error(DieselError::NotFound)),
+        _ => {
+            audit::record(&req, Some(&admin.0.username), "user.delete", Some(&user.username), json!({ "id": user.id, "email": user.email }), conn);
+            tracing::info!(admin = admin.0.username, user_id = *user_id, "deleted user");
+            Ok(HttpResponse::NoContent().finish())
+        }
+    }
+}
+
+pub async fn reset_totp(req: HttpRequest, admin: Admin, user_id: Path<i32>, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+
+    let conn = &mut pool.get().unwrap();
+    let user = User::find(*user_id, conn).map_err(db_error)?;
+    Totp::delete(user.id, conn).map_err(db_error)?;
+    audit::record(&req, Some(&admin.0.username), "user.reset_totp", Some(&user.username), json!({ "id": user.id }), conn);
+
+    tracing::info!(admin = admin.0.username, user = user.username, "reset two-factor authentication");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn list_backends(req: HttpRequest, _admin: Admin, config: Live<Config>) -> HttpResponse {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+
+    let backends: BTreeMap<&String, Listed> = config.backends.iter().map(|(name, item)| (name, Listed { source: item.source, location: item })).collect();
+    HttpResponse::Ok().json(backends)
+}
+
+/// Services in the config file belong to whoever manages the file, only stored ones can change here.
+fn stored_only(name: &str, config: &Config) -> Result<(), JsonError> {
+    match config.backends.get(name).is_some_and(|item| item.source == Source::File) {
+        true => Err(JsonError {
+            status: 409,
+            message: "This service is defined in the config file and is read-only".into(),
+        }),
+        false => Ok(()),
+    }
+}
+
+/// Loads the config again so the stored services are merged into it.
+fn reload_services() {
+    // a full channel means a reload is already pending, which reads the services too
+    if let Some(reload) = crate::RELOAD.get() {
+        let _ = reload.try_send(crate::Reload::Config);
+    }
+}
+
+pub async fn save_service(req: HttpRequest, admin: Admin, name: Path<String>, body: Json<Location>, config: Live<Config>, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+    stored_only(&name, &config)?;
+
+    let item = body.into_inner();
+    let problems = config.check_backend(&name, &item);
+
+    if !problems.is_empty() {
+        return Err(JsonError {
+            status: 400,
+            message: problems.join(", ").into(),
+        });
+    }
+
+    let location = serde_json::to_value(&item).map_err(|err| JsonError {
+        status: 500,
+        message: err.to_string().into(),
+    })?;
+    let conn = &mut pool.get().unwrap();
+    Service::save(&name, location.clone(), conn).map_err(db_error)?;
+    audit::record(&req, Some(&admin.0.username), "service.save", Some(&name), location, conn);
+    reload_services();
+
+    tracing::info!(admin = admin.0.username, service = name.as_str(), "saved service");
+    Ok(HttpResponse::Ok().json(Listed { source: Source::Db, location: &item }))
+}
+
+pub async fn delete_service(req: HttpRequest, admin: Admin, name: Path<String>, config: Live<Config>, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+    stored_only(&name, &config)?;
+
+    let conn = &mut pool.get().unwrap();
+    match Service::delete(&name, conn).map_err(db_error)? {
+        0 => Err(JsonError {
+            status: 404,
+            message: "Service not found".into(),
+        }),
+        _ => {
+            audit::record(&req, Some(&admin.0.username), "service.delete", Some(&name), json!({}), conn);
+            reload_services();
+            tracing::info!(admin = admin.0.username, service = name.as_str(), "deleted service");
+            Ok(HttpResponse::NoContent().finish())
+        }
+    }
+}
+
+pub async fn backend_health(req: HttpRequest, _admin: Admin, routes: Live<Routes>, balancer: Data<Balancer>) -> HttpResponse {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+    HttpResponse::Ok().json(balancer.report(&routes))
+}
+
+fn find_history(user_id: Option<i32>, query: &HistoryQuery, conn: &mut Connection) -> Result<HttpResponse, JsonError> {
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    let history = LoginHistory::find(user_id, query.success, limit, conn).map_err(db_error)?;
+    let entries: Vec<HistoryEntry> = history.into_iter().map(|(entry, username)| HistoryEntry { entry, username }).collect();
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+pub async fn login_history(req: HttpRequest, _admin: Admin, query: Query<HistoryQuery>, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+    find_history(query.user_id, &query, &mut pool.get().unwrap())
+}
+
+pub async fn user_history(req: HttpRequest, _admin: Admin, user_id: Path<i32>, query: Query<HistoryQuery>, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+
+    let conn = &mut pool.get().unwrap();
+    let user = User::find(*user_id, conn).map_err(db_error)?;
+    find_history(Some(user.id), &query, conn)
+}
+
+pub async fn login_failures(req: HttpRequest, _admin: Admin, query: Query<FailureQuery>, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    let failures = LoginFailure::find(query.account.as_deref(), limit, &mut pool.get().unwrap()).map_err(db_error)?;
+
+    Ok(HttpResponse::Ok().json(failures))
+}
+
+pub async fn list_lockouts(req: HttpRequest, _admin: Admin, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+
+    let lockouts = Lockout::all(&mut pool.get().unwrap()).map_err(db_error)?;
+    Ok(HttpResponse::Ok().json(lockouts))
+}
+
+/// Unlocks the account and clears its failures, so it starts over with a full allowance.
+pub async fn clear_lockout(req: HttpRequest, admin: Admin, account: Path<String>, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "admin '{}'", req.uri());
+
+    let conn = &mut pool.get().unwrap();
+    let unlocked = Lockout::delete(&account, conn).map_err(db_error)?;
+    let cleared = LoginFailure::clear(&account, conn).map_err(db_error)?;
+
+    if unlocked == 0 && cleared == 0 {
+        return Err(JsonError {
+            status: 404,
+            message: "No failed sign ins for this account".into(),
+        });
+    }
+
+    audit::record(
+        &req,
+        Some(&admin.0.username),
+        "lockout.clear",
+        Some(&account),
+        json!({ "unlocked": unlocked > 0, "failures": cleared }),
+        conn,
+    );
+    tracing::info!(admin = admin.0.username, account = account.as_str(), failures = cleared, "cleared failed sign ins");
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -1,10 +1,12 @@
-use macros_rs::{str, string};
+use macros_rs::string;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tera::Context;
 use toml_edit::{value, Array};
 
 use crate::{
-    config::{db::Pool, structs::Config},
+    audit,
+    config::{db::Pool, live::Live, structs::Config},
     http::{
         errors::{Error, JsonError},
         token,
@@ -62,7 +64,7 @@ pub struct Service {
     pub tls: bool,
 }
 
-pub async fn dashboard(req: HttpRequest, pool: Data<Pool>, config: Data<Config>) -> Result<HttpResponse, Error> {
+pub async fn dashboard(req: HttpRequest, pool: Data<Pool>, config: Live<Config>) -> Result<HttpResponse, Error> {
     tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
 
     if let Some(cookie) = req.cookie("sp_token") {
@@ -73,16 +75,16 @@ pub async fn dashboard(req: HttpRequest, pool: Data<Pool>, config: Data<Config>)
         }
 
         Err(Error::InternalError {
-            message: "Error while processing token, please try again!",
+            message: "Error while processing token, please try again!".into(),
         })
     } else {
         Err(Error::BadClientData {
-            message: "Token missing from request",
+            message: "Token missing from request".into(),
         })
     }
 }
 
-pub async fn setup(req: HttpRequest, tera: Data<TeraState>, config: Data<Config>) -> HttpResponse {
+pub async fn setup(req: HttpRequest, tera: Data<TeraState>, config: Live<Config>) -> HttpResponse {
     tracing::info!(method = string!(req.method()), "setup '{}'", req.uri());
 
     send!().body(render("setup", &tera.get_ref().0, &mut Context::new(), config.as_ref()))
@@ -121,7 +123,7 @@ pub async fn setup_handler(req: HttpRequest, body: Json<Setup>, pool: Data<Pool>
 
         edit["backends"][service.name.clone()]["port"] = value(port);
         edit["backends"][service.name.clone()]["providers"] = value(Array::default());
-        edit["backends"][service.name.clone()]["tls"] = value(service.tls.clone());
+        edit["backends"][service.name.clone()]["tls"] = value(service.tls);
         edit["backends"][service.name.clone()]["address"] = value(service.address.clone());
         edit["backends"][service.name.clone()]["display_name"] = value(service.display.clone());
     }
@@ -129,11 +131,16 @@ pub async fn setup_handler(req: HttpRequest, body: Json<Setup>, pool: Data<Pool>
     config.set(Config::from_str(&edit.to_string()));
     config.set_path(path).create_dirs().write();
 
-    match User::signup(user_dto, &mut pool.get().unwrap()) {
-        Ok(_) => Ok(ok!().finish()),
+    let conn = &mut pool.get().unwrap();
+    match User::signup(user_dto, conn) {
+        Ok(user) => {
+            let details = json!({ "prefix": body.settings.prefix, "service": body.service.as_ref().map(|service| &service.name) });
+            audit::record(&req, Some(&user.username), "setup", Some(path), details, conn);
+            Ok(ok!().finish())
+        }
         Err(err) => Err(JsonError {
             status: 500,
-            message: str!(err.clone()),
+            message: err.to_string().into(),
         }),
     }
 }
@@ -0,0 +1,312 @@
+use diesel::QueryResult;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    env,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{db::Connection, live::Live, structs::Config},
+    http::address,
+    models::audit::{AuditEvent, NewAuditEvent, GENESIS},
+};
+
+use actix_web::HttpRequest;
+
+/// Events read at a time when walking the chain.
+pub const PAGE_SIZE: i64 = 1000;
+
+/// Denials of the same actor, action and target within this long of the
+/// last one written are only counted.
+const DENIED_WINDOW: Duration = Duration::from_secs(60);
+
+/// Denials tracked before the ones outside their window are dropped.
+const DENIED_TRACKED: usize = 4096;
+
+static DENIED: Lazy<Mutex<Denials>> = Lazy::new(|| Mutex::new(Denials::default()));
+
+/// When each kind of denial was last written, and how often it came back since.
+#[derive(Default)]
+struct Denials(HashMap<(String, String, Option<String>), (Instant, u64)>);
+
+impl Denials {
+    /// The repeats to note on an event that should be written now, `None` when
+    /// this one is only counted.
+    fn admit(&mut self, key: (String, String, Option<String>), now: Instant) -> Option<u64> {
+        if self.0.len() >= DENIED_TRACKED {
+            self.0.retain(|_, (since, _)| now.duration_since(*since) < DENIED_WINDOW);
+        }
+
+        match self.0.get_mut(&key) {
+            Some((since, repeats)) if now.duration_since(*since) < DENIED_WINDOW => {
+                *repeats += 1;
+                None
+            }
+            Some(entry) => Some(std::mem::replace(entry, (now, 0)).1),
+            None => {
+                self.0.insert(key, (now, 0));
+                Some(0)
+            }
+        }
+    }
+}
+
+/// A chain that checked out, `head` is the hash of its latest event.
+pub struct Verified {
+    pub events: u64,
+    pub head: String,
+}
+
+impl Verified {
+    /// Takes in the next event if it is chained to the last one and matches its hash.
+    fn follow(&mut self, event: AuditEvent) -> Result<(), Broken> {
+        if event.prev_hash != self.head {
+            return Err(Broken {
+                id: event.id,
+                problem: "does not follow the event before it, one was changed or removed",
+            });
+        }
+
+        if event.expected_hash() != event.hash {
+            return Err(Broken {
+                id: event.id,
+                problem: "does not match its hash, it was changed",
+            });
+        }
+
+        self.events += 1;
+        self.head = event.hash;
+        Ok(())
+    }
+}
+
+/// The first event that does not match the chain.
+pub struct Broken {
+    pub id: i64,
+    pub problem: &'static str,
+}
+
+/// Records an event for a request. A failed write is logged, it never fails
+/// the request it describes.
+pub(crate) fn record(req: &HttpRequest, actor: Option<&str>, action: &str, target: Option<&str>, details: Value, conn: &mut Connection) {
+    let event = NewAuditEvent {
+        actor: actor.map(String::from),
+        action: action.to_string(),
+        target: target.map(String::from),
+        ip: address::client_ip(req, Live::<Config>::from_req(req).get_ref()).map(|ip| ip.to_string()),
+        details,
+    };
+
+    if let Err(err) = AuditEvent::record(event, conn) {
+        tracing::error!(err = err.to_string(), action, "unable to write audit event");
+    }
+}
+
+/// Records an event for something done from the command line, by the system
+/// user that ran it. Unlike [`record`] a failed write is returned, so the
+/// command can say the change went unaudited.
+pub fn record_local(action: &str, target: Option<&str>, details: Value, conn: &mut Connection) -> QueryResult<AuditEvent> {
+    let user = env::var("USER").or_else(|_| env::var("LOGNAME")).unwrap_or_else(|_| String::from("unknown"));
+    let event = NewAuditEvent {
+        actor: Some(format!("cli:{user}")),
+        action: action.to_string(),
+        target: target.map(String::from),
+        ip: None,
+        details,
+    };
+
+    AuditEvent::record(event, conn)
+}
+
+/// Records a request that was turned away like [`record`], but writes one event
+/// per actor, action and target a minute. Writes queue behind the chain lock,
+/// so a client retrying in a loop must not be able to hold up every other
+/// event. Repeats in between are counted in the next event's `repeats`.
+pub(crate) fn record_denied(req: &HttpRequest, actor: &str, action: &str, target: Option<&str>, mut details: Value, conn: &mut Connection) {
+    let key = (actor.to_string(), action.to_string(), target.map(String::from));
+    let Some(repeats) = DENIED.lock().admit(key, Instant::now()) else {
+        return;
+    };
+
+    if let (Value::Object(map), true) = (&mut details, repeats > 0) {
+        map.insert("repeats".into(), repeats.into());
+    }
+
+    record(req, Some(actor), action, target, details, conn);
+}
+
+/// Walks the chain from the first event. Removing events from the end cannot
+/// be told apart from them never happening, compare `head` with one noted
+/// earlier, or with the last exported event, to catch that.
+pub fn verify(conn: &mut Connection) -> QueryResult<Result<Verified, Broken>> {
+    let mut verified = Verified { events: 0, head: GENESIS.to_string() };
+    let mut after = 0;
+
+    loop {
+        let page = AuditEvent::page(after, PAGE_SIZE, conn)?;
+        let Some(last) = page.last() else {
+            return Ok(Ok(verified));
+        };
+        after = last.id;
+
+        for event in page {
+            if let Err(broken) = verified.follow(event) {
+                return Ok(Err(broken));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use serde_json::json;
+
+    fn event(id: i64, prev_hash: &str) -> AuditEvent {
+        let mut event = AuditEvent {
+            id,
+            created_at: NaiveDate::from_ymd_opt(2026, 10, 18).unwrap().and_hms_micro_opt(9, 0, id as u32, 123456).unwrap(),
+            actor: Some(String::from("admin")),
+            action: String::from("user.update"),
+            target: Some(format!("user{id}")),
+            ip: Some(String::from("127.0.0.1")),
+            details: json!({ "id": id, "admin": true, "services": ["app", "lb"] }),
+            prev_hash: prev_hash.to_string(),
+            hash: String::new(),
+        };
+
+        event.hash = event.expected_hash();
+        event
+    }
+
+    fn chain(count: i64) -> Vec<AuditEvent> {
+        let mut events: Vec<AuditEvent> = vec![];
+        for id in 1..=count {
+            let prev = events.last().map_or(GENESIS, |event| event.hash.as_str()).to_string();
+            events.push(event(id, &prev));
+        }
+        events
+    }
+
+    fn check(events: Vec<AuditEvent>) -> Result<Verified, Broken> {
+        let mut verified = Verified { events: 0, head: GENESIS.to_string() };
+        for event in events {
+            verified.follow(event)?;
+        }
+        Ok(verified)
+    }
+
+    fn broken_at(events: Vec<AuditEvent>) -> i64 {
+        match check(events) {
+            Ok(_) => panic!("chain verified"),
+            Err(broken) => broken.id,
+        }
+    }
+
+    #[test]
+    fn intact_chain_verifies() {
+        let events = chain(3);
+        let head = events[2].hash.clone();
+
+        let verified = check(events).ok().unwrap();
+        assert_eq!(verified.events, 3);
+        assert_eq!(verified.head, head);
+    }
+
+    #[test]
+    fn modified_row_is_detected() {
+        let mut events = chain(3);
+        events[1].details["admin"] = json!(false);
+        assert_eq!(broken_at(events), 2);
+
+        let mut events = chain(3);
+        events[1].actor = None;
+        assert_eq!(broken_at(events), 2);
+    }
+
+    #[test]
+    fn rehashed_row_breaks_the_next_link() {
+        let mut events = chain(3);
+        events[1].target = Some(String::from("mallory"));
+        events[1].hash = events[1].expected_hash();
+        assert_eq!(broken_at(events), 3);
+    }
+
+    #[test]
+    fn reordered_rows_are_detected() {
+        let mut events = chain(3);
+        events.swap(1, 2);
+        assert_eq!(broken_at(events), 3);
+    }
+
+    #[test]
+    fn broken_prev_link_is_detected() {
+        let mut events = chain(3);
+        events[1].prev_hash = GENESIS.to_string();
+        assert_eq!(broken_at(events), 2);
+
+        let mut events = chain(3);
+        events.remove(1);
+        assert_eq!(broken_at(events), 3);
+    }
+
+    #[test]
+    fn hash_ignores_how_details_come_back() {
+        let mut stored = event(1, GENESIS);
+        stored.details = serde_json::from_str(r#"{ "services": ["app", "lb"], "admin": true, "id": 1.0 }"#).unwrap();
+        assert_eq!(stored.expected_hash(), event(1, GENESIS).hash);
+    }
+
+    #[test]
+    fn fields_do_not_bleed_into_each_other() {
+        let mut split = event(1, GENESIS);
+        split.actor = Some(String::from("adminuser."));
+        split.action = String::from("update");
+        assert_ne!(split.expected_hash(), event(1, GENESIS).hash);
+
+        let mut moved = event(1, GENESIS);
+        moved.ip = moved.target.take();
+        assert_ne!(moved.expected_hash(), event(1, GENESIS).hash);
+    }
+
+    fn key(target: &str) -> (String, String, Option<String>) { (String::from("carol"), String::from("access.denied"), Some(target.to_string())) }
+
+    #[test]
+    fn repeats_within_the_window_are_counted() {
+        let mut denials = Denials::default();
+        let start = Instant::now();
+
+        assert_eq!(denials.admit(key("app"), start), Some(0));
+        assert_eq!(denials.admit(key("app"), start + Duration::from_secs(1)), None);
+        assert_eq!(denials.admit(key("app"), start + Duration::from_secs(59)), None);
+        assert_eq!(denials.admit(key("app"), start + DENIED_WINDOW), Some(2));
+        assert_eq!(denials.admit(key("app"), start + DENIED_WINDOW + Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn targets_are_counted_apart() {
+        let mut denials = Denials::default();
+        let now = Instant::now();
+
+        assert_eq!(denials.admit(key("app"), now), Some(0));
+        assert_eq!(denials.admit(key("lb"), now), Some(0));
+        assert_eq!(denials.admit((String::from("dave"), String::from("access.denied"), Some(String::from("app"))), now), Some(0));
+    }
+
+    #[test]
+    fn stale_denials_are_dropped_when_full() {
+        let mut denials = Denials::default();
+        let start = Instant::now();
+
+        for n in 0..DENIED_TRACKED {
+            denials.admit(key(&n.to_string()), start);
+        }
+
+        denials.admit(key("fresh"), start + DENIED_WINDOW);
+        assert_eq!(denials.0.len(), 1);
+    }
+}
@@ -1,19 +1,36 @@
+pub mod assertion;
+pub mod forward;
 pub mod middleware;
+pub mod oauth;
+pub mod totp;
+pub mod webauthn;
 
+use chrono::Utc;
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
 use macros_rs::string;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tera::Context;
+use url::{Position, Url};
 
 use crate::{
-    config::db::Pool,
-    config::structs::Config,
+    audit,
+    config::db::{Connection, Pool},
+    config::{live::Live, routes::Routes, structs::Config},
     http::{
+        address,
         errors::{Error, JsonError},
-        token,
+        metrics::METRICS,
+        select_service, token,
     },
     models::{
+        failure::{Lockout, LoginFailure},
+        history::LoginHistory,
+        session::SessionInfo,
         token::UserToken,
+        totp::Totp,
         user::{LoginDTO, User},
+        webauthn::WebauthnCredential,
     },
     pages::{render, TeraState},
 };
@@ -21,8 +38,11 @@ use crate::{
 use actix_web::{
     cookie::{time::Duration, Cookie},
     dev::ConnectionInfo,
-    http::{header::ContentType, StatusCode},
-    web::{Data, Json},
+    http::{
+        header::{self, ContentType},
+        StatusCode,
+    },
+    web::{Data, Json, Query},
     HttpRequest, HttpResponse,
 };
 
@@ -33,6 +53,36 @@ pub struct Login {
     remember: bool,
 }
 
+#[derive(Deserialize)]
+pub struct LoginPage {
+    redirect: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Ticket {
+    exp: i64,
+    aud: String,
+    sub: String,
+    pub(crate) remember: bool,
+    pub(crate) enroll: bool,
+}
+
+#[derive(Serialize)]
+pub struct Challenge {
+    second_factor: &'static str,
+    methods: Vec<&'static str>,
+    ticket: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Logout {
+    #[serde(default)]
+    all: bool,
+}
+
+const TICKET_AUDIENCE: &str = "second-factor";
+const TICKET_MAX_AGE: i64 = 300;
+
 macro_rules! send {
     () => {
         HttpResponse::build(StatusCode::OK).content_type(ContentType::html())
@@ -47,92 +97,428 @@ macro_rules! ok {
 
 fn remove_suffix<'a>(s: &'a str, suffix: &str) -> &'a str { s.split(suffix).next().unwrap_or(s) }
 
-pub async fn login(req: HttpRequest, config: Data<Config>, tera: Data<TeraState>) -> HttpResponse {
+pub(crate) fn session_info(req: &HttpRequest, method: &str, config: &Config) -> SessionInfo {
+    SessionInfo {
+        ip: address::client_ip(req, config).map(|ip| ip.to_string()),
+        user_agent: req.headers().get(header::USER_AGENT).and_then(|agent| agent.to_str().ok()).map(String::from),
+        max_age: config.settings.max_age,
+        method: method.to_string(),
+    }
+}
+
+pub(crate) fn session_cookie(conn: &ConnectionInfo, token: String, remember: bool) -> Cookie<'static> {
+    let cookie_builder = Cookie::build("sp_token", token)
+        .domain(remove_suffix(conn.host(), ":").to_string())
+        .secure(conn.scheme() == "https")
+        .path("/")
+        .http_only(true);
+
+    match remember {
+        true => cookie_builder.max_age(Duration::seconds(604800)).finish(),
+        false => cookie_builder.expires(None).finish(),
+    }
+}
+
+/// Signs the user in with `method`, the factor that completed the sign in, recorded in the login history.
+pub(crate) fn start_session(req: &HttpRequest, user: &User, method: &str, mfa: bool, remember: bool, conn: &mut Connection, config: &Config) -> Result<Cookie<'static>, JsonError> {
+    match User::create_session(user, &session_info(req, method, config), conn) {
+        Some(login_info) => {
+            METRICS.login(method, true);
+            if let Err(err) = LoginFailure::clear(&user.username, conn) {
+                tracing::error!(err = err.to_string(), "unable to clear failed sign ins");
+            }
+            // a step up replaces the session it started from
+            if let Some((previous, _)) = password_session(req, conn, config).filter(|(_, previous)| previous.id == user.id) {
+                if let Err(err) = User::logout(&previous, false, conn) {
+                    tracing::error!(err = err.to_string(), "unable to end the stepped up session");
+                }
+            }
+
+            let token = UserToken::generate_token(&login_info, method, mfa, config);
+            Ok(session_cookie(&req.connection_info(), token, remember))
+        }
+        None => Err(JsonError {
+            status: 500,
+            message: "Unable to create a session, please try again.".into(),
+        }),
+    }
+}
+
+/// The live session a request carries when it was signed in with a password
+/// alone, which can step up to a second factor without the password again.
+pub(crate) fn password_session(req: &HttpRequest, conn: &mut Connection, config: &Config) -> Option<(UserToken, User)> {
+    let claims = token::decode_token(middleware::request_token(req)?, config).ok()?.claims;
+    if !claims.password_only() {
+        return None;
+    }
+
+    let user = User::find_user_by_token(&claims, conn).ok()?;
+    Some((claims, user))
+}
+
+/// The account a sign in names, and what its failures count against: the
+/// username, so its username and email share one count, or the identifier
+/// itself when no account matches.
+pub(crate) fn find_account(identifier: &str, conn: &mut Connection) -> (String, Option<User>) {
+    let identifier = identifier.trim().to_lowercase();
+
+    match User::find_user_by_username(&identifier, conn).or_else(|_| User::find_user_by_email(&identifier, conn)) {
+        Ok(user) => (user.username.clone(), Some(user)),
+        Err(_) => (identifier, None),
+    }
+}
+
+/// Adds a failed sign in to the user's login history.
+pub(crate) fn log_failure(req: &HttpRequest, user: &User, method: &str, reason: &str, conn: &mut Connection, config: &Config) {
+    METRICS.login(method, false);
+    if let Err(err) = LoginHistory::failure(user.id, &session_info(req, method, config), reason, conn) {
+        tracing::error!(err = err.to_string(), "unable to save login history");
+    }
+}
+
+fn limits_error(err: diesel::result::Error) -> JsonError {
+    tracing::error!(err = err.to_string(), "unable to check sign in limits");
+    JsonError {
+        status: 500,
+        message: "Unable to sign in right now, please try again.".into(),
+    }
+}
+
+fn locked_out() -> JsonError {
+    JsonError {
+        status: 429,
+        message: "Too many failed sign ins, this account is temporarily locked.".into(),
+    }
+}
+
+/// Turns away locked accounts, and addresses with too many recent failures,
+/// before any credential is checked. Attempts on a known `user` are kept in
+/// its login history.
+pub(crate) fn check_limits(req: &HttpRequest, account: &str, user: Option<&User>, method: &str, conn: &mut Connection, config: &Config) -> Result<(), JsonError> {
+    if Lockout::active(account, conn).map_err(limits_error)?.is_some() {
+        tracing::warn!(account, "sign in to locked account");
+        if let Some(user) = user {
+            log_failure(req, user, method, "account locked", conn, config);
+        }
+
+        return Err(locked_out());
+    }
+
+    check_address(req, user, method, conn, config)
+}
+
+/// Turns away addresses with too many recent failures, on its own for sign
+/// ins that only learn the account from the credential.
+pub(crate) fn check_address(req: &HttpRequest, user: Option<&User>, method: &str, conn: &mut Connection, config: &Config) -> Result<(), JsonError> {
+    let limits = &config.settings.login;
+
+    if let Some(ip) = address::client_ip(req, config).map(|ip| ip.to_string()) {
+        if LoginFailure::count_ip(&ip, limits.window, conn).map_err(limits_error)? >= limits.max_per_ip as i64 {
+            tracing::warn!(ip, "too many failed sign ins from address");
+            if let Some(user) = user {
+                log_failure(req, user, method, "too many failures from address", conn, config);
+            }
+
+            return Err(JsonError {
+                status: 429,
+                message: "Too many failed sign ins, please try again later.".into(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Records a failed sign in and locks the account once it reaches
+/// `max_failures` in the window. Returns how long to hold the answer back,
+/// doubling with every failure, and the error to answer with.
+pub(crate) fn record_failure(req: &HttpRequest, account: &str, conn: &mut Connection, config: &Config, error: JsonError) -> (std::time::Duration, JsonError) {
+    let limits = &config.settings.login;
+    let ip = address::client_ip(req, config).map(|ip| ip.to_string());
+
+    if let Err(err) = LoginFailure::record(account, ip.clone(), conn) {
+        tracing::error!(err = err.to_string(), "unable to record failed sign in");
+    }
+
+    let failures = LoginFailure::count_account(account, limits.window, conn).unwrap_or(1).max(1);
+    tracing::warn!(account, ip, failures, "failed sign in");
+
+    let error = match failures >= limits.max_failures as i64 {
+        false => error,
+        true => match Lockout::lock(account, failures as i32, limits.lockout, conn) {
+            Ok(lockout) => {
+                tracing::warn!(account, failures, until = lockout.locked_until.to_string(), "account locked");
+                locked_out()
+            }
+            Err(err) => {
+                tracing::error!(err = err.to_string(), account, "unable to lock account");
+                error
+            }
+        },
+    };
+
+    let delay = limits.delay.saturating_mul(1 << (failures - 1).min(16)).min(limits.max_delay);
+    (std::time::Duration::from_millis(delay), error)
+}
+
+pub(crate) fn second_factors(user_id: i32, conn: &mut Connection) -> Vec<&'static str> {
+    let mut methods = vec![];
+
+    if Totp::is_enabled(user_id, conn) {
+        methods.push("totp");
+    }
+    if WebauthnCredential::exists(user_id, conn) {
+        methods.push("webauthn");
+    }
+
+    methods
+}
+
+/// Issued after a correct password when a second factor is still needed; the
+/// ticket stands in for the password on the follow-up request. Users without
+/// any second factor get an `enroll` ticket that lets them set up TOTP.
+fn challenge(user: &User, methods: Vec<&'static str>, remember: bool, config: &Config) -> Challenge {
+    let ticket = Ticket {
+        exp: Utc::now().timestamp() + TICKET_MAX_AGE,
+        aud: string!(TICKET_AUDIENCE),
+        sub: user.username.clone(),
+        enroll: methods.is_empty(),
+        remember,
+    };
+
+    Challenge {
+        second_factor: methods.first().copied().unwrap_or("enroll"),
+        ticket: jsonwebtoken::encode(&Header::default(), &ticket, &EncodingKey::from_secret(config.settings.secret.as_bytes())).unwrap(),
+        methods,
+    }
+}
+
+pub(crate) fn redeem_ticket(ticket: &str, conn: &mut Connection, config: &Config) -> Result<(User, Ticket), JsonError> {
+    let mut validation = Validation::default();
+    validation.set_audience(&[TICKET_AUDIENCE]);
+
+    let expired = || JsonError {
+        status: 401,
+        message: "Your sign in has expired, please start again.".into(),
+    };
+
+    let ticket = jsonwebtoken::decode::<Ticket>(ticket, &DecodingKey::from_secret(config.settings.secret.as_bytes()), &validation).map_err(|_| expired())?;
+
+    match User::find_user_by_username(&ticket.claims.sub, conn) {
+        Ok(user) if !user.disabled => Ok((user, ticket.claims)),
+        _ => Err(expired()),
+    }
+}
+
+/// Where to send the user once they are signed in: a local path, or an http
+/// or https URL on a host a backend answers for, which is where forward auth
+/// sends them from. Anything else goes to `/`, so a crafted link cannot bounce
+/// users off to another site.
+pub(crate) fn safe_redirect(redirect: Option<&str>, routes: &Routes) -> String {
+    let Some(redirect) = redirect else {
+        return string!("/");
+    };
+
+    // local paths go through the same parser against a placeholder origin, so
+    // both come out percent encoded, and the login page embeds them in a JSON string
+    let local = Url::parse("http://localhost").expect("placeholder origin is valid");
+    let target = match redirect.starts_with('/') && !redirect.starts_with("//") && !redirect.starts_with("/\\") {
+        true => local
+            .join(redirect)
+            .ok()
+            .filter(|url| url.origin() == local.origin())
+            .map(|url| url[Position::BeforePath..].to_string()),
+        false => Url::parse(redirect)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https") && url.host_str().is_some_and(|host| routes.serves_host(host)))
+            .map(String::from),
+    };
+
+    target.map_or_else(|| string!("/"), |target| target.replace('\\', "%5C"))
+}
+
+pub async fn login(req: HttpRequest, query: Query<LoginPage>, pool: Data<Pool>, config: Live<Config>, routes: Live<Routes>, tera: Data<TeraState>) -> HttpResponse {
     tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
 
     let config = config.get_ref();
     let tera = tera.get_ref();
     let mut page = Context::new();
+    page.insert("redirect", &safe_redirect(query.redirect.as_deref(), &routes));
+    page.insert("step_up", &password_session(&req, &mut pool.get().unwrap(), config).is_some());
 
     // use display name from config
-    match req.headers().get("SelectService") {
+    match select_service(&req, &routes) {
         None => page.insert("service_name", "(no service selected)"),
-        Some(name) => page.insert("service_name", name.to_str().unwrap_or("(service name error)")),
+        Some(name) => page.insert("service_name", name),
     };
 
     send!().body(render("login", &tera.0, &mut page, config))
 }
 
-pub async fn login_handler(req: HttpRequest, conn: ConnectionInfo, body: Json<Login>, pool: Data<Pool>, config: Data<Config>) -> Result<HttpResponse, JsonError> {
+pub async fn login_handler(req: HttpRequest, body: Json<Login>, pool: Data<Pool>, config: Live<Config>, routes: Live<Routes>) -> Result<HttpResponse, JsonError> {
     tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
 
-    let email = body.email.to_lowercase();
-    let password = body.password.clone();
-    let remember = body.remember.clone();
-
-    let login_dto = LoginDTO { password, username_or_email: email };
-
-    match User::login(login_dto, &mut pool.get().unwrap()) {
-        Some(logged_user) => {
-            let token = UserToken::generate_token(&logged_user, config.as_ref());
+    let config = config.as_ref();
+    let mut conn = pool.get().unwrap();
+    let login_dto = LoginDTO {
+        password: body.password.clone(),
+        username_or_email: body.email.to_lowercase(),
+    };
 
-            if logged_user.login_session.is_empty() {
-                Err(JsonError {
-                    status: 401,
-                    message: "Wrong username or password, please try again.",
-                })
-            } else {
-                let cookie_builder = Cookie::build("sp_token", token).domain(remove_suffix(conn.host(), ":")).secure(false).path("/").http_only(true);
+    let (account, known) = find_account(&body.email, &mut conn);
+    check_limits(&req, &account, known.as_ref(), "password", &mut conn, config)?;
 
-                let cookie = match remember {
-                    true => cookie_builder.max_age(Duration::seconds(604800)).finish(),
-                    false => cookie_builder.expires(None).finish(),
-                };
+    let Some(user) = User::authenticate(&login_dto, &mut conn) else {
+        if let Some(user) = &known {
+            let reason = match (user.disabled, user.password.is_empty()) {
+                (true, _) => "account disabled",
+                (_, true) => "no password set",
+                _ => "wrong password",
+            };
 
-                Ok(ok!().cookie(cookie).finish())
-            }
+            log_failure(&req, user, "password", reason, &mut conn, config);
+        } else {
+            METRICS.login("password", false);
         }
-        None => Err(JsonError {
+
+        let wrong = JsonError {
             status: 401,
-            message: "Wrong username or password, please try again.",
-        }),
+            message: "Wrong username or password, please try again.".into(),
+        };
+
+        let (delay, error) = record_failure(&req, &account, &mut conn, config, wrong);
+        drop(conn);
+
+        tokio::time::sleep(delay).await;
+        return Err(error);
+    };
+
+    let conn = &mut conn;
+
+    let methods = second_factors(user.id, conn);
+    if !methods.is_empty() || config.requires_2fa(select_service(&req, &routes)) {
+        tracing::info!(user = user.username, methods = methods.join(","), "second factor required");
+        return Ok(HttpResponse::Accepted().json(challenge(&user, methods, body.remember, config)));
     }
+
+    let cookie = start_session(&req, &user, "password", false, body.remember, conn, config)?;
+    Ok(ok!().cookie(cookie).finish())
 }
 
-pub async fn logout(req: HttpRequest, tera: Data<TeraState>, config: Data<Config>) -> HttpResponse {
+/// Starts the second factor for a session signed in with a password alone,
+/// answering like a password sign in that needs one.
+pub async fn step_up(req: HttpRequest, pool: Data<Pool>, config: Live<Config>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let config = config.as_ref();
+    let conn = &mut pool.get().unwrap();
+
+    let Some((_, user)) = password_session(&req, conn, config) else {
+        return Err(JsonError {
+            status: 401,
+            message: "Sign in with your password first.".into(),
+        });
+    };
+
+    let methods = second_factors(user.id, conn);
+    tracing::info!(user = user.username, methods = methods.join(","), "second factor step up");
+
+    // browsers do not send the cookie's lifetime back, so the new session lasts until the browser closes
+    Ok(HttpResponse::Accepted().json(challenge(&user, methods, false, config)))
+}
+
+pub async fn logout(req: HttpRequest, tera: Data<TeraState>, config: Live<Config>, routes: Live<Routes>) -> HttpResponse {
     tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
 
     let tera = tera.get_ref();
     let mut page = Context::new();
 
     // use display name from config
-    match req.headers().get("SelectService") {
+    match select_service(&req, &routes) {
         None => page.insert("service_name", "(no service selected)"),
-        Some(name) => page.insert("service_name", name.to_str().unwrap_or("(service name error)")),
+        Some(name) => page.insert("service_name", name),
     };
 
     send!().body(render("logout", &tera.0, &mut page, config.as_ref()))
 }
 
-pub async fn logout_handler(req: HttpRequest, pool: Data<Pool>, config: Data<Config>) -> Result<HttpResponse, Error> {
+pub async fn logout_handler(req: HttpRequest, query: Query<Logout>, pool: Data<Pool>, config: Live<Config>) -> Result<HttpResponse, Error> {
     tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
 
     if let Some(cookie) = req.cookie("sp_token") {
         if let Ok(token_data) = token::decode_token(cookie.value().to_string(), config.as_ref()) {
-            if let Ok(username) = token::verify_token(&token_data, &pool) {
-                if let Ok(user) = User::find_user_by_username(&username, &mut pool.get().unwrap()) {
-                    User::logout(user.id, &mut pool.get().unwrap());
-                    return Ok(ok!().finish());
-                }
+            let conn = &mut pool.get().unwrap();
+            if User::logout(&token_data.claims, query.all, conn).is_ok() {
+                let claims = &token_data.claims;
+                audit::record(&req, Some(&claims.user), "logout", Some(&claims.login_session), json!({ "everywhere": query.all }), conn);
+
+                let mut cookie = Cookie::build("sp_token", "").domain(remove_suffix(req.connection_info().host(), ":").to_string()).path("/").finish();
+                cookie.make_removal();
+
+                tracing::info!(user = token_data.claims.user, everywhere = query.all, "logout");
+                return Ok(ok!().cookie(cookie).finish());
             }
         }
 
         Err(Error::InternalError {
-            message: "Error while processing token, please try again!",
+            message: "Error while processing token, please try again!".into(),
         })
     } else {
         Err(Error::BadClientData {
-            message: "Token missing from request",
+            message: "Token missing from request".into(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::structs::Location;
+
+    fn routes() -> Routes {
+        let mut config = Config::new();
+        let backend = r#"
+            display_name = "App"
+            providers = []
+            address = "127.0.0.1"
+            port = 3000
+            hosts = ["app.test", "*.apps.test"]
+        "#;
+
+        config.backends.insert(string!("app"), toml::from_str::<Location>(backend).unwrap());
+        Routes::new(&config).unwrap()
+    }
+
+    #[test]
+    fn safe_redirect_keeps_local_paths() {
+        let routes = routes();
+
+        assert_eq!(safe_redirect(Some("/app?tab=1"), &routes), "/app?tab=1");
+        assert_eq!(safe_redirect(Some("//evil.test/"), &routes), "/");
+        assert_eq!(safe_redirect(Some("/\\evil.test"), &routes), "/");
+        assert_eq!(safe_redirect(Some("app"), &routes), "/");
+        assert_eq!(safe_redirect(Some("/\t/evil.test/"), &routes), "/");
+        assert_eq!(safe_redirect(None, &routes), "/");
+    }
+
+    #[test]
+    fn safe_redirect_allows_backend_hosts_only() {
+        let routes = routes();
+
+        assert_eq!(safe_redirect(Some("https://app.test/path?q=1"), &routes), "https://app.test/path?q=1");
+        assert_eq!(safe_redirect(Some("http://APP.test:8080/"), &routes), "http://app.test:8080/");
+        assert_eq!(safe_redirect(Some("https://one.apps.test/"), &routes), "https://one.apps.test/");
+        assert_eq!(safe_redirect(Some("https://evil.test/"), &routes), "/");
+        assert_eq!(safe_redirect(Some("https://app.test.evil.test/"), &routes), "/");
+        assert_eq!(safe_redirect(Some("https://two.levels.apps.test/"), &routes), "/");
+        assert_eq!(safe_redirect(Some("javascript://app.test/%0aalert(1)"), &routes), "/");
+    }
+
+    #[test]
+    fn safe_redirect_encodes_quotes_and_backslashes() {
+        let routes = routes();
+
+        assert_eq!(safe_redirect(Some("/app?q=\"x\\"), &routes), "/app?q=%22x%5C");
+        assert_eq!(safe_redirect(Some("https://app.test/?q=\"x\\"), &routes), "https://app.test/?q=%22x%5C");
+    }
+}
@@ -0,0 +1,206 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use macros_rs::string;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{live::Live, structs::Config},
+    http::errors::JsonError,
+    models::{token::UserToken, user::User},
+};
+
+use actix_web::{http::header, web::Path, HttpMessage, HttpRequest, HttpResponse};
+
+const ASSERTION_MAX_AGE: i64 = 60;
+
+/// PKCS#8 wrapping for a bare Ed25519 seed, see RFC 8410.
+const PKCS8_PREFIX: [u8; 16] = [0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20];
+
+#[derive(Serialize)]
+struct Assertion<'a> {
+    iss: &'a str,
+    aud: &'a str,
+    sub: &'a str,
+    email: &'a str,
+    admin: bool,
+    sid: &'a str,
+    mfa: bool,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Serialize)]
+struct Jwk {
+    kty: &'static str,
+    crv: &'static str,
+    alg: &'static str,
+    #[serde(rename = "use")]
+    usage: &'static str,
+    kid: String,
+    x: String,
+}
+
+#[derive(Serialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+/// Every backend signs with its own Ed25519 key derived from `settings.secret`,
+/// so all instances agree on it and nothing has to be stored.
+fn seed(service: &str, config: &Config) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(config.settings.secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("zerotrust-assertion:{service}").as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+fn jwk(service: &str, config: &Config) -> Jwk {
+    let pair = Ed25519KeyPair::from_seed_unchecked(&seed(service, config)).expect("seed is 32 bytes");
+    let x = URL_SAFE_NO_PAD.encode(pair.public_key().as_ref());
+
+    // RFC 7638 thumbprint
+    let thumbprint = format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{x}"}}"#);
+
+    Jwk {
+        kty: "OKP",
+        crv: "Ed25519",
+        alg: "EdDSA",
+        usage: "sig",
+        kid: URL_SAFE_NO_PAD.encode(Sha256::digest(thumbprint.as_bytes())),
+        x,
+    }
+}
+
+fn issuer(config: &Config) -> &str { config.settings.server.external_url.as_deref().unwrap_or("zerotrust") }
+
+fn sign(user: &User, claims: &UserToken, service: &str, config: &Config) -> String {
+    let now = Utc::now().timestamp();
+    let assertion = Assertion {
+        iss: issuer(config),
+        aud: service,
+        sub: &user.username,
+        email: &user.email,
+        admin: user.admin,
+        sid: &claims.login_session,
+        mfa: claims.mfa,
+        iat: now,
+        exp: now + ASSERTION_MAX_AGE,
+    };
+
+    let mut header = Header::new(Algorithm::EdDSA);
+    header.kid = Some(jwk(service, config).kid);
+
+    let key = EncodingKey::from_ed_der(&[&PKCS8_PREFIX[..], &seed(service, config)].concat());
+    jsonwebtoken::encode(&header, &assertion, &key).expect("derived Ed25519 key is valid")
+}
+
+/// Whether a header is one zerotrust sets itself, and must never be taken from the client.
+pub(crate) fn is_identity_header(name: &str, config: &Config) -> bool { config.settings.identity.fields().iter().any(|(_, header)| !header.is_empty() && header.eq_ignore_ascii_case(name)) }
+
+/// Builds the identity headers for a request that went through
+/// [`super::middleware::Authentication`], including the assertion when the backend asks for one.
+pub(crate) fn identity_headers<'a>(req: &HttpRequest, service: &str, config: &'a Config) -> Vec<(&'a str, String)> {
+    let extensions = req.extensions();
+    match (extensions.get::<User>(), extensions.get::<UserToken>()) {
+        (Some(user), Some(claims)) => headers_for(user, claims, Some(service), config),
+        _ => vec![],
+    }
+}
+
+/// The identity headers for `user`, named as `settings.identity` says. Forward
+/// auth passes them back to the proxy in front, which may not know the service.
+pub(crate) fn headers_for<'a>(user: &User, claims: &UserToken, service: Option<&str>, config: &'a Config) -> Vec<(&'a str, String)> {
+    let identity = &config.settings.identity;
+    let groups = match user.admin {
+        true => [vec![string!("admin")], user.services.clone()].concat(),
+        false => user.services.clone(),
+    };
+
+    let mut headers = vec![
+        (identity.user.as_str(), user.username.clone()),
+        (identity.email.as_str(), user.email.clone()),
+        (identity.admin.as_str(), user.admin.to_string()),
+        (identity.groups.as_str(), groups.join(",")),
+        (identity.session.as_str(), claims.login_session.clone()),
+    ];
+
+    if let Some(service) = service.filter(|service| config.backends.get(*service).is_some_and(|item| item.assertion)) {
+        headers.push((identity.assertion.as_str(), sign(user, claims, service, config)));
+    }
+
+    headers.retain(|(name, _)| !name.is_empty());
+    headers
+}
+
+pub async fn jwks(req: HttpRequest, path: Path<String>, config: Live<Config>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let service = path.into_inner();
+    if !config.backends.get(&service).is_some_and(|item| item.assertion) {
+        return Err(JsonError {
+            status: 404,
+            message: "This service does not use signed assertions.".into(),
+        });
+    }
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "public, max-age=3600"))
+        .json(Jwks { keys: vec![jwk(&service, &config)] }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(admin: bool) -> (User, UserToken) {
+        let user = User {
+            id: 1,
+            admin,
+            username: string!("ada"),
+            email: string!("ada@example.com"),
+            password: string!(),
+            providers: vec![],
+            services: vec![string!("app"), string!("lb")],
+            tokens: vec![],
+            disabled: false,
+        };
+
+        let claims = UserToken {
+            iat: 0,
+            exp: 0,
+            user: string!("ada"),
+            login_session: string!("session"),
+            method: string!("basic"),
+            mfa: false,
+        };
+
+        (user, claims)
+    }
+
+    #[test]
+    fn headers_follow_identity_settings() {
+        let mut config = Config::new();
+        config.settings.identity.user = string!("Remote-User");
+        config.settings.identity.groups = string!("Remote-Groups");
+        config.settings.identity.session = string!();
+
+        let (user, claims) = user(true);
+        let headers = headers_for(&user, &claims, None, &config);
+
+        assert!(headers.contains(&("Remote-User", string!("ada"))));
+        assert!(headers.contains(&("Remote-Groups", string!("admin,app,lb"))));
+        assert!(headers.contains(&("X-Auth-Email", string!("ada@example.com"))));
+        assert!(!headers.iter().any(|(name, _)| name.is_empty() || *name == "X-Auth-Session"));
+    }
+
+    #[test]
+    fn groups_leave_out_admin_for_users() {
+        let (user, claims) = user(false);
+        let config = Config::new();
+
+        assert!(headers_for(&user, &claims, None, &config).contains(&("X-Auth-Groups", string!("app,lb"))));
+    }
+}
@@ -0,0 +1,198 @@
+use macros_rs::string;
+use serde::Deserialize;
+use url::{form_urlencoded::byte_serialize, Url};
+
+use super::{
+    assertion,
+    middleware::{authorize, request_token, Denied},
+    oauth,
+};
+use crate::config::{db::Pool, live::Live, routes::Routes, structs::Config};
+
+use actix_web::{
+    http::header,
+    web::{Data, Query},
+    HttpRequest, HttpResponse,
+};
+
+#[derive(Deserialize)]
+pub struct VerifyQuery {
+    /// Overrides the response for unauthenticated requests: `true` always
+    /// redirects, `false` always answers 401.
+    redirect: Option<bool>,
+}
+
+fn forwarded<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> { req.headers().get(name).and_then(|value| value.to_str().ok()) }
+
+/// Rebuilds the URL the user originally asked for. nginx passes it whole as
+/// `X-Original-URL`, Traefik splits it over the `X-Forwarded-*` headers.
+fn original_url(req: &HttpRequest) -> Option<Url> {
+    if let Some(url) = forwarded(req, "X-Original-URL").and_then(|url| Url::parse(url).ok()) {
+        return Some(url);
+    }
+
+    let info = req.connection_info();
+    let uri = forwarded(req, "X-Forwarded-Uri").or(forwarded(req, "X-Original-URI")).unwrap_or("/");
+
+    Url::parse(&format!("{}://{}{uri}", info.scheme(), info.host())).ok()
+}
+
+/// Where to sign in, `login` or `oauth/{provider}` under the prefix, coming
+/// back to the original URL afterwards.
+fn sign_in_url(path: &str, original: Option<&Url>, config: &Config) -> String {
+    let prefix = &config.settings.server.prefix;
+    let base = match (&config.settings.server.external_url, original) {
+        (Some(external), _) => external.trim_end_matches('/').to_string(),
+        (None, Some(url)) => url.origin().ascii_serialization(),
+        (None, None) => string!(),
+    };
+
+    match original {
+        Some(url) => format!("{base}/{prefix}/{path}?redirect={}", byte_serialize(url.as_str().as_bytes()).collect::<String>()),
+        None => format!("{base}/{prefix}/{path}"),
+    }
+}
+
+/// Answers forward auth subrequests from nginx `auth_request` or Traefik
+/// `forwardAuth`, applying the same checks as [`super::middleware::Authentication`]
+/// to the service the original URL routes to.
+pub async fn verify(req: HttpRequest, query: Query<VerifyQuery>, pool: Data<Pool>, config: Live<Config>, routes: Live<Routes>) -> HttpResponse {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let config = config.as_ref();
+    let original = original_url(&req);
+
+    let service = match &original {
+        Some(url) => routes.route(url.host_str(), url.path(), forwarded(&req, "SelectService")),
+        None => None,
+    };
+
+    let location = match request_token(&req) {
+        Some(token) => match authorize(&req, &token, service, original.as_ref().map_or("/", |url| url.path()), pool.as_ref(), config) {
+            Ok((claims, user)) => {
+                // backends limited to providers turn away sessions from the others, as the proxy does
+                let providers = service.and_then(|name| routes.get(name)).map_or(&[][..], |backend| backend.providers.as_slice());
+
+                match oauth::required_provider(&claims, providers, config) {
+                    Ok(()) => {
+                        let mut res = HttpResponse::Ok();
+                        for header in assertion::headers_for(&user, &claims, service, config) {
+                            res.insert_header(header);
+                        }
+
+                        return res.finish();
+                    }
+                    Err(Some(provider)) => sign_in_url(&format!("oauth/{provider}"), original.as_ref(), config),
+                    Err(None) => return HttpResponse::Forbidden().body(oauth::WRONG_PROVIDER),
+                }
+            }
+            Err(Denied::Forbidden { message, .. }) => return HttpResponse::Forbidden().body(message),
+            Err(Denied::StepUp | Denied::Unauthenticated) => sign_in_url("login", original.as_ref(), config),
+        },
+        None => sign_in_url("login", original.as_ref(), config),
+    };
+
+    // nginx only understands 401 from auth_request, Traefik hands the response straight to the browser
+    let browser = forwarded(&req, "X-Forwarded-Uri").is_some() && !req.headers().contains_key(header::AUTHORIZATION);
+
+    match query.redirect.unwrap_or(browser) {
+        true => HttpResponse::Found().insert_header((header::LOCATION, location)).finish(),
+        false => HttpResponse::Unauthorized().insert_header((header::LOCATION, location)).finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{
+            db,
+            live::{Shared, Snapshot},
+            structs::{Location, Provider},
+        },
+        models::{
+            session::SessionInfo,
+            token::UserToken,
+            user::{User, UserDTO},
+        },
+    };
+
+    use actix_web::{test::TestRequest, FromRequest};
+    use diesel::r2d2::ConnectionManager;
+    use macros_rs::string;
+    use uuid::Uuid;
+
+    fn backend(host: &str, providers: &str) -> Location {
+        let backend = format!("display_name = \"App\"\nproviders = {providers}\naddress = \"127.0.0.1\"\nport = 3000\nhosts = [\"{host}\"]");
+        toml::from_str(&backend).unwrap()
+    }
+
+    async fn verify_at(url: &str, token: &str, pool: &Pool, snapshot: &Data<Shared>) -> HttpResponse {
+        let req = TestRequest::default()
+            .insert_header(("X-Original-URL", url))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+            .app_data(snapshot.clone())
+            .to_http_request();
+
+        let config = Live::<Config>::extract(&req).await.unwrap();
+        let routes = Live::<Routes>::extract(&req).await.unwrap();
+        verify(req, Query(VerifyQuery { redirect: None }), Data::new(pool.clone()), config, routes).await
+    }
+
+    #[actix_web::test]
+    async fn basic_sessions_are_refused_by_provider_only_services() {
+        let Ok(url) = std::env::var("ZEROTRUST_TEST_DATABASE") else {
+            eprintln!("skipped, ZEROTRUST_TEST_DATABASE is not set");
+            return;
+        };
+
+        let pool = Pool::builder().max_size(2).build(ConnectionManager::new(url)).unwrap();
+        let conn = &mut pool.get().unwrap();
+        db::try_run_migrations(conn).unwrap();
+
+        let username = format!("forward-{}", Uuid::new_v4().simple());
+        let user = User::signup(
+            UserDTO {
+                admin: false,
+                email: format!("{username}@localhost"),
+                username,
+                password: string!("unused"),
+                tokens: vec![],
+                services: vec![string!("gated"), string!("open")],
+                providers: vec![string!("basic")],
+            },
+            conn,
+        )
+        .unwrap();
+
+        let mut config = Config::new();
+        config.settings.secret = string!("test secret");
+        let provider =
+            "client_id = \"id\"\nclient_secret = \"secret\"\nauth_url = \"https://github.test/authorize\"\ntoken_url = \"https://github.test/token\"\nuserinfo_url = \"https://github.test/user\"";
+        config.providers.insert(string!("github"), toml::from_str::<Provider>(provider).unwrap());
+        config.backends.insert(string!("gated"), backend("gated.test", "[\"github\"]"));
+        config.backends.insert(string!("open"), backend("open.test", "[]"));
+
+        let info = SessionInfo {
+            ip: None,
+            user_agent: None,
+            max_age: config.settings.max_age,
+            method: string!("password"),
+        };
+        let login = User::create_session(&user, &info, conn).unwrap();
+        let token = UserToken::generate_token(&login, "password", false, &config);
+
+        let routes = Routes::new(&config).unwrap();
+        let snapshot = Data::new(Shared::from_pointee(Snapshot { config, routes }));
+
+        let open = verify_at("http://open.test/", &token, &pool, &snapshot).await;
+        let gated = verify_at("http://gated.test/page", &token, &pool, &snapshot).await;
+        let _ = User::delete(user.id, conn);
+
+        assert_eq!(open.status(), 200);
+        assert_eq!(gated.status(), 401);
+
+        let location = gated.headers().get(header::LOCATION).unwrap().to_str().unwrap();
+        assert_eq!(location, "http://gated.test/_zero/oauth/github?redirect=http%3A%2F%2Fgated.test%2Fpage");
+    }
+}
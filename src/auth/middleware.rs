@@ -2,18 +2,31 @@ use actix_service::forward_ready;
 use actix_web::body::EitherBody;
 use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
 use actix_web::web::Data;
-use actix_web::HttpResponse;
+use actix_web::{dev::Payload, FromRequest, HttpMessage, HttpRequest, HttpResponse};
 use actix_web::{guard::GuardContext, http::header::HeaderValue};
-use actix_web::{http::header, Error};
+use actix_web::{
+    http::{header, header::ContentType, StatusCode},
+    Error,
+};
 use diesel::prelude::RunQueryDsl;
-use futures::future::{ok, LocalBoxFuture, Ready};
-use macros_rs::fmtstr;
+use futures::future::{err, ok, LocalBoxFuture, Ready};
+use serde_json::json;
 use std::collections::BTreeMap;
+use url::form_urlencoded::byte_serialize;
 
 use crate::{
-    config::{db::Pool, structs::Config},
-    http::token,
-    models::user::User,
+    audit,
+    config::{
+        db::Pool,
+        live::{Live, Shared},
+        routes::Routes,
+        structs::Config,
+    },
+    http::{
+        errors::{create_error, JsonError},
+        select_service, token,
+    },
+    models::{token::UserToken, user::User},
     schema::users,
 };
 
@@ -51,45 +64,157 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let config = req.app_data::<Data<Config>>().unwrap();
+        let config = Live::<Config>::from_req(req.request());
+        let routes = Live::<Routes>::from_req(req.request());
 
         if let Some(pool) = req.app_data::<Data<Pool>>() {
-            if let Err(_) = users::table.first::<User>(&mut pool.get().unwrap()) {
+            if users::table.first::<User>(&mut pool.get().unwrap()).is_err() {
                 let (request, _pl) = req.into_parts();
                 let header = (header::LOCATION, "/setup");
                 let response = HttpResponse::TemporaryRedirect().insert_header(header).finish();
                 return Box::pin(async { Ok(ServiceResponse::new(request, response.map_into_right_body())) });
             }
 
-            if let Some(cookie) = req.cookie("sp_token") {
-                let token = cookie.value();
-                if let Ok(token_data) = token::decode_token(token.to_string(), config.as_ref()) {
-                    if token::verify_token(&token_data, pool).is_ok() {
+            if let Some(token) = request_token(req.request()) {
+                let service = select_service(req.request(), &routes);
+
+                match authorize(req.request(), &token, service, req.path(), pool, &config) {
+                    Ok((claims, user)) => {
+                        req.extensions_mut().insert(claims);
+                        req.extensions_mut().insert(user);
+
                         let res = self.service.call(req);
                         return Box::pin(async move { res.await.map(ServiceResponse::map_into_left_body) });
                     }
+                    Err(Denied::Forbidden { message, title }) => return forbidden(req, message, title),
+                    Err(Denied::StepUp) => {
+                        let redirect: String = byte_serialize(req.uri().to_string().as_bytes()).collect();
+                        return login_redirect(req, format!("/{}/login?redirect={redirect}", config.settings.server.prefix));
+                    }
+                    Err(Denied::Unauthenticated) => {}
                 }
             }
         }
 
-        let prefix = config.settings.server.prefix.clone();
-        let (request, _pl) = req.into_parts();
+        let location = format!("/{}/login", config.settings.server.prefix);
+        login_redirect(req, location)
+    }
+}
+
+fn login_redirect<B: 'static>(req: ServiceRequest, location: String) -> LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>> {
+    let (request, _pl) = req.into_parts();
+    let response = HttpResponse::TemporaryRedirect().insert_header((header::LOCATION, location)).finish().map_into_right_body();
+
+    Box::pin(async { Ok(ServiceResponse::new(request, response)) })
+}
 
-        let response = HttpResponse::TemporaryRedirect()
-            .insert_header((header::LOCATION, fmtstr!("/{prefix}/login")))
-            .finish()
-            .map_into_right_body();
+/// Why [`authorize`] turned a request away.
+pub(crate) enum Denied {
+    Unauthenticated,
+    /// Signed in with a password alone where a second factor is required,
+    /// the login page asks for it without asking for the password again.
+    StepUp,
+    Forbidden {
+        message: &'static str,
+        title: &'static str,
+    },
+}
 
-        return Box::pin(async { Ok(ServiceResponse::new(request, response)) });
+/// Reads the session token from the `sp_token` cookie, or from an
+/// `Authorization: Bearer` header for clients that cannot keep cookies.
+pub(crate) fn request_token(req: &HttpRequest) -> Option<String> {
+    if let Some(cookie) = req.cookie("sp_token") {
+        return Some(cookie.value().to_string());
+    }
+
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+/// Resolves the user behind a session token and checks they may reach
+/// `service`. Signed in users that are turned away go to the audit log.
+pub(crate) fn authorize(req: &HttpRequest, token: &str, service: Option<&str>, path: &str, pool: &Pool, config: &Config) -> Result<(UserToken, User), Denied> {
+    let token_data = token::decode_token(token.to_string(), config).map_err(|_| Denied::Unauthenticated)?;
+    let conn = &mut pool.get().unwrap();
+    let user = User::find_user_by_token(&token_data.claims, conn).map_err(|_| Denied::Unauthenticated)?;
+
+    if let Some(name) = service {
+        if config.backends.contains_key(name) && !user.can_access(name) {
+            tracing::warn!(user = user.username, service = name, "access denied");
+            audit::record_denied(req, &user.username, "access.denied", Some(name), json!({ "reason": "no access", "path": path }), conn);
+            return Err(Denied::Forbidden {
+                message: "You do not have access to this service.",
+                title: "Access denied",
+            });
+        }
+    }
+
+    // signing out stays possible without the second factor
+    let claims = token_data.claims;
+    if claims.password_only() && config.requires_2fa(service) && !is_logout(path, config) {
+        tracing::warn!(user = user.username, service, "second factor required");
+        audit::record_denied(req, &user.username, "access.denied", service, json!({ "reason": "second factor required", "path": path }), conn);
+        return Err(Denied::StepUp);
+    }
+
+    Ok((claims, user))
+}
+
+fn is_logout(path: &str, config: &Config) -> bool {
+    let prefix = &config.settings.server.prefix;
+    path == format!("/{prefix}/logout") || path == format!("/{prefix}/api/logout")
+}
+
+fn forbidden<B: 'static>(req: ServiceRequest, message: &str, title: &str) -> LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>> {
+    let (request, _pl) = req.into_parts();
+    let body = create_error(StatusCode::FORBIDDEN, message, Some(title));
+    let response = HttpResponse::Forbidden().content_type(ContentType::html()).body(body).map_into_right_body();
+
+    Box::pin(async { Ok(ServiceResponse::new(request, response)) })
+}
+
+/// Extracts the signed in user from a request that went through
+/// [`Authentication`], rejecting anyone who is not an administrator.
+pub(crate) struct Admin(pub User);
+
+impl FromRequest for Admin {
+    type Error = JsonError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        // cloned so the extensions are free again for the connection info the audit reads
+        let user = req.extensions().get::<User>().cloned();
+
+        match user {
+            Some(user) if user.admin => ok(Admin(user)),
+            user => {
+                if let (Some(user), Some(pool)) = (user, crate::POOL.get()) {
+                    tracing::warn!(user = user.username, "administrator access denied");
+                    audit::record_denied(
+                        req,
+                        &user.username,
+                        "admin.denied",
+                        None,
+                        json!({ "method": req.method().as_str(), "path": req.path() }),
+                        &mut pool.get().unwrap(),
+                    );
+                }
+
+                err(JsonError {
+                    status: 403,
+                    message: "Administrator access is required.".into(),
+                })
+            }
+        }
     }
 }
 
 pub fn setup_guard(_ctx: &GuardContext<'_>) -> bool {
     let pool = crate::POOL.get().unwrap();
-    match users::table.first::<User>(&mut pool.get().unwrap()) {
-        Ok(_) => false,
-        Err(_) => true,
-    }
+    users::table.first::<User>(&mut pool.get().unwrap()).is_err()
 }
 
 pub fn token_guard(ctx: &GuardContext<'_>) -> bool {
@@ -105,16 +230,16 @@ pub fn token_guard(ctx: &GuardContext<'_>) -> bool {
 
         if cookies.contains_key("sp_token") {
             let pool = crate::POOL.get().unwrap();
-            let path = crate::CONFIG_PATH.get().unwrap();
-            let config = Config::new().set_path(path).read();
-            match token::decode_token(cookies.get("sp_token").unwrap().to_string(), &config) {
-                Ok(token) => !User::is_valid_login_session(&token.claims, &mut pool.get().unwrap()),
+            let snapshot = ctx.app_data::<Data<Shared>>().unwrap().load();
+            // password only sessions sign in again to step up to a second factor
+            match token::decode_token(cookies.get("sp_token").unwrap().to_string(), &snapshot.config) {
+                Ok(token) => token.claims.password_only() || !User::is_valid_login_session(&token.claims, &mut pool.get().unwrap()),
                 Err(_) => true,
             }
         } else {
-            return true;
+            true
         }
     } else {
-        return true;
+        true
     }
 }
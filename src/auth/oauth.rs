@@ -0,0 +1,758 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use diesel::{result::Error as DieselError, QueryResult};
+use macros_rs::{string, ternary};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tera::Context;
+
+use crate::{
+    config::{
+        db::{Connection, Pool},
+        live::Live,
+        routes::Routes,
+        structs::{Config, Provider},
+    },
+    http::{errors::Error, metrics::METRICS},
+    models::{token::UserToken, user::User},
+    pages::{render, TeraState},
+};
+
+use actix_web::{
+    cookie::{time::Duration, Cookie, SameSite},
+    dev::ConnectionInfo,
+    http::{header, StatusCode},
+    web::{Data, Json, Path, Query},
+    HttpMessage, HttpRequest, HttpResponse,
+};
+
+use jsonwebtoken::{jwk::JwkSet, DecodingKey, EncodingKey, Header, Validation};
+
+pub(crate) const STATE_COOKIE: &str = "sp_oauth";
+const STATE_MAX_AGE: i64 = 600;
+const USERNAME_ATTEMPTS: usize = 20;
+
+const GITHUB_USER_URL: &str = "https://api.github.com/user";
+const GITHUB_EMAILS_URL: &str = "https://api.github.com/user/emails";
+
+pub(crate) const WRONG_PROVIDER: &str = "This service requires signing in with a different method.";
+
+#[derive(Serialize, Deserialize)]
+struct OAuthState {
+    exp: i64,
+    provider: String,
+    state: String,
+    nonce: String,
+    verifier: String,
+    redirect: String,
+}
+
+#[derive(Deserialize)]
+pub struct Start {
+    redirect: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Callback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct Identity {
+    email: Option<String>,
+    email_verified: Option<bool>,
+    preferred_username: Option<String>,
+    login: Option<String>,
+    nonce: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProviderEmail {
+    email: String,
+    #[serde(default)]
+    primary: bool,
+    #[serde(default)]
+    verified: bool,
+}
+
+#[derive(Serialize)]
+pub struct ProviderInfo {
+    name: String,
+    display_name: String,
+}
+
+pub(crate) fn random_string() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn code_challenge(verifier: &str) -> String { URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) }
+
+fn redirect_uri(name: &str, provider: &Provider, conn: &ConnectionInfo, config: &Config) -> String {
+    match &provider.redirect_url {
+        Some(url) => url.clone(),
+        None => format!("{}://{}/{}/oauth/{name}/callback", conn.scheme(), conn.host(), config.settings.server.prefix),
+    }
+}
+
+/// Only a missing account may lead to provisioning one, any other failure ends the sign in.
+fn database_error(name: &str, err: DieselError) -> Error {
+    tracing::error!(provider = name, err = err.to_string(), "unable to look up user");
+    Error::InternalError {
+        message: "Unable to sign you in, please try again.".into(),
+    }
+}
+
+/// The preferred username, or the first of `name-2`, `name-3`... no account holds yet.
+fn unique_username(name: &str, conn: &mut Connection) -> QueryResult<Option<String>> {
+    let name = name.to_lowercase();
+
+    for attempt in 1..=USERNAME_ATTEMPTS {
+        let candidate = ternary!(attempt == 1, name.clone(), format!("{name}-{attempt}"));
+        match User::find_user_by_username(&candidate, conn) {
+            Ok(_) => continue,
+            Err(DieselError::NotFound) => return Ok(Some(candidate)),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(None)
+}
+
+/// GitHub's profile carries no `email_verified`, its verified addresses are listed separately.
+fn emails_url(provider: &Provider) -> Option<&str> {
+    match (&provider.emails_url, provider.userinfo_url.as_deref()) {
+        (Some(url), _) => Some(url),
+        (None, Some(GITHUB_USER_URL)) => Some(GITHUB_EMAILS_URL),
+        _ => None,
+    }
+}
+
+fn state_cookie(value: String, config: &Config) -> Cookie<'static> {
+    Cookie::build(STATE_COOKIE, value)
+        .path(format!("/{}/oauth", config.settings.server.prefix))
+        .same_site(SameSite::Lax)
+        .max_age(Duration::seconds(STATE_MAX_AGE))
+        .http_only(true)
+        .finish()
+}
+
+/// Whether a session may reach a backend restricted to `providers`. When it
+/// may not, names the configured provider to sign in with instead, if any.
+pub fn required_provider<'a>(token: &UserToken, providers: &'a [String], config: &Config) -> Result<(), Option<&'a str>> {
+    if providers.is_empty() || providers.iter().any(|name| name == token.provider()) {
+        return Ok(());
+    }
+
+    Err(providers.iter().find(|name| config.providers.contains_key(*name)).map(String::as_str))
+}
+
+/// Returns a redirect to the provider a backend requires when the current
+/// session was not created through one of the backend's allowed providers.
+pub fn enforce_providers(req: &HttpRequest, providers: &[String], config: &Config) -> Result<(), Error> {
+    let Some(token) = req.extensions().get::<UserToken>().cloned() else {
+        return Ok(());
+    };
+
+    match required_provider(&token, providers, config) {
+        Ok(()) => Ok(()),
+        Err(Some(name)) => {
+            let redirect: String = url::form_urlencoded::byte_serialize(req.uri().to_string().as_bytes()).collect();
+            Err(Error::Redirect {
+                location: format!("/{}/oauth/{name}?redirect={redirect}", config.settings.server.prefix),
+            })
+        }
+        Err(None) => Err(Error::Unauthorized { message: WRONG_PROVIDER.into() }),
+    }
+}
+
+pub async fn providers(config: Live<Config>) -> Json<Vec<ProviderInfo>> {
+    let providers = config
+        .providers
+        .iter()
+        .map(|(name, provider)| ProviderInfo {
+            name: name.clone(),
+            display_name: provider.display_name.clone().unwrap_or_else(|| name.clone()),
+        })
+        .collect();
+
+    Json(providers)
+}
+
+pub async fn start(req: HttpRequest, conn: ConnectionInfo, name: Path<String>, query: Query<Start>, config: Live<Config>, routes: Live<Routes>, tera: Data<TeraState>) -> Result<HttpResponse, Error> {
+    tracing::info!(method = string!(req.method()), "oauth '{}'", req.uri());
+
+    let name = name.into_inner();
+    let config = config.get_ref();
+
+    let provider = match config.providers.get(&name) {
+        Some(provider) => provider,
+        None => return Err(Error::NotFound { message: "Provider not found".into() }),
+    };
+
+    let state = OAuthState {
+        exp: Utc::now().timestamp() + STATE_MAX_AGE,
+        provider: name.clone(),
+        state: random_string(),
+        nonce: random_string(),
+        verifier: random_string(),
+        redirect: super::safe_redirect(query.redirect.as_deref(), &routes),
+    };
+
+    let mut url = match url::Url::parse(&provider.auth_url) {
+        Ok(url) => url,
+        Err(_) => {
+            return Err(Error::InternalError {
+                message: "Provider has an invalid auth_url".into(),
+            })
+        }
+    };
+
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &provider.client_id)
+        .append_pair("redirect_uri", &redirect_uri(&name, provider, &conn, config))
+        .append_pair("scope", &provider.scopes.join(" "))
+        .append_pair("state", &state.state)
+        .append_pair("nonce", &state.nonce)
+        .append_pair("code_challenge", &code_challenge(&state.verifier))
+        .append_pair("code_challenge_method", "S256");
+
+    let secret = EncodingKey::from_secret(config.settings.secret.as_bytes());
+    let cookie = match jsonwebtoken::encode(&Header::default(), &state, &secret) {
+        Ok(value) => state_cookie(value, config),
+        Err(_) => {
+            return Err(Error::InternalError {
+                message: "Unable to start sign in".into(),
+            })
+        }
+    };
+
+    let mut page = Context::new();
+    page.insert("provider_name", provider.display_name.as_ref().unwrap_or(&name));
+    page.insert("provider_url", url.as_str());
+
+    Ok(HttpResponse::build(StatusCode::FOUND)
+        .insert_header((header::LOCATION, url.as_str()))
+        .content_type(header::ContentType::html())
+        .cookie(cookie)
+        .body(render("provider", &tera.0, &mut page, config)))
+}
+
+pub async fn callback(req: HttpRequest, conn: ConnectionInfo, name: Path<String>, query: Query<Callback>, pool: Data<Pool>, config: Live<Config>) -> Result<HttpResponse, Error> {
+    tracing::info!(method = string!(req.method()), "oauth '{}'", req.uri());
+
+    let name = name.into_inner();
+    let config = config.get_ref();
+
+    let provider = match config.providers.get(&name) {
+        Some(provider) => provider,
+        None => return Err(Error::NotFound { message: "Provider not found".into() }),
+    };
+
+    if let Some(error) = &query.error {
+        tracing::warn!(provider = name, error, description = query.error_description, "provider rejected sign in");
+        return Err(Error::Unauthorized {
+            message: "The provider rejected the sign in request.".into(),
+        });
+    }
+
+    let state = match req.cookie(STATE_COOKIE) {
+        Some(cookie) => {
+            let secret = DecodingKey::from_secret(config.settings.secret.as_bytes());
+            match jsonwebtoken::decode::<OAuthState>(cookie.value(), &secret, &Validation::default()) {
+                Ok(data) => data.claims,
+                Err(_) => {
+                    return Err(Error::BadClientData {
+                        message: "Sign in request expired, please try again.".into(),
+                    })
+                }
+            }
+        }
+        None => {
+            return Err(Error::BadClientData {
+                message: "Sign in request expired, please try again.".into(),
+            })
+        }
+    };
+
+    if state.provider != name || query.state.as_deref() != Some(state.state.as_str()) {
+        return Err(Error::BadClientData {
+            message: "Sign in state mismatch, please try again.".into(),
+        });
+    }
+
+    let code = match &query.code {
+        Some(code) => code,
+        None => {
+            return Err(Error::BadClientData {
+                message: "Authorization code missing from request".into(),
+            })
+        }
+    };
+
+    let identity = match exchange(&name, provider, code, &state, &conn, config).await {
+        Ok(identity) => identity,
+        Err(err) => {
+            tracing::error!(provider = name, err = err.to_string(), "oauth exchange failed");
+            return Err(Error::Unauthorized {
+                message: "Unable to verify your identity with the provider.".into(),
+            });
+        }
+    };
+
+    // accounts are matched by email, so one the provider has not verified could take over another
+    let email = match identity.email {
+        Some(email) if identity.email_verified == Some(true) => email.to_lowercase(),
+        _ => {
+            return Err(Error::Unauthorized {
+                message: "The provider did not return a verified email address.".into(),
+            })
+        }
+    };
+
+    let conn_db = &mut match pool.get() {
+        Ok(conn) => conn,
+        Err(err) => {
+            tracing::error!(provider = name, err = err.to_string(), "unable to reach the database");
+            return Err(Error::InternalError {
+                message: "Unable to sign you in, please try again.".into(),
+            });
+        }
+    };
+
+    let user = match User::find_user_by_email(&email, conn_db) {
+        Ok(user) if user.disabled => {
+            super::log_failure(&req, &user, &name, "account disabled", conn_db, config);
+            return Err(Error::Unauthorized {
+                message: "Your account has been disabled.".into(),
+            });
+        }
+        Ok(user) if user.providers.contains(&name) => user,
+        Ok(user) => {
+            super::log_failure(&req, &user, &name, "provider not linked", conn_db, config);
+            return Err(Error::Unauthorized {
+                message: format!("Your account is not linked to {name}.").into(),
+            });
+        }
+        Err(DieselError::NotFound) if provider.auto_provision => {
+            let username = identity.preferred_username.or(identity.login).unwrap_or_else(|| email.split('@').next().unwrap_or(&email).to_string());
+            let username = match unique_username(&username, conn_db) {
+                Ok(Some(username)) => username,
+                Ok(None) => {
+                    return Err(Error::Generic {
+                        status: StatusCode::CONFLICT,
+                        message: "Unable to pick a username for your account, ask an administrator to create it.".into(),
+                    })
+                }
+                Err(err) => return Err(database_error(&name, err)),
+            };
+
+            match User::provision(&username, &email, &name, conn_db) {
+                Ok(user) => {
+                    tracing::info!(provider = name, user = user.username, "provisioned user");
+                    user
+                }
+                Err(err) => {
+                    tracing::error!(provider = name, err = err.to_string(), "unable to provision user");
+                    return Err(Error::InternalError {
+                        message: "Unable to create your account.".into(),
+                    });
+                }
+            }
+        }
+        Err(DieselError::NotFound) => {
+            return Err(Error::Unauthorized {
+                message: "No account exists for this email address.".into(),
+            })
+        }
+        Err(err) => return Err(database_error(&name, err)),
+    };
+
+    let login_info = match User::create_session(&user, &super::session_info(&req, &name, config), conn_db) {
+        Some(login_info) => login_info,
+        None => {
+            return Err(Error::InternalError {
+                message: "Unable to create a session.".into(),
+            })
+        }
+    };
+
+    METRICS.login(&name, true);
+    let token = UserToken::generate_token(&login_info, &name, false, config);
+    let mut expired = state_cookie(string!(), config);
+    expired.make_removal();
+
+    tracing::info!(provider = name, user = user.username, "oauth login");
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, state.redirect))
+        .cookie(super::session_cookie(&conn, token, false))
+        .cookie(expired)
+        .finish())
+}
+
+async fn exchange(name: &str, provider: &Provider, code: &str, state: &OAuthState, conn: &ConnectionInfo, config: &Config) -> anyhow::Result<Identity> {
+    let client = reqwest::Client::new();
+    let redirect_uri = redirect_uri(name, provider, conn, config);
+
+    let params = [
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &redirect_uri),
+        ("client_id", &provider.client_id),
+        ("client_secret", &provider.client_secret),
+        ("code_verifier", &state.verifier),
+    ];
+
+    let tokens: TokenResponse = client
+        .post(&provider.token_url)
+        .header(header::ACCEPT.as_str(), "application/json")
+        .form(&params)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let mut identity = match &tokens.id_token {
+        Some(id_token) => verify_id_token(id_token, provider, &client).await?,
+        None => Identity::default(),
+    };
+
+    if tokens.id_token.is_some() && identity.nonce.as_deref() != Some(state.nonce.as_str()) {
+        anyhow::bail!("id_token nonce mismatch");
+    }
+
+    if let Some(userinfo_url) = &provider.userinfo_url {
+        let info: Identity = client
+            .get(userinfo_url)
+            .bearer_auth(&tokens.access_token)
+            .header(header::ACCEPT.as_str(), "application/json")
+            .header(header::USER_AGENT.as_str(), env!("CARGO_PKG_NAME"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        identity.email = identity.email.or(info.email);
+        identity.email_verified = identity.email_verified.or(info.email_verified);
+        identity.preferred_username = identity.preferred_username.or(info.preferred_username);
+        identity.login = identity.login.or(info.login);
+    }
+
+    if let (Some(emails_url), false) = (emails_url(provider), identity.email_verified == Some(true)) {
+        let emails: Vec<ProviderEmail> = client
+            .get(emails_url)
+            .bearer_auth(&tokens.access_token)
+            .header(header::ACCEPT.as_str(), "application/json")
+            .header(header::USER_AGENT.as_str(), env!("CARGO_PKG_NAME"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // keep the profile's address when it is verified, otherwise take the verified primary one
+        let current = identity.email.as_deref().map(str::to_lowercase);
+        let verified = emails
+            .into_iter()
+            .filter(|entry| entry.verified)
+            .max_by_key(|entry| (Some(entry.email.to_lowercase()) == current, entry.primary));
+
+        if let Some(entry) = verified {
+            identity.email = Some(entry.email);
+            identity.email_verified = Some(true);
+        }
+    }
+
+    Ok(identity)
+}
+
+async fn verify_id_token(id_token: &str, provider: &Provider, client: &reqwest::Client) -> anyhow::Result<Identity> {
+    let header = jsonwebtoken::decode_header(id_token)?;
+    let mut validation = Validation::new(header.alg);
+
+    validation.set_audience(&[&provider.client_id]);
+    if let Some(issuer) = &provider.issuer {
+        validation.set_issuer(&[issuer]);
+    }
+
+    // without a jwks endpoint the token is trusted because it was received
+    // directly from the token endpoint over the back channel (OIDC core 3.1.3.7)
+    let key = match &provider.jwks_url {
+        Some(jwks_url) => {
+            let jwks: JwkSet = client.get(jwks_url).send().await?.error_for_status()?.json().await?;
+            let jwk = match &header.kid {
+                Some(kid) => jwks.find(kid),
+                None => jwks.keys.first(),
+            };
+
+            match jwk {
+                Some(jwk) => DecodingKey::from_jwk(jwk)?,
+                None => anyhow::bail!("no matching key in jwks"),
+            }
+        }
+        None => {
+            validation.insecure_disable_signature_validation();
+            DecodingKey::from_secret(&[])
+        }
+    };
+
+    Ok(jsonwebtoken::decode::<Identity>(id_token, &key, &validation)?.claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        db,
+        live::{Shared, Snapshot},
+    };
+
+    use actix_web::{test::TestRequest, web, App, HttpServer};
+    use diesel::r2d2::ConnectionManager;
+    use jsonwebtoken::Algorithm;
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    const KEY: &[u8] = b"mock provider signing key";
+    const CLIENT_ID: &str = "zerotrust";
+    const ISSUER: &str = "https://issuer.test";
+
+    /// A provider answering the code `good-code` with an id_token carrying `claims`, signed with a key from its jwks,
+    /// or without one when `claims` is null, like GitHub.
+    async fn mock_provider(claims: Value) -> String {
+        let server = HttpServer::new(move || {
+            let claims = claims.clone();
+            App::new()
+                .route(
+                    "/token",
+                    web::post().to(move |form: web::Form<HashMap<String, String>>| {
+                        let claims = claims.clone();
+                        async move {
+                            let field = |name: &str| form.get(name).map(String::as_str);
+                            if field("code") != Some("good-code") || field("client_secret") != Some("secret") || field("code_verifier").is_none() {
+                                return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }));
+                            }
+
+                            if claims.is_null() {
+                                return HttpResponse::Ok().json(json!({ "access_token": "mock-access", "token_type": "Bearer" }));
+                            }
+
+                            let mut header = Header::new(Algorithm::HS256);
+                            header.kid = Some(string!("mock"));
+                            let id_token = jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(KEY)).unwrap();
+                            HttpResponse::Ok().json(json!({ "access_token": "mock-access", "token_type": "Bearer", "id_token": id_token }))
+                        }
+                    }),
+                )
+                .route("/user", web::get().to(|| async { HttpResponse::Ok().json(json!({ "login": "ada", "email": "ada@private.test" })) }))
+                .route(
+                    "/user/emails",
+                    web::get().to(|| async {
+                        HttpResponse::Ok().json(json!([
+                            { "email": "ada@old.test", "primary": false, "verified": true },
+                            { "email": "ada@example.com", "primary": true, "verified": true },
+                            { "email": "ada@private.test", "primary": false, "verified": false },
+                        ]))
+                    }),
+                )
+                .route(
+                    "/jwks",
+                    web::get().to(|| async { HttpResponse::Ok().json(json!({ "keys": [{ "kty": "oct", "kid": "mock", "alg": "HS256", "k": URL_SAFE_NO_PAD.encode(KEY) }] })) }),
+                )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+
+        let base = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        base
+    }
+
+    fn claims(nonce: &str) -> Value {
+        json!({
+            "iss": ISSUER,
+            "aud": CLIENT_ID,
+            "exp": Utc::now().timestamp() + 300,
+            "email": "Ada@Example.com",
+            "email_verified": true,
+            "preferred_username": "ada",
+            "nonce": nonce,
+        })
+    }
+
+    fn provider(base: &str) -> Provider {
+        Provider {
+            client_id: CLIENT_ID.into(),
+            client_secret: "secret".into(),
+            auth_url: format!("{base}/authorize"),
+            token_url: format!("{base}/token"),
+            userinfo_url: None,
+            emails_url: None,
+            jwks_url: Some(format!("{base}/jwks")),
+            redirect_url: Some("https://zerotrust.test/_zero/oauth/mock/callback".into()),
+            display_name: None,
+            issuer: Some(ISSUER.into()),
+            scopes: vec![],
+            auto_provision: false,
+        }
+    }
+
+    fn state(nonce: &str) -> OAuthState {
+        OAuthState {
+            exp: Utc::now().timestamp() + STATE_MAX_AGE,
+            provider: string!("mock"),
+            state: string!("expected"),
+            nonce: nonce.to_string(),
+            verifier: string!("verifier"),
+            redirect: string!("/"),
+        }
+    }
+
+    fn config(provider: Provider) -> Config {
+        let mut config = Config::new();
+        config.settings.secret = string!("test secret");
+        config.providers.insert(string!("mock"), provider);
+        config
+    }
+
+    async fn exchange_with(claims: Value, code: &str, nonce: &str) -> anyhow::Result<Identity> {
+        let base = mock_provider(claims).await;
+        let config = config(provider(&base));
+        let conn = TestRequest::default().to_http_request().connection_info().clone();
+
+        exchange("mock", &config.providers["mock"], code, &state(nonce), &conn, &config).await
+    }
+
+    #[actix_web::test]
+    async fn exchanges_code_for_verified_identity() {
+        let identity = exchange_with(claims("n1"), "good-code", "n1").await.unwrap();
+
+        assert_eq!(identity.email.as_deref(), Some("Ada@Example.com"));
+        assert_eq!(identity.email_verified, Some(true));
+        assert_eq!(identity.preferred_username.as_deref(), Some("ada"));
+    }
+
+    #[actix_web::test]
+    async fn rejects_unknown_code() {
+        assert!(exchange_with(claims("n1"), "bad-code", "n1").await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn rejects_other_issuer() {
+        let mut claims = claims("n1");
+        claims["iss"] = json!("https://evil.test");
+        assert!(exchange_with(claims, "good-code", "n1").await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn rejects_other_audience() {
+        let mut claims = claims("n1");
+        claims["aud"] = json!("another-client");
+        assert!(exchange_with(claims, "good-code", "n1").await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn rejects_nonce_mismatch() {
+        assert!(exchange_with(claims("n1"), "good-code", "n2").await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn looks_up_verified_email_when_profile_has_none() {
+        let base = mock_provider(Value::Null).await;
+        let mut provider = provider(&base);
+        provider.userinfo_url = Some(format!("{base}/user"));
+        provider.emails_url = Some(format!("{base}/user/emails"));
+
+        let config = config(provider);
+        let conn = TestRequest::default().to_http_request().connection_info().clone();
+        let identity = exchange("mock", &config.providers["mock"], "good-code", &state("n1"), &conn, &config).await.unwrap();
+
+        assert_eq!(identity.email.as_deref(), Some("ada@example.com"));
+        assert_eq!(identity.email_verified, Some(true));
+        assert_eq!(identity.login.as_deref(), Some("ada"));
+    }
+
+    #[test]
+    fn github_lists_emails_by_default() {
+        let mut github = provider("https://github.com/login/oauth");
+        github.userinfo_url = Some(GITHUB_USER_URL.into());
+        assert_eq!(emails_url(&github), Some(GITHUB_EMAILS_URL));
+
+        github.emails_url = Some("https://github.example.com/api/v3/user/emails".into());
+        assert_eq!(emails_url(&github), Some("https://github.example.com/api/v3/user/emails"));
+        assert_eq!(emails_url(&provider("https://issuer.test")), None);
+    }
+
+    #[test]
+    fn provisioning_skips_taken_usernames() {
+        let Ok(url) = std::env::var("ZEROTRUST_TEST_DATABASE") else {
+            eprintln!("skipped, ZEROTRUST_TEST_DATABASE is not set");
+            return;
+        };
+
+        let pool = Pool::builder().max_size(1).build(ConnectionManager::new(url)).unwrap();
+        let conn = &mut pool.get().unwrap();
+        db::try_run_migrations(conn).unwrap();
+
+        let username = format!("oauth-{}", Uuid::new_v4().simple());
+        assert_eq!(unique_username(&username.to_uppercase(), conn).unwrap(), Some(username.clone()));
+
+        for taken in [username.clone(), format!("{username}-2")] {
+            User::provision(&taken, &format!("{taken}@localhost"), "mock", conn).unwrap();
+        }
+
+        assert_eq!(unique_username(&username, conn).unwrap(), Some(format!("{username}-3")));
+    }
+
+    async fn callback_with(cookie: Option<&OAuthState>, query_state: &str) -> Result<HttpResponse, Error> {
+        let config = config(provider("http://127.0.0.1:9"));
+        let mut req = TestRequest::default();
+
+        if let Some(state) = cookie {
+            let value = jsonwebtoken::encode(&Header::default(), state, &EncodingKey::from_secret(config.settings.secret.as_bytes())).unwrap();
+            req = req.cookie(Cookie::new(STATE_COOKIE, value));
+        }
+
+        let routes = Routes::new(&config).unwrap();
+        let req = req.app_data(Data::new(Shared::from_pointee(Snapshot { config, routes }))).to_http_request();
+        let pool = Pool::builder().min_idle(Some(0)).build_unchecked(ConnectionManager::new("postgres://127.0.0.1:9/unused"));
+
+        let query = Callback {
+            code: Some(string!("good-code")),
+            state: Some(query_state.to_string()),
+            error: None,
+            error_description: None,
+        };
+
+        let conn = req.connection_info().clone();
+        callback(req.clone(), conn, Path::from(string!("mock")), Query(query), Data::new(pool), Live::from_req(&req)).await
+    }
+
+    #[actix_web::test]
+    async fn callback_rejects_state_mismatch() {
+        let result = callback_with(Some(&state("n1")), "forged").await;
+        assert!(matches!(result, Err(Error::BadClientData { message }) if message.contains("mismatch")));
+    }
+
+    #[actix_web::test]
+    async fn callback_rejects_missing_state_cookie() {
+        let result = callback_with(None, "expected").await;
+        assert!(matches!(result, Err(Error::BadClientData { message }) if message.contains("expired")));
+    }
+}
@@ -0,0 +1,465 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use hmac::{Hmac, Mac};
+use macros_rs::string;
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use url::form_urlencoded::byte_serialize;
+
+use crate::{
+    config::{
+        db::{Connection, Pool, PooledConnection},
+        live::Live,
+        structs::Config,
+    },
+    http::errors::JsonError,
+    models::{totp::Totp, user::User, webauthn::WebauthnCredential},
+};
+
+use actix_web::{
+    web::{Data, Json},
+    HttpMessage, HttpRequest, HttpResponse,
+};
+
+const STEP: i64 = 30;
+const DIGITS: u32 = 6;
+const SKEW: i64 = 1;
+const RECOVERY_CODES: usize = 10;
+
+#[derive(Serialize)]
+struct Enrollment {
+    secret: String,
+    uri: String,
+}
+
+#[derive(Serialize)]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Verify {
+    ticket: String,
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct TicketBody {
+    ticket: String,
+}
+
+#[derive(Deserialize)]
+pub struct Code {
+    code: String,
+}
+
+fn invalid_code() -> JsonError {
+    JsonError {
+        status: 401,
+        message: "Invalid authentication code, please try again.".into(),
+    }
+}
+
+fn server_error() -> JsonError {
+    JsonError {
+        status: 500,
+        message: "Unable to update two-factor authentication, please try again.".into(),
+    }
+}
+
+/// Secrets are encrypted with a key derived from `settings.secret`, so
+/// changing it means every user has to enroll again.
+fn cipher(config: &Config) -> Aes256Gcm { Aes256Gcm::new(&Sha256::digest(format!("zerotrust-totp:{}", config.settings.secret))) }
+
+fn encrypt(secret: &[u8], config: &Config) -> String {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let sealed = cipher(config).encrypt(&nonce, secret).expect("AES-GCM encryption failed");
+
+    STANDARD.encode([nonce.as_slice(), &sealed].concat())
+}
+
+fn decrypt(value: &str, config: &Config) -> Option<Vec<u8>> {
+    let bytes = STANDARD.decode(value).ok()?;
+    if bytes.len() < 12 {
+        return None;
+    }
+
+    let (nonce, sealed) = bytes.split_at(12);
+    cipher(config).decrypt(Nonce::from_slice(nonce), sealed).ok()
+}
+
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[19] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]) & 0x7fff_ffff;
+
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Finds the time step a code belongs to, allowing one step of clock drift
+/// and refusing anything at or before the last step that was accepted.
+fn matching_step(secret: &[u8], code: &str, last_step: i64) -> Option<i64> { step_at(secret, code, last_step, Utc::now().timestamp() / STEP) }
+
+fn step_at(secret: &[u8], code: &str, last_step: i64, now: i64) -> Option<i64> {
+    let code: u32 = code.parse().ok()?;
+
+    (now - SKEW..=now + SKEW).filter(|step| *step > last_step).find(|step| hotp(secret, *step as u64) == code)
+}
+
+fn normalize(code: &str) -> String { code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase() }
+
+fn hash_code(code: &str) -> String { STANDARD.encode(Sha256::digest(normalize(code).as_bytes())) }
+
+/// Returns the codes to show the user once, and their hashes to store.
+fn recovery_codes() -> (Vec<String>, Vec<String>) {
+    const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = rand::thread_rng();
+
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| {
+            let chars: String = (0..10).map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char).collect();
+            format!("{}-{}", &chars[..5], &chars[5..])
+        })
+        .collect();
+
+    let hashed = codes.iter().map(|code| hash_code(code)).collect();
+    (codes, hashed)
+}
+
+fn provisioning_uri(secret: &str, account: &str, config: &Config) -> String {
+    let issuer: String = byte_serialize(config.settings.app.name.as_bytes()).collect();
+    let account: String = byte_serialize(account.as_bytes()).collect();
+
+    format!("otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP}")
+}
+
+/// Checks a six digit code against the user's secret, or when `recovery` is
+/// set, consumes one of their recovery codes.
+fn check_code(totp: &Totp, code: &str, recovery: bool, conn: &mut Connection, config: &Config) -> bool {
+    let code = normalize(code);
+
+    if code.len() == DIGITS as usize && code.chars().all(|c| c.is_ascii_digit()) {
+        return match decrypt(&totp.secret, config).and_then(|secret| matching_step(&secret, &code, totp.last_step)) {
+            Some(step) => Totp::use_step(totp.user_id, step, conn),
+            None => false,
+        };
+    }
+
+    recovery && totp.enabled && Totp::use_recovery_code(totp.user_id, &hash_code(&code), conn)
+}
+
+fn begin_enrollment(user: &User, conn: &mut Connection, config: &Config) -> Result<Enrollment, JsonError> {
+    let mut secret = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+
+    match Totp::begin(user.id, encrypt(&secret, config), conn) {
+        Ok(_) => {
+            let secret = BASE32_NOPAD.encode(&secret);
+            Ok(Enrollment {
+                uri: provisioning_uri(&secret, &user.email, config),
+                secret,
+            })
+        }
+        // an enabled secret is kept, so the insert runs into its primary key
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Err(JsonError {
+            status: 409,
+            message: "Two-factor authentication is already enabled.".into(),
+        }),
+        Err(err) => {
+            tracing::error!(user = user.username, err = err.to_string(), "unable to begin two-factor enrollment");
+            Err(server_error())
+        }
+    }
+}
+
+/// Enables a pending enrollment, `None` when the code does not match its secret.
+fn finish_enrollment(totp: &Totp, code: &str, conn: &mut Connection, config: &Config) -> Result<Option<RecoveryCodes>, JsonError> {
+    let Some(step) = decrypt(&totp.secret, config).and_then(|secret| matching_step(&secret, &normalize(code), totp.last_step)) else {
+        return Ok(None);
+    };
+    let (codes, hashed) = recovery_codes();
+
+    match Totp::enable(totp.user_id, step, hashed, conn) {
+        Ok(_) => Ok(Some(RecoveryCodes { recovery_codes: codes })),
+        Err(_) => Err(server_error()),
+    }
+}
+
+/// Counts a wrong code against the account and holds the answer back like a
+/// wrong password would be.
+async fn rejected(req: &HttpRequest, user: &User, mut conn: PooledConnection, config: &Config) -> JsonError {
+    tracing::warn!(user = user.username, "invalid second factor");
+    super::log_failure(req, user, "totp", "invalid code", &mut conn, config);
+
+    let (delay, error) = super::record_failure(req, &user.username, &mut conn, config, invalid_code());
+    drop(conn);
+
+    tokio::time::sleep(delay).await;
+    error
+}
+
+/// Whether the user needs a second factor anywhere, globally or for one of
+/// the backends they can reach.
+pub(crate) fn required_for(user: &User, config: &Config) -> bool { config.requires_2fa(None) || config.backends.keys().any(|name| user.can_access(name) && config.requires_2fa(Some(name))) }
+
+fn current_user(req: &HttpRequest) -> Result<User, JsonError> {
+    req.extensions().get::<User>().cloned().ok_or(JsonError {
+        status: 401,
+        message: "You are not signed in.".into(),
+    })
+}
+
+pub async fn login_verify(req: HttpRequest, body: Json<Verify>, pool: Data<Pool>, config: Live<Config>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let config = config.as_ref();
+    let mut conn = pool.get().unwrap();
+    let (user, ticket) = super::redeem_ticket(&body.ticket, &mut conn, config)?;
+    super::check_limits(&req, &user.username, Some(&user), "totp", &mut conn, config)?;
+
+    let totp = Totp::find(user.id, &mut conn).map_err(|_| JsonError {
+        status: 400,
+        message: "Two-factor authentication has not been set up.".into(),
+    })?;
+
+    if totp.enabled {
+        if !check_code(&totp, &body.code, true, &mut conn, config) {
+            return Err(rejected(&req, &user, conn, config).await);
+        }
+
+        let cookie = super::start_session(&req, &user, "totp", true, ticket.remember, &mut conn, config)?;
+        return Ok(HttpResponse::Ok().cookie(cookie).finish());
+    }
+
+    if !ticket.enroll {
+        return Err(invalid_code());
+    }
+
+    let Some(codes) = finish_enrollment(&totp, &body.code, &mut conn, config)? else {
+        return Err(rejected(&req, &user, conn, config).await);
+    };
+    let cookie = super::start_session(&req, &user, "totp", true, ticket.remember, &mut conn, config)?;
+
+    tracing::info!(user = user.username, "enrolled two-factor authentication");
+    Ok(HttpResponse::Ok().cookie(cookie).json(codes))
+}
+
+pub async fn login_enroll(req: HttpRequest, body: Json<TicketBody>, pool: Data<Pool>, config: Live<Config>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let conn = &mut pool.get().unwrap();
+    let (user, ticket) = super::redeem_ticket(&body.ticket, conn, config.as_ref())?;
+
+    if !ticket.enroll {
+        return Err(JsonError {
+            status: 403,
+            message: "Use one of your existing second factors to sign in.".into(),
+        });
+    }
+
+    Ok(HttpResponse::Ok().json(begin_enrollment(&user, conn, config.as_ref())?))
+}
+
+pub async fn enroll(req: HttpRequest, pool: Data<Pool>, config: Live<Config>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let user = current_user(&req)?;
+    Ok(HttpResponse::Ok().json(begin_enrollment(&user, &mut pool.get().unwrap(), config.as_ref())?))
+}
+
+pub async fn confirm(req: HttpRequest, body: Json<Code>, pool: Data<Pool>, config: Live<Config>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let user = current_user(&req)?;
+    let conn = &mut pool.get().unwrap();
+
+    let totp = match Totp::find(user.id, conn) {
+        Ok(totp) if !totp.enabled => totp,
+        _ => {
+            return Err(JsonError {
+                status: 400,
+                message: "There is no pending two-factor enrollment.".into(),
+            })
+        }
+    };
+
+    let codes = finish_enrollment(&totp, &body.code, conn, config.as_ref())?.ok_or_else(invalid_code)?;
+
+    tracing::info!(user = user.username, "enrolled two-factor authentication");
+    Ok(HttpResponse::Ok().json(codes))
+}
+
+pub async fn regenerate(req: HttpRequest, body: Json<Code>, pool: Data<Pool>, config: Live<Config>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let user = current_user(&req)?;
+    let conn = &mut pool.get().unwrap();
+
+    match Totp::find(user.id, conn) {
+        Ok(totp) if totp.enabled && check_code(&totp, &body.code, false, conn, config.as_ref()) => {
+            let (codes, hashed) = recovery_codes();
+            Totp::replace_recovery_codes(user.id, hashed, conn).map_err(|_| server_error())?;
+
+            tracing::info!(user = user.username, "regenerated recovery codes");
+            Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes: codes }))
+        }
+        _ => Err(invalid_code()),
+    }
+}
+
+pub async fn disable(req: HttpRequest, body: Json<Code>, pool: Data<Pool>, config: Live<Config>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let user = current_user(&req)?;
+    let conn = &mut pool.get().unwrap();
+
+    if required_for(&user, config.as_ref()) && !WebauthnCredential::exists(user.id, conn) {
+        return Err(JsonError {
+            status: 403,
+            message: "Two-factor authentication is required by your administrator.".into(),
+        });
+    }
+
+    match Totp::find(user.id, conn) {
+        Ok(totp) if totp.enabled && check_code(&totp, &body.code, true, conn, config.as_ref()) => {
+            Totp::delete(user.id, conn).map_err(|_| server_error())?;
+
+            tracing::info!(user = user.username, "disabled two-factor authentication");
+            Ok(HttpResponse::NoContent().finish())
+        }
+        _ => Err(invalid_code()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{
+            db,
+            live::{Shared, Snapshot},
+            routes::Routes,
+        },
+        models::{failure::LoginFailure, user::UserDTO},
+    };
+
+    use actix_web::{test::TestRequest, FromRequest};
+    use diesel::r2d2::ConnectionManager;
+    use uuid::Uuid;
+
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    fn code_at(step: i64) -> String { format!("{:06}", hotp(SECRET, step as u64)) }
+
+    #[test]
+    fn hotp_matches_rfc4226() {
+        let expected = [755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489];
+
+        for (counter, code) in expected.into_iter().enumerate() {
+            assert_eq!(hotp(SECRET, counter as u64), code, "counter {counter}");
+        }
+    }
+
+    #[test]
+    fn steps_match_rfc6238() {
+        // the SHA1 vectors, cut down to six digits
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+
+        for (time, code) in vectors {
+            assert_eq!(step_at(SECRET, code, 0, time / STEP), Some(time / STEP), "time {time}");
+        }
+    }
+
+    #[test]
+    fn one_step_of_drift_is_allowed() {
+        let now = 1_000_000;
+
+        assert_eq!(step_at(SECRET, &code_at(now - 1), 0, now), Some(now - 1));
+        assert_eq!(step_at(SECRET, &code_at(now + 1), 0, now), Some(now + 1));
+        assert_eq!(step_at(SECRET, &code_at(now - 2), 0, now), None);
+        assert_eq!(step_at(SECRET, &code_at(now + 2), 0, now), None);
+    }
+
+    #[test]
+    fn used_steps_are_refused() {
+        let now = 1_000_000;
+
+        assert_eq!(step_at(SECRET, &code_at(now), now - 1, now), Some(now));
+        assert_eq!(step_at(SECRET, &code_at(now), now, now), None);
+        assert_eq!(step_at(SECRET, &code_at(now - 1), now, now), None);
+        assert_eq!(step_at(SECRET, &code_at(now + 1), now, now), Some(now + 1));
+    }
+
+    #[test]
+    fn malformed_codes_are_refused() {
+        assert_eq!(step_at(SECRET, "", 0, 1), None);
+        assert_eq!(step_at(SECRET, "28708x", 0, 1), None);
+    }
+
+    #[actix_web::test]
+    async fn enrollment_counts_wrong_codes_and_refuses_enabled_secrets() {
+        let Ok(url) = std::env::var("ZEROTRUST_TEST_DATABASE") else {
+            eprintln!("skipped, ZEROTRUST_TEST_DATABASE is not set");
+            return;
+        };
+
+        let pool = Pool::builder().max_size(2).build(ConnectionManager::new(url)).unwrap();
+        let conn = &mut pool.get().unwrap();
+        db::try_run_migrations(conn).unwrap();
+
+        let username = format!("totp-{}", Uuid::new_v4().simple());
+        let user = User::signup(
+            UserDTO {
+                admin: false,
+                email: format!("{username}@localhost"),
+                username,
+                password: string!("unused"),
+                tokens: vec![],
+                services: vec![],
+                providers: vec![string!("basic")],
+            },
+            conn,
+        )
+        .unwrap();
+
+        let mut config = Config::new();
+        config.settings.secret = string!("test secret");
+        config.settings.login.delay = 0;
+        let ticket = super::super::challenge(&user, vec![], false, &config).ticket;
+
+        let routes = Routes::new(&config).unwrap();
+        let req = TestRequest::default().app_data(Data::new(Shared::from_pointee(Snapshot { config, routes }))).to_http_request();
+        let live = || Live::<Config>::extract(&req);
+
+        assert!(begin_enrollment(&user, conn, live().await.unwrap().as_ref()).is_ok());
+
+        let body = Verify { ticket, code: string!("not-a-code") };
+        let result = login_verify(req.clone(), Json(body), Data::new(pool.clone()), live().await.unwrap()).await;
+        assert_eq!(result.err().map(|err| err.status), Some(401));
+        assert_eq!(LoginFailure::count_account(&user.username, 60, conn).unwrap(), 1);
+
+        Totp::enable(user.id, 0, vec![], conn).unwrap();
+        assert_eq!(begin_enrollment(&user, conn, live().await.unwrap().as_ref()).err().map(|err| err.status), Some(409));
+
+        User::delete(user.id, conn).unwrap();
+        LoginFailure::clear(&user.username, conn).unwrap();
+    }
+}
@@ -0,0 +1,634 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use macros_rs::string;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    audit,
+    config::{
+        db::{Connection, Pool, PooledConnection},
+        live::Live,
+        structs::Config,
+    },
+    http::{errors::JsonError, metrics::METRICS},
+    models::{
+        totp::Totp,
+        user::User,
+        webauthn::{WebauthnCeremony, WebauthnCredential, WebauthnCredentialInsertableDTO},
+    },
+};
+
+use actix_web::{
+    cookie::{time::Duration, Cookie, SameSite},
+    web::{Data, Json, Path},
+    HttpMessage, HttpRequest, HttpResponse,
+};
+
+use webauthn_rs::prelude::*;
+use webauthn_rs_proto::AllowCredentials;
+
+pub(crate) const STATE_COOKIE: &str = "sp_webauthn";
+const STATE_MAX_AGE: i64 = 300;
+
+/// In-flight ceremony, stored between `start` and `finish` under a random id
+/// the browser holds in a cookie.
+#[derive(Serialize, Deserialize)]
+enum Ceremony {
+    Register {
+        user_id: i32,
+        handle: Uuid,
+        name: String,
+        state: PasskeyRegistration,
+    },
+    Login {
+        user_id: Option<i32>,
+        remember: bool,
+        state: DiscoverableAuthentication,
+    },
+}
+
+#[derive(Deserialize)]
+pub struct RegisterStart {
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LoginStart {
+    ticket: Option<String>,
+    #[serde(default)]
+    remember: bool,
+}
+
+fn failed() -> JsonError {
+    JsonError {
+        status: 401,
+        message: "Passkey verification failed, please try again.".into(),
+    }
+}
+
+fn server_error() -> JsonError {
+    JsonError {
+        status: 500,
+        message: "Unable to process passkey, please try again.".into(),
+    }
+}
+
+/// The relying party defaults to the host the request came in on, so passkeys
+/// only work across backends when `settings.rp_id` names a shared parent domain.
+fn webauthn(req: &HttpRequest, config: &Config) -> Result<Webauthn, JsonError> {
+    let conn = req.connection_info();
+    let rp_id = config.settings.rp_id.clone().unwrap_or_else(|| super::remove_suffix(conn.host(), ":").to_string());
+    let origin = Url::parse(&format!("{}://{}", conn.scheme(), conn.host())).map_err(|_| server_error())?;
+
+    WebauthnBuilder::new(&rp_id, &origin)
+        .map(|builder| builder.rp_name(&config.settings.app.name).allow_subdomains(config.settings.rp_id.is_some()))
+        .and_then(|builder| builder.build())
+        .map_err(|err| {
+            tracing::error!(err = err.to_string(), rp_id, "invalid webauthn relying party");
+            server_error()
+        })
+}
+
+fn state_cookie(value: String, config: &Config) -> Cookie<'static> {
+    Cookie::build(STATE_COOKIE, value)
+        .path(format!("/{}/api/webauthn", config.settings.server.prefix))
+        .same_site(SameSite::Strict)
+        .max_age(Duration::seconds(STATE_MAX_AGE))
+        .http_only(true)
+        .finish()
+}
+
+fn expired_state(config: &Config) -> Cookie<'static> {
+    let mut cookie = state_cookie(string!(), config);
+    cookie.make_removal();
+    cookie
+}
+
+fn save_ceremony(ceremony: Ceremony, conn: &mut Connection, config: &Config) -> Result<Cookie<'static>, JsonError> {
+    let id = super::oauth::random_string();
+    let value = serde_json::to_string(&ceremony).map_err(|_| server_error())?;
+
+    WebauthnCeremony::save(&id, value, STATE_MAX_AGE, conn).map_err(|err| {
+        tracing::error!(err = err.to_string(), "unable to save passkey ceremony");
+        server_error()
+    })?;
+
+    Ok(state_cookie(id, config))
+}
+
+/// Takes the ceremony the cookie points to, so it cannot be finished twice.
+fn load_ceremony(req: &HttpRequest, conn: &mut Connection) -> Result<Ceremony, JsonError> {
+    let cookie = req.cookie(STATE_COOKIE).ok_or(JsonError {
+        status: 400,
+        message: "No passkey ceremony is in progress.".into(),
+    })?;
+
+    let stored = WebauthnCeremony::take(cookie.value(), conn).map_err(|err| {
+        tracing::error!(err = err.to_string(), "unable to load passkey ceremony");
+        server_error()
+    })?;
+
+    let value = stored.ok_or(JsonError {
+        status: 400,
+        message: "Your passkey request has expired, please try again.".into(),
+    })?;
+
+    serde_json::from_str(&value).map_err(|_| server_error())
+}
+
+fn encode_id(id: &[u8]) -> String { URL_SAFE_NO_PAD.encode(id) }
+
+fn passkeys(credentials: &[WebauthnCredential]) -> Vec<(String, Passkey)> {
+    credentials
+        .iter()
+        .filter_map(|credential| match serde_json::from_str::<Passkey>(&credential.passkey) {
+            Ok(passkey) => Some((credential.id.clone(), passkey)),
+            Err(err) => {
+                tracing::error!(err = err.to_string(), credential = credential.id, "unable to read stored passkey");
+                None
+            }
+        })
+        .collect()
+}
+
+fn current_user(req: &HttpRequest) -> Result<User, JsonError> {
+    req.extensions().get::<User>().cloned().ok_or(JsonError {
+        status: 401,
+        message: "You are not signed in.".into(),
+    })
+}
+
+/// Verifies an assertion against the user's passkeys and stores the new
+/// signature counter, so cloned authenticators are noticed.
+fn verify(webauthn: &Webauthn, credential: &PublicKeyCredential, state: DiscoverableAuthentication, user_id: i32, conn: &mut Connection) -> Result<(), JsonError> {
+    let stored = passkeys(&WebauthnCredential::find_by_user(user_id, conn).map_err(|_| server_error())?);
+    let keys: Vec<DiscoverableKey> = stored.iter().map(|(_, passkey)| passkey.into()).collect();
+
+    let result = webauthn.finish_discoverable_authentication(credential, state, &keys).map_err(|err| {
+        tracing::warn!(err = err.to_string(), user_id, "passkey assertion rejected");
+        failed()
+    })?;
+
+    let (id, mut passkey) = stored.into_iter().find(|(_, passkey)| passkey.cred_id() == result.cred_id()).ok_or_else(failed)?;
+    let updated = match passkey.update_credential(&result) {
+        Some(true) => serde_json::to_string(&passkey).ok(),
+        _ => None,
+    };
+
+    WebauthnCredential::used(&id, updated, conn).map_err(|_| server_error())?;
+    Ok(())
+}
+
+pub async fn register_start(req: HttpRequest, body: Json<RegisterStart>, pool: Data<Pool>, config: Live<Config>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let config = config.as_ref();
+    let user = current_user(&req)?;
+    let conn = &mut pool.get().unwrap();
+
+    let existing = WebauthnCredential::find_by_user(user.id, conn).map_err(|_| server_error())?;
+    let handle = match existing.first() {
+        Some(credential) => Uuid::parse_str(&credential.user_handle).map_err(|_| server_error())?,
+        None => Uuid::new_v4(),
+    };
+
+    let exclude: Vec<CredentialID> = passkeys(&existing).iter().map(|(_, passkey)| passkey.cred_id().clone()).collect();
+    let (challenge, state) = webauthn(&req, config)?
+        .start_passkey_registration(handle, &user.email, &user.username, Some(exclude))
+        .map_err(|_| server_error())?;
+
+    let name = body.name.clone().filter(|name| !name.trim().is_empty()).unwrap_or_else(|| format!("Passkey {}", existing.len() + 1));
+    let cookie = save_ceremony(
+        Ceremony::Register {
+            user_id: user.id,
+            handle,
+            name,
+            state,
+        },
+        conn,
+        config,
+    )?;
+
+    Ok(HttpResponse::Ok().cookie(cookie).json(challenge))
+}
+
+pub async fn register_finish(req: HttpRequest, body: Json<RegisterPublicKeyCredential>, pool: Data<Pool>, config: Live<Config>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let config = config.as_ref();
+    let user = current_user(&req)?;
+    let conn = &mut pool.get().unwrap();
+
+    let (handle, name, state) = match load_ceremony(&req, conn)? {
+        Ceremony::Register { user_id, handle, name, state } if user_id == user.id => (handle, name, state),
+        _ => return Err(failed()),
+    };
+
+    let passkey = webauthn(&req, config)?.finish_passkey_registration(&body, &state).map_err(|err| {
+        tracing::warn!(err = err.to_string(), user = user.username, "passkey registration rejected");
+        failed()
+    })?;
+
+    let record = WebauthnCredentialInsertableDTO {
+        id: encode_id(passkey.cred_id()),
+        user_id: user.id,
+        user_handle: handle.to_string(),
+        name,
+        passkey: serde_json::to_string(&passkey).map_err(|_| server_error())?,
+        created_at: Utc::now().naive_utc(),
+    };
+
+    let credential = WebauthnCredential::create(record, conn).map_err(|_| JsonError {
+        status: 409,
+        message: "This passkey is already registered.".into(),
+    })?;
+
+    audit::record(&req, Some(&user.username), "passkey.add", Some(&credential.id), json!({ "name": credential.name }), conn);
+    tracing::info!(user = user.username, credential = credential.name, "registered passkey");
+    Ok(HttpResponse::Created().cookie(expired_state(config)).json(credential))
+}
+
+pub async fn login_start(req: HttpRequest, body: Json<LoginStart>, pool: Data<Pool>, config: Live<Config>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let config = config.as_ref();
+    let conn = &mut pool.get().unwrap();
+    let (mut challenge, state) = webauthn(&req, config)?.start_discoverable_authentication().map_err(|_| server_error())?;
+    challenge.mediation = None;
+
+    // as a second factor, the browser is told which credentials may answer
+    let (user_id, remember) = match &body.ticket {
+        Some(ticket) => {
+            let (user, ticket) = super::redeem_ticket(ticket, conn, config)?;
+            super::check_limits(&req, &user.username, Some(&user), "passkey", conn, config)?;
+            let stored = passkeys(&WebauthnCredential::find_by_user(user.id, conn).map_err(|_| server_error())?);
+
+            if stored.is_empty() {
+                return Err(JsonError {
+                    status: 400,
+                    message: "You have no passkeys registered.".into(),
+                });
+            }
+
+            challenge.public_key.allow_credentials = stored
+                .iter()
+                .map(|(_, passkey)| AllowCredentials {
+                    type_: string!("public-key"),
+                    id: passkey.cred_id().clone().into(),
+                    transports: None,
+                })
+                .collect();
+            (Some(user.id), ticket.remember)
+        }
+        None => {
+            super::check_address(&req, None, "passkey", conn, config)?;
+            (None, body.remember)
+        }
+    };
+
+    let cookie = save_ceremony(Ceremony::Login { user_id, remember, state }, conn, config)?;
+    Ok(HttpResponse::Ok().cookie(cookie).json(challenge))
+}
+
+/// Counts a rejected assertion against `account` and holds the answer back
+/// like a wrong password would be.
+async fn rejected(req: &HttpRequest, account: &str, mut conn: PooledConnection, config: &Config) -> JsonError {
+    let (delay, error) = super::record_failure(req, account, &mut conn, config, failed());
+    drop(conn);
+
+    tokio::time::sleep(delay).await;
+    error
+}
+
+pub async fn login_finish(req: HttpRequest, body: Json<PublicKeyCredential>, pool: Data<Pool>, config: Live<Config>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let config = config.as_ref();
+    let webauthn = webauthn(&req, config)?;
+    let mut conn = pool.get().unwrap();
+
+    let (user_id, remember, state) = match load_ceremony(&req, &mut conn)? {
+        Ceremony::Login { user_id, remember, state } => (user_id, remember, state),
+        _ => return Err(failed()),
+    };
+
+    // passwordless sign ins only learn who the user is from the authenticator
+    let user_id = match user_id {
+        Some(user_id) => Some(user_id),
+        None => match webauthn.identify_discoverable_authentication(&body) {
+            Ok((handle, _)) => {
+                let credentials = WebauthnCredential::find_by_handle(&handle.to_string(), &mut conn).map_err(|_| server_error())?;
+                credentials.first().map(|credential| credential.user_id)
+            }
+            Err(_) => None,
+        },
+    };
+
+    // failures with a credential nobody owns count against its id, and the address
+    let user = match user_id.map(|user_id| User::find(user_id, &mut conn)) {
+        Some(Ok(user)) if !user.disabled => user,
+        Some(Ok(user)) => {
+            super::check_limits(&req, &user.username, Some(&user), "passkey", &mut conn, config)?;
+            super::log_failure(&req, &user, "passkey", "account disabled", &mut conn, config);
+            return Err(rejected(&req, &user.username, conn, config).await);
+        }
+        _ => {
+            super::check_limits(&req, &body.id, None, "passkey", &mut conn, config)?;
+            METRICS.login("passkey", false);
+            return Err(rejected(&req, &body.id, conn, config).await);
+        }
+    };
+
+    super::check_limits(&req, &user.username, Some(&user), "passkey", &mut conn, config)?;
+
+    match verify(&webauthn, &body, state, user.id, &mut conn) {
+        Ok(()) => {}
+        Err(err) if err.status == 500 => return Err(err),
+        Err(_) => {
+            super::log_failure(&req, &user, "passkey", "passkey rejected", &mut conn, config);
+            return Err(rejected(&req, &user.username, conn, config).await);
+        }
+    }
+
+    let cookie = super::start_session(&req, &user, "passkey", true, remember, &mut conn, config)?;
+
+    tracing::info!(user = user.username, "passkey login");
+    Ok(HttpResponse::Ok().cookie(cookie).cookie(expired_state(config)).finish())
+}
+
+pub async fn list_credentials(req: HttpRequest, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let user = current_user(&req)?;
+    let credentials = WebauthnCredential::find_by_user(user.id, &mut pool.get().unwrap()).map_err(|_| server_error())?;
+
+    Ok(HttpResponse::Ok().json(credentials))
+}
+
+pub async fn delete_credential(req: HttpRequest, credential_id: Path<String>, pool: Data<Pool>, config: Live<Config>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let user = current_user(&req)?;
+    let conn = &mut pool.get().unwrap();
+
+    if super::totp::required_for(&user, config.as_ref()) && !Totp::is_enabled(user.id, conn) {
+        let credentials = WebauthnCredential::find_by_user(user.id, conn).map_err(|_| server_error())?;
+        if !credentials.is_empty() && credentials.iter().all(|credential| credential.id == *credential_id) {
+            return Err(JsonError {
+                status: 403,
+                message: "Two-factor authentication is required by your administrator.".into(),
+            });
+        }
+    }
+
+    match WebauthnCredential::delete(&credential_id, user.id, conn) {
+        Ok(0) => Err(JsonError {
+            status: 404,
+            message: "Passkey not found.".into(),
+        }),
+        Ok(_) => {
+            audit::record(&req, Some(&user.username), "passkey.remove", Some(&credential_id), json!({}), conn);
+            tracing::info!(user = user.username, credential = *credential_id, "removed passkey");
+            Ok(HttpResponse::NoContent().finish())
+        }
+        Err(_) => Err(server_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{
+            db,
+            live::{Shared, Snapshot},
+            routes::Routes,
+        },
+        models::{
+            failure::{Lockout, LoginFailure},
+            user::UserDTO,
+        },
+    };
+
+    use actix_web::{body, cookie::Cookie, http::header, test::TestRequest, FromRequest};
+    use diesel::r2d2::ConnectionManager;
+    use std::sync::Arc;
+    use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
+
+    const HOST: &str = "localhost";
+
+    struct Harness {
+        pool: Pool,
+        snapshot: Data<Shared>,
+        user: User,
+        authenticator: WebauthnAuthenticator<SoftPasskey>,
+    }
+
+    impl Drop for Harness {
+        fn drop(&mut self) {
+            let conn = &mut self.pool.get().unwrap();
+            let _ = User::delete(self.user.id, conn);
+            let _ = LoginFailure::clear(&self.user.username, conn);
+            let _ = Lockout::delete(&self.user.username, conn);
+        }
+    }
+
+    /// Signs a fresh user up in the database named by `ZEROTRUST_TEST_DATABASE`,
+    /// `None` when it is not set.
+    fn harness(name: &str) -> Option<Harness> {
+        let Ok(url) = std::env::var("ZEROTRUST_TEST_DATABASE") else {
+            eprintln!("skipped, ZEROTRUST_TEST_DATABASE is not set");
+            return None;
+        };
+
+        let pool = Pool::builder().max_size(2).build(ConnectionManager::new(url)).unwrap();
+        let conn = &mut pool.get().unwrap();
+        db::try_run_migrations(conn).unwrap();
+
+        let username = format!("webauthn-{name}-{}", Uuid::new_v4().simple());
+        let user = User::signup(
+            UserDTO {
+                admin: false,
+                email: format!("{username}@{HOST}"),
+                username,
+                password: string!("unused"),
+                tokens: vec![],
+                services: vec![],
+                providers: vec![string!("basic")],
+            },
+            conn,
+        )
+        .unwrap();
+
+        let mut config = Config::new();
+        config.settings.secret = string!("test secret");
+        config.settings.login.delay = 0;
+        let routes = Routes::new(&config).unwrap();
+
+        Some(Harness {
+            pool,
+            snapshot: Data::new(Shared::from_pointee(Snapshot { config, routes })),
+            user,
+            authenticator: WebauthnAuthenticator::new(SoftPasskey::new(true)),
+        })
+    }
+
+    impl Harness {
+        fn request(&self, signed_in: bool, state: Option<&Cookie<'static>>) -> HttpRequest {
+            let mut req = TestRequest::default().insert_header((header::HOST, HOST)).app_data(self.snapshot.clone());
+            if let Some(state) = state {
+                req = req.cookie(state.clone());
+            }
+
+            let req = req.to_http_request();
+            if signed_in {
+                req.extensions_mut().insert(self.user.clone());
+            }
+            req
+        }
+
+        async fn config(&self, req: &HttpRequest) -> Live<Config> { Live::<Config>::extract(req).await.unwrap() }
+
+        fn origin(&self) -> Url { Url::parse(&format!("http://{HOST}")).unwrap() }
+
+        async fn registration(&mut self) -> (Cookie<'static>, RegisterPublicKeyCredential) {
+            let req = self.request(true, None);
+            let res = register_start(req.clone(), Json(RegisterStart { name: None }), Data::new(self.pool.clone()), self.config(&req).await)
+                .await
+                .unwrap();
+            let state = state_of(&res);
+            let challenge: CreationChallengeResponse = json_of(res).await;
+
+            (state, self.authenticator.do_registration(self.origin(), challenge).unwrap())
+        }
+
+        async fn finish_registration(&self, state: &Cookie<'static>, credential: RegisterPublicKeyCredential) -> Result<HttpResponse, JsonError> {
+            let req = self.request(true, Some(state));
+            register_finish(req.clone(), Json(credential), Data::new(self.pool.clone()), self.config(&req).await).await
+        }
+
+        async fn register(&mut self) {
+            let (state, credential) = self.registration().await;
+            assert_eq!(self.finish_registration(&state, credential).await.unwrap().status(), 201);
+        }
+
+        /// Starts a passwordless sign in and answers it, with the user handle a
+        /// resident key would hand back.
+        async fn assertion(&mut self) -> (Cookie<'static>, PublicKeyCredential) {
+            let req = self.request(false, None);
+            let body = LoginStart { ticket: None, remember: false };
+            let res = login_start(req.clone(), Json(body), Data::new(self.pool.clone()), self.config(&req).await).await.unwrap();
+            let state = state_of(&res);
+            let mut challenge: RequestChallengeResponse = json_of(res).await;
+
+            // the soft authenticator only signs with credentials it is pointed at
+            let conn = &mut self.pool.get().unwrap();
+            let stored = passkeys(&WebauthnCredential::find_by_user(self.user.id, conn).unwrap());
+            challenge.public_key.allow_credentials = stored
+                .iter()
+                .map(|(_, passkey)| AllowCredentials {
+                    type_: string!("public-key"),
+                    id: passkey.cred_id().clone().into(),
+                    transports: None,
+                })
+                .collect();
+
+            let mut credential = self.authenticator.do_authentication(self.origin(), challenge).unwrap();
+            let handle = Uuid::parse_str(&WebauthnCredential::user_handle(self.user.id, conn).unwrap()).unwrap();
+            credential.response.user_handle = Some(handle.as_bytes().to_vec().into());
+            (state, credential)
+        }
+
+        async fn delete(&self, credential_id: &str) -> Result<HttpResponse, JsonError> {
+            let req = self.request(true, None);
+            delete_credential(req.clone(), Path::from(credential_id.to_string()), Data::new(self.pool.clone()), self.config(&req).await).await
+        }
+
+        async fn finish(&self, state: &Cookie<'static>, credential: PublicKeyCredential) -> Result<HttpResponse, JsonError> {
+            let req = self.request(false, Some(state));
+            login_finish(req.clone(), Json(credential), Data::new(self.pool.clone()), self.config(&req).await).await
+        }
+    }
+
+    #[actix_web::test]
+    async fn keeps_the_last_second_factor_while_required() {
+        let Some(mut harness) = harness("required") else { return };
+        harness.register().await;
+
+        let mut config = harness.snapshot.load().config.clone();
+        config.settings.require_2fa = true;
+        let routes = Routes::new(&config).unwrap();
+        harness.snapshot.store(Arc::new(Snapshot { config, routes }));
+
+        let first = WebauthnCredential::find_by_user(harness.user.id, &mut harness.pool.get().unwrap()).unwrap()[0].id.clone();
+        assert_eq!(harness.delete(&first).await.err().map(|err| err.status), Some(403));
+
+        harness.register().await;
+        assert_eq!(harness.delete(&first).await.unwrap().status(), 204);
+    }
+
+    fn state_of(res: &HttpResponse) -> Cookie<'static> { res.cookies().find(|cookie| cookie.name() == STATE_COOKIE).unwrap().into_owned() }
+
+    async fn json_of<T: serde::de::DeserializeOwned>(res: HttpResponse) -> T {
+        let bytes = body::to_bytes(res.into_body()).await.ok().unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn registers_and_signs_in_without_a_password() {
+        let Some(mut harness) = harness("login") else { return };
+        harness.register().await;
+
+        let (state, credential) = harness.assertion().await;
+        let res = harness.finish(&state, credential).await.unwrap();
+
+        assert_eq!(res.status(), 200);
+        assert!(res.cookies().any(|cookie| cookie.name() == "sp_token"));
+    }
+
+    #[actix_web::test]
+    async fn ceremonies_finish_once() {
+        let Some(mut harness) = harness("replay") else { return };
+        let (state, credential) = harness.registration().await;
+        assert!(harness.finish_registration(&state, credential.clone()).await.is_ok());
+        assert_eq!(harness.finish_registration(&state, credential).await.err().map(|err| err.status), Some(400));
+
+        let (state, credential) = harness.assertion().await;
+        assert!(harness.finish(&state, credential.clone()).await.is_ok());
+        assert_eq!(harness.finish(&state, credential).await.err().map(|err| err.status), Some(400));
+    }
+
+    #[actix_web::test]
+    async fn rejected_assertions_count_as_failures() {
+        let Some(mut harness) = harness("failure") else { return };
+        harness.register().await;
+
+        let (state, mut credential) = harness.assertion().await;
+        let mut signature = credential.response.signature.to_vec();
+        signature[8] ^= 0xff;
+        credential.response.signature = signature.into();
+
+        assert_eq!(harness.finish(&state, credential).await.err().map(|err| err.status), Some(401));
+
+        let conn = &mut harness.pool.get().unwrap();
+        assert_eq!(LoginFailure::count_account(&harness.user.username, 60, conn).unwrap(), 1);
+    }
+
+    #[actix_web::test]
+    async fn locked_accounts_cannot_use_passkeys() {
+        let Some(mut harness) = harness("locked") else { return };
+        harness.register().await;
+
+        let max_failures = harness.snapshot.load().config.settings.login.max_failures;
+        for _ in 0..max_failures {
+            let (state, mut credential) = harness.assertion().await;
+            credential.response.signature = vec![0; 8].into();
+            assert!(harness.finish(&state, credential).await.is_err());
+        }
+
+        let (state, credential) = harness.assertion().await;
+        assert_eq!(harness.finish(&state, credential).await.err().map(|err| err.status), Some(429));
+    }
+}
@@ -1,6 +1,383 @@
+use clap::{Args, Subcommand};
+use colored::Colorize;
+use macros_rs::{crashln, string, ternary};
+use serde_json::{json, Value};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufWriter, IsTerminal, Write},
+};
+
+use crate::{
+    config::{self, db::Connection},
+    models::{
+        audit::AuditEvent,
+        history::LoginHistory,
+        session::Session,
+        user::{User, UserDTO, UserUpdateDTO},
+    },
+};
+
+#[derive(Clone, Subcommand)]
+pub enum Commands {
+    /// Manage user accounts
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Inspect and revoke login sessions
+    #[command(subcommand)]
+    Session(SessionCommand),
+    /// Show sign in attempts, newest first
+    History {
+        /// Only show attempts on this user
+        #[arg(long)]
+        user: Option<String>,
+        /// Only show failed attempts
+        #[arg(long)]
+        failed: bool,
+        /// Number of attempts to show
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+    /// Validate the configuration file
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Check and export the audit log
+    #[command(subcommand)]
+    Audit(AuditCommand),
+    /// Run pending database migrations
+    Migrate,
+}
+
+#[derive(Clone, Subcommand)]
+pub enum UserCommand {
+    /// Create a new user
+    Add(UserAdd),
+    /// List all users
+    List,
+    /// Change the password of a user
+    Passwd {
+        username: String,
+        /// New password, read from stdin or prompted when omitted
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Delete a user, keeping their login history
+    Delete { username: String },
+    /// Grant or revoke administrator access
+    Promote {
+        username: String,
+        /// Remove administrator access instead
+        #[arg(long)]
+        revoke: bool,
+    },
+}
+
+#[derive(Clone, Args)]
+pub struct UserAdd {
+    pub username: String,
+    pub email: String,
+    /// Password, read from stdin or prompted when omitted
+    #[arg(long)]
+    pub password: Option<String>,
+    /// Create the user as an administrator
+    #[arg(long)]
+    pub admin: bool,
+    /// Service the user may access (repeatable)
+    #[arg(long = "service")]
+    pub services: Vec<String>,
+    /// Login provider the user may sign in with (repeatable)
+    #[arg(long = "provider", default_value = "basic")]
+    pub providers: Vec<String>,
+}
+
+#[derive(Clone, Subcommand)]
+pub enum SessionCommand {
+    /// List active sessions
+    List {
+        /// Only show sessions of this user
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Revoke a session by id, or every session of a user
+    Revoke {
+        id: Option<String>,
+        /// Revoke all sessions of this user
+        #[arg(long, conflicts_with = "id")]
+        user: Option<String>,
+    },
+}
+
+#[derive(Clone, Subcommand)]
+pub enum AuditCommand {
+    /// Check that no event was changed or removed
+    Verify,
+    /// Write events as JSON Lines, oldest first
+    Export {
+        /// File to write to instead of stdout
+        #[arg(long)]
+        output: Option<String>,
+        /// Only export events after this id
+        #[arg(long, default_value_t = 0)]
+        after: i64,
+    },
+}
+
+#[derive(Clone, Subcommand)]
+pub enum ConfigCommand {
+    /// Parse and validate the configuration file
+    Check,
+}
+
 pub fn get_version(short: bool) -> String {
-    return match short {
+    match short {
         true => format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
         false => format!("{} ({} {}) [{}]", env!("CARGO_PKG_VERSION"), env!("GIT_HASH"), env!("BUILD_DATE"), env!("PROFILE")),
+    }
+}
+
+pub fn run(command: Commands, path: &str) {
+    match command {
+        Commands::Config(ConfigCommand::Check) => check_config(path),
+        Commands::Migrate => {
+            let pool = config::db::init_db(path);
+            match config::db::try_run_migrations(&mut pool.get().unwrap()) {
+                Ok(applied) => println!("{} applied {applied} migration(s)", "✔".green()),
+                Err(err) => crashln!("Migration failed.\n{}", err.white()),
+            }
+        }
+        Commands::User(command) => user(command, &mut connect(path)),
+        Commands::Session(command) => session(command, &mut connect(path)),
+        Commands::History { user, failed, limit } => history(user, failed, limit, &mut connect(path)),
+        Commands::Audit(command) => audit(command, &mut connect(path)),
+    }
+}
+
+fn connect(path: &str) -> config::db::PooledConnection {
+    let pool = config::db::init_db(path);
+    let conn = pool.get().unwrap_or_else(|err| crashln!("Unable to connect to postgres.\n{}", string!(err).white()));
+
+    if crate::POOL.set(pool).is_err() {
+        crashln!("Failed to set pool!");
+    }
+
+    conn
+}
+
+fn read_password(password: Option<String>) -> String {
+    if let Some(password) = password {
+        return password;
+    }
+
+    let password = match io::stdin().is_terminal() {
+        true => rpassword::prompt_password("Password: ").unwrap_or_default(),
+        false => io::stdin().lock().lines().next().and_then(|line| line.ok()).unwrap_or_default(),
     };
+
+    if password.is_empty() {
+        crashln!("A password is required.");
+    }
+
+    password
+}
+
+/// Notes a change in the audit log, warning rather than failing since the change is already made.
+fn audited(action: &str, target: &str, details: Value, conn: &mut Connection) {
+    if let Err(err) = crate::audit::record_local(action, Some(target), details, conn) {
+        eprintln!("{} unable to write the audit event, the change was made\n{}", "!".yellow(), string!(err).white());
+    }
+}
+
+fn find_user(username: &str, conn: &mut Connection) -> User {
+    User::find_user_by_username(&username.to_lowercase(), conn).unwrap_or_else(|_| crashln!("User '{}' does not exist.", username.white()))
+}
+
+fn check_config(path: &str) {
+    let config = match config::file::try_read(path) {
+        Ok(config) => config,
+        Err(err) => crashln!("{} {path} could not be parsed.\n{}", "✖".red(), err.white()),
+    };
+
+    let problems = config.check();
+    if problems.is_empty() {
+        return println!("{} {path} is valid ({} backends, {} providers)", "✔".green(), config.backends.len(), config.providers.len());
+    }
+
+    for problem in problems.iter() {
+        eprintln!("{} {problem}", "✖".red());
+    }
+
+    crashln!("{} problem(s) found in {path}", problems.len());
+}
+
+fn user(command: UserCommand, conn: &mut Connection) {
+    match command {
+        UserCommand::Add(add) => {
+            let password = ternary!(add.providers.iter().any(|p| p == "basic"), read_password(add.password), string!());
+            let user_dto = UserDTO {
+                admin: add.admin,
+                username: add.username.to_lowercase(),
+                email: add.email.to_lowercase(),
+                password,
+                tokens: vec![],
+                services: add.services,
+                providers: add.providers,
+            };
+
+            match User::signup(user_dto, conn) {
+                Ok(user) => {
+                    let details = json!({ "id": user.id, "email": user.email, "admin": user.admin, "services": user.services, "providers": user.providers });
+                    audited("user.create", &user.username, details, conn);
+                    println!("{} created user {} ({})", "✔".green(), user.username.bold(), user.id)
+                }
+                Err(err) => crashln!("Unable to create user.\n{}", string!(err).white()),
+            }
+        }
+        UserCommand::List => {
+            let users = User::all(conn).unwrap_or_else(|err| crashln!("Unable to list users.\n{}", string!(err).white()));
+
+            println!("{:<6} {:<20} {:<32} {:<6} {:<9} SERVICES", "ID", "USERNAME", "EMAIL", "ADMIN", "DISABLED");
+            for user in users {
+                println!("{:<6} {:<20} {:<32} {:<6} {:<9} {}", user.id, user.username, user.email, user.admin, user.disabled, user.services.join(","));
+            }
+        }
+        UserCommand::Passwd { username, password } => {
+            let user = find_user(&username, conn);
+            let changes = UserUpdateDTO {
+                password: Some(read_password(password)),
+                ..Default::default()
+            };
+
+            match User::update(user.id, changes, conn) {
+                Ok(user) => {
+                    audited("user.update", &user.username, json!({ "password_changed": true }), conn);
+                    println!("{} updated password for {}", "✔".green(), user.username.bold())
+                }
+                Err(err) => crashln!("Unable to update password.\n{}", string!(err).white()),
+            }
+        }
+        UserCommand::Delete { username } => {
+            let user = find_user(&username, conn);
+
+            match User::delete(user.id, conn) {
+                Ok(_) => {
+                    audited("user.delete", &user.username, json!({ "id": user.id, "email": user.email }), conn);
+                    println!("{} deleted user {}", "✔".green(), user.username.bold())
+                }
+                Err(err) => crashln!("Unable to delete user.\n{}", string!(err).white()),
+            }
+        }
+        UserCommand::Promote { username, revoke } => {
+            let user = find_user(&username, conn);
+            let changes = UserUpdateDTO { admin: Some(!revoke), ..Default::default() };
+
+            match User::update(user.id, changes, conn) {
+                Ok(user) => {
+                    audited("user.update", &user.username, json!({ "admin": user.admin }), conn);
+                    println!("{} {} is {} an administrator", "✔".green(), user.username.bold(), ternary!(user.admin, "now", "no longer"))
+                }
+                Err(err) => crashln!("Unable to update user.\n{}", string!(err).white()),
+            }
+        }
+    }
+}
+
+fn session(command: SessionCommand, conn: &mut Connection) {
+    match command {
+        SessionCommand::List { user } => {
+            let sessions = Session::all_active(conn).unwrap_or_else(|err| crashln!("Unable to list sessions.\n{}", string!(err).white()));
+            let filter = user.map(|name| name.to_lowercase());
+
+            println!("{:<38} {:<20} {:<20} {:<20} {:<16} USER AGENT", "ID", "USER", "CREATED", "LAST SEEN", "IP");
+            for (session, user) in sessions.iter().filter(|(_, user)| filter.as_ref().is_none_or(|name| &user.username == name)) {
+                println!(
+                    "{:<38} {:<20} {:<20} {:<20} {:<16} {}",
+                    session.id,
+                    user.username,
+                    session.created_at.format("%Y-%m-%d %H:%M:%S"),
+                    session.last_seen.format("%Y-%m-%d %H:%M:%S"),
+                    session.ip.as_deref().unwrap_or("-"),
+                    session.user_agent.as_deref().unwrap_or("-")
+                );
+            }
+        }
+        SessionCommand::Revoke { id, user } => {
+            let (revoked, target) = match (id, user) {
+                (Some(id), _) => (Session::revoke(&id, conn), id),
+                (None, Some(username)) => {
+                    let user = find_user(&username, conn);
+                    (Session::revoke_all(user.id, conn), user.username)
+                }
+                (None, None) => crashln!("Pass a session id or --user."),
+            };
+
+            match revoked {
+                Ok(count) => {
+                    audited("session.revoke", &target, json!({ "sessions": count }), conn);
+                    println!("{} revoked {count} session(s)", "✔".green())
+                }
+                Err(err) => crashln!("Unable to revoke sessions.\n{}", string!(err).white()),
+            }
+        }
+    }
+}
+
+fn history(user: Option<String>, failed: bool, limit: i64, conn: &mut Connection) {
+    let user_id = user.map(|username| find_user(&username, conn).id);
+    let history = LoginHistory::find(user_id, ternary!(failed, Some(false), None), limit.max(1), conn).unwrap_or_else(|err| crashln!("Unable to read login history.\n{}", string!(err).white()));
+
+    println!("{:<20} {:<20} {:<8} {:<10} {:<16} {:<38} {:<24} USER AGENT", "TIME", "USER", "RESULT", "METHOD", "IP", "SESSION", "REASON");
+    for (entry, username) in history {
+        println!(
+            "{:<20} {:<20} {:<8} {:<10} {:<16} {:<38} {:<24} {}",
+            entry.login_timestamp.format("%Y-%m-%d %H:%M:%S"),
+            username.as_deref().unwrap_or("(deleted)"),
+            ternary!(entry.success, "ok", "failed"),
+            entry.method,
+            entry.ip.as_deref().unwrap_or("-"),
+            entry.session_id.as_deref().unwrap_or("-"),
+            entry.reason.as_deref().unwrap_or("-"),
+            entry.user_agent.as_deref().unwrap_or("-")
+        );
+    }
+}
+
+fn audit(command: AuditCommand, conn: &mut Connection) {
+    match command {
+        AuditCommand::Verify => match crate::audit::verify(conn) {
+            Ok(Ok(verified)) => println!("{} {} event(s) verified, latest hash {}", "✔".green(), verified.events, verified.head),
+            Ok(Err(broken)) => crashln!("{} event {} {}", "✖".red(), broken.id, broken.problem),
+            Err(err) => crashln!("Unable to read the audit log.\n{}", string!(err).white()),
+        },
+        AuditCommand::Export { output, after } => {
+            let out: Box<dyn Write> = match &output {
+                Some(file) => Box::new(File::create(file).unwrap_or_else(|err| crashln!("Unable to create {file}.\n{}", string!(err).white()))),
+                None => Box::new(io::stdout().lock()),
+            };
+
+            let mut out = BufWriter::new(out);
+            let mut after = after;
+            let mut count = 0;
+
+            loop {
+                let page = AuditEvent::page(after, crate::audit::PAGE_SIZE, conn).unwrap_or_else(|err| crashln!("Unable to read the audit log.\n{}", string!(err).white()));
+                let Some(last) = page.last() else { break };
+                after = last.id;
+
+                for event in page {
+                    let line = serde_json::to_string(&event).unwrap_or_default();
+                    if let Err(err) = writeln!(out, "{line}") {
+                        crashln!("Unable to write events.\n{}", string!(err).white());
+                    }
+                    count += 1;
+                }
+            }
+
+            if let Err(err) = out.flush() {
+                crashln!("Unable to write events.\n{}", string!(err).white());
+            }
+
+            if let Some(file) = output {
+                println!("{} exported {count} event(s) to {file}", "✔".green());
+            }
+        }
+    }
 }
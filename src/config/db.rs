@@ -10,21 +10,27 @@ pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
 pub type Connection = PgConnection;
 pub type Pool = r2d2::Pool<ConnectionManager<Connection>>;
+pub type PooledConnection = r2d2::PooledConnection<ConnectionManager<Connection>>;
 
-pub fn init_db(path: &String) -> Pool {
+pub fn init_db(path: &str) -> Pool {
     let config = Config::new().set_path(path).read();
 
-    let pool = r2d2::Pool::builder()
+    r2d2::Pool::builder()
         .max_size(16)
         .build(ConnectionManager::<Connection>::new(config.get_database()))
-        .expect("Failed to create pool.");
-
-    return pool;
+        .expect("Failed to create pool.")
 }
 
 pub fn run_migrations(conn: &mut impl MigrationHarness<Pg>) {
-    match conn.run_pending_migrations(MIGRATIONS) {
+    match try_run_migrations(conn) {
         Ok(_) => tracing::info!("migrated records"),
-        Err(err) => tracing::error!(err = err.to_string(), "error migrating records"),
+        Err(err) => tracing::error!(err, "error migrating records"),
+    }
+}
+
+pub fn try_run_migrations(conn: &mut impl MigrationHarness<Pg>) -> Result<usize, String> {
+    match conn.run_pending_migrations(MIGRATIONS) {
+        Ok(applied) => Ok(applied.len()),
+        Err(err) => Err(err.to_string()),
     }
 }
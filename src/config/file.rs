@@ -1,10 +1,7 @@
 use super::structs::Config;
 
-pub fn read(path: &String) -> Config {
-    let contents = match std::fs::read_to_string(path) {
-        Ok(contents) => contents,
-        Err(_) => String::from(""),
-    };
+pub fn read(path: &str) -> Config {
+    let contents = std::fs::read_to_string(path).unwrap_or_default();
 
     match toml::from_str(&contents) {
         Ok(parsed) => parsed,
@@ -14,3 +11,8 @@ pub fn read(path: &String) -> Config {
         }
     }
 }
+
+pub fn try_read(path: &str) -> Result<Config, String> {
+    let contents = std::fs::read_to_string(path).map_err(|err| format!("unable to read {path}: {err}"))?;
+    toml::from_str(&contents).map_err(|err| err.to_string())
+}
@@ -0,0 +1,197 @@
+use arc_swap::ArcSwap;
+use futures::future::{ok, Ready};
+use std::{convert::Infallible, marker::PhantomData, ops::Deref, sync::Arc};
+
+use super::{db::Pool, file, routes::Routes, structs::Config};
+use crate::models::service::Service;
+
+use actix_web::{dev::Payload, web::Data, FromRequest, HttpMessage, HttpRequest};
+
+/// A config together with the routing table built from it.
+pub struct Snapshot {
+    pub config: Config,
+    pub routes: Routes,
+}
+
+/// The snapshot requests are served with, replaced whole when the config file changes.
+pub type Shared = ArcSwap<Snapshot>;
+
+impl Snapshot {
+    /// Reads the config with the command line overrides applied and the stored
+    /// services merged in. Unlike [`Config::read`] a file that does not parse is
+    /// an error, not the defaults. When the database cannot be reached the
+    /// stored services of `previous` stay, so a file edit still applies.
+    pub fn read(cli: &crate::Cli, pool: &Pool, previous: Option<&Snapshot>) -> Result<Self, Vec<String>> {
+        let mut config = file::try_read(&cli.config).map_err(|err| vec![err])?;
+        config.set_path(&cli.config);
+
+        let services = pool.get().map_err(|err| err.to_string()).and_then(|mut conn| Service::all(&mut conn).map_err(|err| err.to_string()));
+        match (services, previous) {
+            (Ok(services), _) => config.merge_services(services),
+            (Err(err), Some(previous)) => {
+                tracing::warn!("services: {err}, keeping the ones already loaded");
+                config.keep_services(&previous.config);
+            }
+            (Err(err), None) => return Err(vec![format!("services: {err}")]),
+        }
+
+        if let Some(port) = cli.port {
+            config.override_port(port)
+        }
+
+        if let Some(address) = cli.address.clone() {
+            config.override_address(address)
+        }
+
+        // the same checks as `zerotrust check`, so a reload cannot swap in what it would reject
+        let problems = config.check();
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
+        let routes = Routes::new(&config)?;
+        Ok(Self { config, routes })
+    }
+
+    /// Whether `next` changes what the server listens on or the paths it
+    /// serves its own pages from, which only a restart can apply. Routes and
+    /// everything else are read from the snapshot on every request.
+    pub fn needs_restart(&self, next: &Snapshot) -> bool {
+        let listener = |config: &Config| {
+            let server = &config.settings.server;
+            let tls = server.tls.as_ref().map(|tls| (tls.redirect_port, tls.acme.as_ref().map(|acme| acme.storage.is_none())));
+
+            (config.get_address(), tls, server.prefix.clone(), server.files.clone())
+        };
+
+        listener(&self.config) != listener(&next.config)
+    }
+}
+
+impl AsRef<Config> for Snapshot {
+    fn as_ref(&self) -> &Config { &self.config }
+}
+
+impl AsRef<Routes> for Snapshot {
+    fn as_ref(&self) -> &Routes { &self.routes }
+}
+
+/// The config or routing table of the snapshot a request started with. The
+/// snapshot is kept on the request, so a reload landing halfway through does
+/// not mix two configs.
+pub struct Live<T> {
+    snapshot: Arc<Snapshot>,
+    part: PhantomData<fn() -> T>,
+}
+
+impl<T> Live<T>
+where
+    Snapshot: AsRef<T>,
+{
+    pub fn from_req(req: &HttpRequest) -> Self {
+        if let Some(snapshot) = req.extensions().get::<Arc<Snapshot>>() {
+            return Self {
+                snapshot: snapshot.clone(),
+                part: PhantomData,
+            };
+        }
+
+        let snapshot = req.app_data::<Data<Shared>>().expect("config is not registered").load_full();
+        req.extensions_mut().insert(snapshot.clone());
+
+        Self { snapshot, part: PhantomData }
+    }
+
+    pub fn get_ref(&self) -> &T { (*self.snapshot).as_ref() }
+}
+
+impl<T> Deref for Live<T>
+where
+    Snapshot: AsRef<T>,
+{
+    type Target = T;
+    fn deref(&self) -> &T { self.get_ref() }
+}
+
+impl<T> AsRef<T> for Live<T>
+where
+    Snapshot: AsRef<T>,
+{
+    fn as_ref(&self) -> &T { self.get_ref() }
+}
+
+impl<T> FromRequest for Live<T>
+where
+    Snapshot: AsRef<T>,
+{
+    type Error = Infallible;
+    type Future = Ready<Result<Self, Infallible>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future { ok(Self::from_req(req)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::structs::Location;
+    use clap::Parser;
+    use diesel::r2d2::ConnectionManager;
+    use macros_rs::string;
+    use std::{fs, time::Duration};
+
+    fn valid() -> Config {
+        let mut config = Config::new();
+        let backend = "display_name = \"App\"\nproviders = []\naddress = \"127.0.0.1\"\nport = 3000\nhosts = [\"app.test\"]";
+
+        config.settings.secret = string!("test secret");
+        config.settings.database.user = string!("z");
+        config.settings.database.name = string!("z");
+        config.settings.database.address = string!("localhost");
+        config.backends.insert(string!("app"), toml::from_str::<Location>(backend).unwrap());
+        config
+    }
+
+    /// Reads `config` back from a file as a reload would, with the database
+    /// out of reach so the services of `previous` are kept.
+    fn reload(config: &Config, previous: &Snapshot) -> Result<Snapshot, Vec<String>> {
+        let path = std::env::temp_dir().join(format!("zerotrust-live-{}.toml", std::process::id()));
+        fs::write(&path, toml::to_string(config).unwrap()).unwrap();
+
+        let cli = crate::Cli::parse_from(["zerotrust", "--config", path.to_str().unwrap()]);
+        let pool = Pool::builder()
+            .connection_timeout(Duration::from_millis(200))
+            .build_unchecked(ConnectionManager::new("postgres://z@127.0.0.1:1/z"));
+        let next = Snapshot::read(&cli, &pool, Some(previous));
+
+        fs::remove_file(path).unwrap();
+        next
+    }
+
+    #[test]
+    fn reloads_are_checked_like_the_check_command() {
+        let config = valid();
+        let previous = Snapshot {
+            routes: Routes::new(&config).unwrap(),
+            config,
+        };
+
+        let next = reload(&previous.config, &previous).unwrap();
+        assert!(next.routes.get("app").is_some());
+
+        let mut invalid = valid();
+        invalid.settings.rate_limit_store = string!("redis");
+        invalid.settings.trusted_proxies = vec![string!("nowhere")];
+
+        let Err(problems) = reload(&invalid, &previous) else {
+            panic!("an invalid config was loaded");
+        };
+
+        assert_eq!(
+            problems,
+            [
+                "settings.rate_limit_store: 'redis' is not memory or database",
+                "settings.trusted_proxies: 'nowhere' is not an address or CIDR range",
+            ]
+        );
+    }
+}
@@ -1,14 +1,17 @@
 pub mod db;
 pub mod file;
+pub mod live;
+pub mod routes;
 pub mod structs;
 
+use actix_web::http::header::HeaderName;
 use colored::Colorize;
-use macros_rs::{clone, crashln, folder_exists, string, ternary};
-use std::{collections::BTreeMap, fs};
-use structs::{App, Backend, Config, Database, Server, Settings};
+use macros_rs::{crashln, folder_exists, string};
+use std::{collections::BTreeMap, fs, path::Path};
+use structs::{App, Config, Database, Identity, Location, LoginLimits, Server, Settings, Source};
 use toml_edit::Document;
 
-type Backends = BTreeMap<String, Backend>;
+use crate::models::service::Service;
 
 impl Config {
     pub fn new() -> Self {
@@ -23,6 +26,13 @@ impl Config {
             config_path: "config.toml".into(),
             settings: Settings {
                 secret: "CHANGE ME".into(),
+                require_2fa: false,
+                rp_id: None,
+                identity: Identity::default(),
+                login: LoginLimits::default(),
+                rate_limit_store: "memory".into(),
+                metrics: None,
+                trusted_proxies: vec![],
                 max_age: 604800,
                 database: Database {
                     name: "".into(),
@@ -36,6 +46,8 @@ impl Config {
                     files: "static_files".into(),
                     address: "127.0.0.1".into(),
                     port: 8080,
+                    external_url: None,
+                    tls: None,
                 },
                 app: App {
                     name: "Zerotrust".into(),
@@ -48,19 +60,19 @@ impl Config {
         }
     }
 
-    pub fn set_path(&mut self, config_path: &String) -> &mut Self {
-        self.config_path = config_path.clone();
-        return self;
+    pub fn set_path(&mut self, config_path: &str) -> &mut Self {
+        self.config_path = config_path.to_string();
+        self
     }
 
     pub fn create_dirs(&self) -> &Self {
         let file_path = self.get_static();
 
         if !folder_exists!(&file_path) {
-            fs::create_dir(file_path.to_string()).unwrap();
+            fs::create_dir(&file_path).unwrap();
         }
 
-        return self;
+        self
     }
 
     pub fn write(&self) -> &Self {
@@ -75,43 +87,221 @@ impl Config {
 
         tracing::info!(path = self.config_path, created = true, "config");
 
-        return self;
+        self
     }
 
-    pub fn backends(&self) -> Backends {
-        let mut backends: Backends = BTreeMap::new();
+    /// A backend's own `require_2fa` wins over the global setting, so single
+    /// services can opt in or out.
+    pub fn requires_2fa(&self, service: Option<&str>) -> bool {
+        match service.and_then(|name| self.backends.get(name)).and_then(|item| item.require_2fa) {
+            Some(required) => required,
+            None => self.settings.require_2fa,
+        }
+    }
+
+    /// Lists every problem that would stop the config from working, without exiting.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let database = &self.settings.database;
+
+        if database.user.is_empty() || database.name.is_empty() || database.address.is_empty() {
+            problems.push(string!("settings.database: user, name and address are required"));
+        }
+
+        if self.settings.secret == "CHANGE ME" || self.settings.secret.is_empty() {
+            problems.push(string!("settings.secret: must be changed from the default"));
+        }
+
+        if let Some(tls) = &self.settings.server.tls {
+            for path in tls.paths().into_iter().filter(|path| !Path::new(path).is_file()) {
+                problems.push(format!("settings.server.tls: '{path}' does not exist"));
+            }
+
+            if tls.cert.is_some() != tls.key.is_some() || (tls.cert.is_none() && tls.acme.is_none()) {
+                problems.push(string!("settings.server.tls: cert and key are required unless acme is configured"));
+            }
+
+            match tls.acme.as_ref().map(|acme| acme.challenge.as_str()) {
+                None | Some("tls-alpn-01") => {}
+                Some("http-01") if tls.redirect_port.is_some() => {}
+                Some("http-01") => problems.push(string!("settings.server.tls.acme: http-01 needs redirect_port to answer challenges")),
+                Some(other) => problems.push(format!("settings.server.tls.acme.challenge: '{other}' is not http-01 or tls-alpn-01")),
+            }
+
+            if tls.acme.as_ref().is_some_and(|acme| !acme.accept_terms) {
+                problems.push(string!("settings.server.tls.acme: set accept_terms once you agree to the directory's terms of service"));
+            }
+        }
+
+        for (field, value) in self.settings.identity.fields() {
+            if !value.is_empty() && HeaderName::from_bytes(value.as_bytes()).is_err() {
+                problems.push(format!("settings.identity.{field}: '{value}' is not a valid header name"));
+            }
+        }
+
+        if !["memory", "database"].contains(&self.settings.rate_limit_store.as_str()) {
+            problems.push(format!("settings.rate_limit_store: '{}' is not memory or database", self.settings.rate_limit_store));
+        }
+
+        let not_address = |entry: &&String| entry.parse::<ipnet::IpNet>().is_err() && entry.parse::<std::net::IpAddr>().is_err();
+        for entry in self.settings.trusted_proxies.iter().filter(not_address) {
+            problems.push(format!("settings.trusted_proxies: '{entry}' is not an address or CIDR range"));
+        }
+
+        if let Some(metrics) = &self.settings.metrics {
+            for entry in metrics.allow.iter().filter(not_address) {
+                problems.push(format!("settings.metrics.allow: '{entry}' is not an address or CIDR range"));
+            }
+
+            if metrics.token.as_ref().is_some_and(|token| token.trim().is_empty()) {
+                problems.push(string!("settings.metrics.token: must not be empty"));
+            }
+
+            if metrics.allow.is_empty() && metrics.token.is_none() {
+                problems.push(string!("settings.metrics: set allow or token, nobody can read metrics otherwise"));
+            }
+        }
+
+        let login = &self.settings.login;
+        if login.window == 0 || login.max_failures == 0 || login.max_per_ip == 0 {
+            problems.push(string!("settings.login: window, max_failures and max_per_ip must be greater than zero"));
+        }
+
+        for (name, provider) in self.providers.iter() {
+            for (field, value) in [("auth_url", &provider.auth_url), ("token_url", &provider.token_url)] {
+                if url::Url::parse(value).is_err() {
+                    problems.push(format!("providers.{name}.{field}: '{value}' is not a valid url"));
+                }
+            }
+        }
+
+        problems.extend(self.check_backends());
+        problems
+    }
+
+    /// The part of [`Config::check`] a routing table cannot be built without.
+    pub fn check_backends(&self) -> Vec<String> { self.backends.iter().flat_map(|(name, item)| self.check_backend(name, item)).collect() }
+
+    pub fn check_backend(&self, name: &str, item: &Location) -> Vec<String> {
+        let mut problems = vec![];
+
+        if item.upstreams.is_empty() && (item.address.is_empty() || url::Url::parse(&format!("http://{}:{}", item.address, item.port)).is_err()) {
+            problems.push(format!("backends.{name}: '{}:{}' is not a valid address", item.address, item.port));
+        }
+
+        for upstream in item.upstreams.iter() {
+            if upstream.address.is_empty() || url::Url::parse(&format!("http://{}:{}", upstream.address, upstream.port)).is_err() {
+                problems.push(format!("backends.{name}.upstreams: '{}:{}' is not a valid address", upstream.address, upstream.port));
+            }
+
+            if upstream.weight == 0 {
+                problems.push(format!("backends.{name}.upstreams: '{}:{}' needs a weight of at least 1", upstream.address, upstream.port));
+            }
+        }
+
+        if !["round-robin", "least-connections", "hash"].contains(&item.balance.as_str()) {
+            problems.push(format!("backends.{name}.balance: '{}' is not round-robin, least-connections or hash", item.balance));
+        }
+
+        if let Some(path) = item.health.path.as_ref().filter(|path| !path.starts_with('/')) {
+            problems.push(format!("backends.{name}.health.path: '{path}' must start with '/'"));
+        }
+
+        if item.health.interval == 0 || item.health.timeout == 0 || item.health.healthy_threshold == 0 || item.health.unhealthy_threshold == 0 {
+            problems.push(format!("backends.{name}.health: interval, timeout and thresholds must be at least 1"));
+        }
+
+        if item.timeouts.connect == 0 || item.timeouts.read == 0 || item.timeouts.total == 0 {
+            problems.push(format!("backends.{name}.timeouts: connect, read and total must be at least 1"));
+        }
+
+        if item.pool_size == 0 {
+            problems.push(format!("backends.{name}.pool_size: must be at least 1"));
+        }
+
+        if let Some(limit) = &item.rate_limit {
+            if !["user", "ip", "token"].contains(&limit.key.as_str()) {
+                problems.push(format!("backends.{name}.rate_limit.key: '{}' is not user, ip or token", limit.key));
+            }
+
+            if limit.rate.is_nan() || limit.rate <= 0.0 || limit.burst == 0 {
+                problems.push(format!("backends.{name}.rate_limit: rate and burst must be greater than zero"));
+            }
+        }
+
+        if item.hash_on != "user" && item.hash_on.strip_prefix("cookie:").is_none_or(str::is_empty) {
+            problems.push(format!("backends.{name}.hash_on: '{}' is not user or cookie:<name>", item.hash_on));
+        }
+
+        for host in item.hosts.iter().filter(|host| host.is_empty() || host.trim_start_matches("*.").contains('*')) {
+            problems.push(format!("backends.{name}.hosts: '{host}' is not a valid host pattern"));
+        }
+
+        if let Some(prefix) = item.path_prefix.as_ref().filter(|prefix| !prefix.starts_with('/')) {
+            problems.push(format!("backends.{name}.path_prefix: '{prefix}' must start with '/'"));
+        }
 
-        for (name, item) in self.backends.iter() {
-            let tls = match item.tls {
-                None => "http",
-                Some(is_tls) => ternary!(is_tls, "https", "http"),
+        for (field, list) in [("allow_headers", &item.allow_headers), ("deny_headers", &item.deny_headers)] {
+            for pattern in list.iter().filter(|pattern| HeaderName::from_bytes(pattern.strip_suffix('*').unwrap_or(pattern).as_bytes()).is_err()) {
+                problems.push(format!("backends.{name}.{field}: '{pattern}' is not a valid header name"));
+            }
+        }
+
+        for provider in item.providers.iter().filter(|provider| *provider != "basic" && !self.providers.contains_key(*provider)) {
+            problems.push(format!("backends.{name}.providers: '{provider}' is not configured"));
+        }
+
+        problems
+    }
+
+    /// Adds the services stored in the database to `backends`. A backend in the
+    /// file wins over a stored one of the same name, and a stored service that
+    /// does not check out is left out instead of failing the whole config.
+    pub fn merge_services(&mut self, services: Vec<Service>) {
+        for service in services {
+            if self.backends.contains_key(&service.name) {
+                tracing::warn!(service = service.name, "stored service is shadowed by the config file");
+                continue;
+            }
+
+            let mut item: Location = match serde_json::from_value(service.location) {
+                Ok(item) => item,
+                Err(err) => {
+                    tracing::error!(service = service.name, "stored service left out: {err}");
+                    continue;
+                }
             };
 
-            let url = format!("{tls}://{}:{}", item.address, item.port);
+            let problems = self.check_backend(&service.name, &item);
+            if !problems.is_empty() {
+                tracing::error!(service = service.name, problems = problems.join(", "), "stored service left out");
+                continue;
+            }
 
-            backends.insert(
-                name.clone(),
-                Backend {
-                    providers: clone!(item.providers),
-                    url: url::Url::parse(&url).unwrap(),
-                },
-            );
+            item.source = Source::Db;
+            self.backends.insert(service.name, item);
         }
+    }
 
-        return backends;
+    /// Takes over the stored services of `previous` that the config file does not shadow.
+    pub fn keep_services(&mut self, previous: &Config) {
+        for (name, item) in previous.backends.iter().filter(|(_, item)| item.source == Source::Db) {
+            self.backends.entry(name.clone()).or_insert_with(|| item.clone());
+        }
     }
 
     pub fn get_database(&self) -> String {
-        if self.settings.database.user == "" || self.settings.database.name == "" || self.settings.database.address == "" {
+        if self.settings.database.user.is_empty() || self.settings.database.name.is_empty() || self.settings.database.address.is_empty() {
             crashln!("Invalid postgres details, check configuration file!");
         }
 
         format!(
-            "postgres://{username}:{password}@{server}/{db_name}",
+            "postgres://{username}:{password}@{addr}:{port}/{db_name}",
             username = self.settings.database.user,
             password = self.settings.database.password,
             db_name = self.settings.database.name,
-            server = format!("{addr}:{port}", addr = self.settings.database.address, port = self.settings.database.port)
+            addr = self.settings.database.address,
+            port = self.settings.database.port
         )
     }
 
@@ -126,3 +316,30 @@ impl Config {
     pub fn get_address(&self) -> (String, u16) { (self.settings.server.address.to_string(), self.settings.server.port) }
     pub fn edit(&self) -> Document { toml::to_string(self).unwrap().parse::<Document>().expect("Invalid config") }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend(source: Source) -> Location {
+        let mut item = toml::from_str::<Location>("display_name = \"App\"\nproviders = []\naddress = \"127.0.0.1\"\nport = 3000\nhosts = [\"app.test\"]").unwrap();
+        item.source = source;
+        item
+    }
+
+    #[test]
+    fn keep_services_only_carries_stored_services_over() {
+        let mut previous = Config::new();
+        previous.backends.insert(string!("file"), backend(Source::File));
+        previous.backends.insert(string!("stored"), backend(Source::Db));
+        previous.backends.insert(string!("shadowed"), backend(Source::Db));
+
+        let mut next = Config::new();
+        next.backends.insert(string!("shadowed"), backend(Source::File));
+        next.keep_services(&previous);
+
+        assert_eq!(next.backends.keys().collect::<Vec<_>>(), ["shadowed", "stored"]);
+        assert_eq!(next.backends["shadowed"].source, Source::File);
+        assert_eq!(next.backends["stored"].source, Source::Db);
+    }
+}
@@ -0,0 +1,232 @@
+use macros_rs::{clone, ternary};
+use std::collections::BTreeMap;
+use url::Url;
+
+use super::structs::{Backend, Config, Target};
+
+/// Every backend with its upstreams parsed and its host patterns normalized,
+/// built once per config load and shared by all workers.
+pub struct Routes {
+    backends: BTreeMap<String, Backend>,
+}
+
+impl Routes {
+    /// Fails with every problem `Config::check_backends` finds, so a bad
+    /// backend stops the load instead of a request.
+    pub fn new(config: &Config) -> Result<Self, Vec<String>> {
+        let problems = config.check_backends();
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
+        let mut backends = BTreeMap::new();
+
+        for (name, item) in config.backends.iter() {
+            let targets = match item.upstreams.is_empty() {
+                true => vec![Target {
+                    url: upstream_url(&item.address, item.port, item.tls).map_err(|err| vec![format!("backends.{name}: {err}")])?,
+                    weight: 1,
+                }],
+                false => item
+                    .upstreams
+                    .iter()
+                    .map(|upstream| {
+                        Ok(Target {
+                            url: upstream_url(&upstream.address, upstream.port, upstream.tls.or(item.tls)).map_err(|err| vec![format!("backends.{name}.upstreams: {err}")])?,
+                            weight: upstream.weight,
+                        })
+                    })
+                    .collect::<Result<_, Vec<String>>>()?,
+            };
+
+            backends.insert(
+                name.clone(),
+                Backend {
+                    targets,
+                    balance: clone!(item.balance),
+                    hash_on: clone!(item.hash_on),
+                    health: clone!(item.health),
+                    timeouts: clone!(item.timeouts),
+                    retries: item.retries,
+                    retry_backoff: item.retry_backoff,
+                    pool_size: item.pool_size,
+                    pool_idle_timeout: item.pool_idle_timeout,
+                    rate_limit: clone!(item.rate_limit),
+                    providers: clone!(item.providers),
+                    hosts: item.hosts.iter().map(|host| host.to_lowercase()).collect(),
+                    path_prefix: item.path_prefix.as_ref().map(|prefix| prefix.trim_end_matches('/').to_string()),
+                },
+            );
+        }
+
+        Ok(Self { backends })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Backend> { self.backends.get(name) }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Backend)> { self.backends.iter() }
+
+    /// Whether any backend answers for `host`, by name or wildcard.
+    pub fn serves_host(&self, host: &str) -> bool {
+        let host = normalize_host(host);
+        self.backends.values().any(|backend| backend.hosts.iter().any(|pattern| matches_host(host, pattern).is_some()))
+    }
+
+    /// Picks the backend for a request. Exact hosts beat wildcard hosts, which beat
+    /// backends matched on `path_prefix` alone; within a tier the longest matching
+    /// prefix wins and remaining ties go to the first backend by name. The
+    /// `SelectService` header is only consulted when nothing else matched.
+    pub fn route(&self, host: Option<&str>, path: &str, header: Option<&str>) -> Option<&str> {
+        let host = host.map(normalize_host);
+        let mut matched: Option<((u8, usize, usize), &String)> = None;
+
+        for (name, backend) in self.backends.iter() {
+            let prefix_len = match &backend.path_prefix {
+                Some(prefix) if matches_prefix(path, prefix) => prefix.len(),
+                Some(_) => continue,
+                None => 0,
+            };
+
+            let (tier, specificity) = match (host, backend.hosts.is_empty()) {
+                (_, true) if backend.path_prefix.is_some() => (1, 0),
+                (Some(host), false) => match backend.hosts.iter().filter_map(|pattern| matches_host(host, pattern)).max() {
+                    Some(rank) => rank,
+                    None => continue,
+                },
+                _ => continue,
+            };
+
+            let rank = (tier, specificity, prefix_len);
+            if matched.is_none_or(|(best, _)| rank > best) {
+                matched = Some((rank, name));
+            }
+        }
+
+        match matched {
+            Some((_, name)) => Some(name.as_str()),
+            None => header.and_then(|name| self.backends.get_key_value(name)).map(|(name, _)| name.as_str()),
+        }
+    }
+}
+
+fn upstream_url(address: &str, port: u16, tls: Option<bool>) -> Result<Url, String> {
+    let scheme = match tls {
+        None => "http",
+        Some(is_tls) => ternary!(is_tls, "https", "http"),
+    };
+
+    Url::parse(&format!("{scheme}://{address}:{port}")).map_err(|err| format!("'{address}:{port}' is not a valid address, {err}"))
+}
+
+fn normalize_host(host: &str) -> &str {
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or(v6),
+        None => host.split(':').next().unwrap_or(host),
+    };
+
+    host.trim_end_matches('.')
+}
+
+fn strip_suffix_ignore_case<'a>(host: &'a str, suffix: &str) -> Option<&'a str> {
+    let split = host.len().checked_sub(suffix.len())?;
+    ternary!(host.get(split..)?.eq_ignore_ascii_case(suffix), host.get(..split), None)
+}
+
+/// `pattern` is already lowercase, `host` is compared without allocating.
+fn matches_host(host: &str, pattern: &str) -> Option<(u8, usize)> {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => {
+            let label = strip_suffix_ignore_case(host, suffix)?.strip_suffix('.')?;
+            ternary!(!label.is_empty() && !label.contains('.'), Some((2, suffix.len())), None)
+        }
+        None => ternary!(host.eq_ignore_ascii_case(pattern), Some((3, pattern.len())), None),
+    }
+}
+
+/// `prefix` has its trailing slashes trimmed already.
+fn matches_prefix(path: &str, prefix: &str) -> bool { prefix.is_empty() || path == prefix || path.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/')) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::structs::Location;
+    use macros_rs::string;
+
+    fn routes(backends: &[(&str, &str)]) -> Routes {
+        let mut config = Config::new();
+
+        for (name, extra) in backends {
+            let backend = format!("display_name = \"{name}\"\nproviders = []\naddress = \"127.0.0.1\"\nport = 3000\n{extra}");
+            config.backends.insert(string!(*name), toml::from_str::<Location>(&backend).unwrap());
+        }
+
+        Routes::new(&config).unwrap()
+    }
+
+    #[test]
+    fn wildcards_match_a_single_label() {
+        assert_eq!(matches_host("a.apps.test", "*.apps.test"), Some((2, 9)));
+        assert_eq!(matches_host("A.Apps.Test", "*.apps.test"), Some((2, 9)));
+        assert_eq!(matches_host("apps.test", "*.apps.test"), None);
+        assert_eq!(matches_host(".apps.test", "*.apps.test"), None);
+        assert_eq!(matches_host("a.b.apps.test", "*.apps.test"), None);
+        assert_eq!(matches_host("a.xapps.test", "*.apps.test"), None);
+        assert_eq!(matches_host("App.Test", "app.test"), Some((3, 8)));
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        assert!(matches_prefix("/api", "/api"));
+        assert!(matches_prefix("/api/users", "/api"));
+        assert!(!matches_prefix("/apix", "/api"));
+        assert!(!matches_prefix("/", "/api"));
+        assert!(matches_prefix("/anything", ""));
+    }
+
+    #[test]
+    fn most_specific_host_wins() {
+        let routes = routes(&[
+            ("exact", "hosts = [\"a.apps.test\"]"),
+            ("wildcard", "hosts = [\"*.apps.test\"]"),
+            ("prefix", "hosts = []\npath_prefix = \"/api\""),
+        ]);
+
+        assert_eq!(routes.route(Some("a.apps.test"), "/api", None), Some("exact"));
+        assert_eq!(routes.route(Some("b.apps.test"), "/api", None), Some("wildcard"));
+        assert_eq!(routes.route(Some("other.test"), "/api/users", None), Some("prefix"));
+        assert_eq!(routes.route(Some("other.test"), "/apix", None), None);
+    }
+
+    #[test]
+    fn longest_prefix_wins_within_a_tier() {
+        let routes = routes(&[
+            ("api", "hosts = [\"app.test\"]\npath_prefix = \"/api/\""),
+            ("admin", "hosts = [\"app.test\"]\npath_prefix = \"/api/admin\""),
+            ("site", "hosts = [\"app.test\"]"),
+        ]);
+
+        assert_eq!(routes.route(Some("app.test"), "/api/admin/users", None), Some("admin"));
+        assert_eq!(routes.route(Some("app.test"), "/api/adminx", None), Some("api"));
+        assert_eq!(routes.route(Some("app.test"), "/apix", None), Some("site"));
+    }
+
+    #[test]
+    fn hosts_are_normalized() {
+        let routes = routes(&[("app", "hosts = [\"App.Test\"]")]);
+
+        assert_eq!(routes.route(Some("app.test:8443"), "/", None), Some("app"));
+        assert_eq!(routes.route(Some("APP.TEST."), "/", None), Some("app"));
+        assert!(routes.serves_host("app.test:80"));
+        assert!(!routes.serves_host("[::1]:80"));
+    }
+
+    #[test]
+    fn header_is_only_a_fallback() {
+        let routes = routes(&[("app", "hosts = [\"app.test\"]"), ("other", "hosts = [\"other.test\"]")]);
+
+        assert_eq!(routes.route(Some("app.test"), "/", Some("other")), Some("app"));
+        assert_eq!(routes.route(Some("unknown.test"), "/", Some("other")), Some("other"));
+        assert_eq!(routes.route(Some("unknown.test"), "/", Some("missing")), None);
+        assert_eq!(routes.route(None, "/", None), None);
+    }
+}
@@ -2,8 +2,26 @@ use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
 pub struct Backend {
-    pub url: url::Url,
+    pub targets: Vec<Target>,
+    pub balance: String,
+    pub hash_on: String,
+    pub health: HealthCheck,
+    pub timeouts: Timeouts,
+    pub retries: u32,
+    pub retry_backoff: u64,
+    pub pool_size: usize,
+    pub pool_idle_timeout: u64,
+    pub rate_limit: Option<RateLimit>,
     pub providers: Vec<String>,
+    /// Lowercase host patterns
+    pub hosts: Vec<String>,
+    /// Without trailing slashes
+    pub path_prefix: Option<String>,
+}
+
+pub struct Target {
+    pub url: url::Url,
+    pub weight: u32,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -20,11 +38,78 @@ pub struct Settings {
     #[serde(alias = "max-age")]
     pub max_age: i64,
     pub secret: String,
+    #[serde(default, alias = "require-2fa")]
+    pub require_2fa: bool,
+    #[serde(alias = "rp-id")]
+    pub rp_id: Option<String>,
+    #[serde(default)]
+    pub identity: Identity,
+    #[serde(default)]
+    pub login: LoginLimits,
+    /// Where backend rate limit buckets live: `memory`, or `database` to share them between nodes
+    #[serde(default = "default_rate_limit_store", alias = "rate-limit-store")]
+    pub rate_limit_store: String,
+    pub metrics: Option<Metrics>,
+    /// Addresses or CIDR ranges of proxies in front of zerotrust, whose
+    /// `X-Forwarded-For` is believed for the client address
+    #[serde(default, alias = "trusted-proxies")]
+    pub trusted_proxies: Vec<String>,
     pub app: App,
     pub server: Server,
     pub database: Database,
 }
 
+/// Limits on failed sign ins. Failures count within a sliding `window`, each
+/// one for an account doubles the delay before the answer, and `max_failures`
+/// of them lock the account for `lockout` seconds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LoginLimits {
+    #[serde(default = "default_login_window")]
+    pub window: u64,
+    #[serde(default = "default_max_failures", alias = "max-failures")]
+    pub max_failures: u32,
+    /// Failures from one address, across every account, before it is turned away
+    #[serde(default = "default_max_per_ip", alias = "max-per-ip")]
+    pub max_per_ip: u32,
+    #[serde(default = "default_lockout")]
+    pub lockout: u64,
+    /// Delay after the first failure in milliseconds, capped at `max_delay`
+    #[serde(default = "default_login_delay")]
+    pub delay: u64,
+    #[serde(default = "default_max_delay", alias = "max-delay")]
+    pub max_delay: u64,
+}
+
+/// Serves Prometheus metrics at `/{prefix}/metrics` to addresses in `allow`,
+/// and to anyone sending `token` as a bearer token. Nobody else, not even
+/// loopback, since a proxy on the same host would make everyone loopback.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Metrics {
+    /// Addresses or CIDR ranges, matched against the client address
+    #[serde(default)]
+    pub allow: Vec<String>,
+    pub token: Option<String>,
+}
+
+/// Header names used to pass the signed in user to backends, an empty name
+/// leaves that header out.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Identity {
+    #[serde(default = "default_user_header")]
+    pub user: String,
+    #[serde(default = "default_email_header")]
+    pub email: String,
+    #[serde(default = "default_admin_header")]
+    pub admin: String,
+    /// Comma separated services the user may reach, with `admin` first for administrators
+    #[serde(default = "default_groups_header")]
+    pub groups: String,
+    #[serde(default = "default_session_header")]
+    pub session: String,
+    #[serde(default = "default_assertion_header")]
+    pub assertion: String,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Database {
     #[serde(alias = "db-name")]
@@ -42,6 +127,50 @@ pub struct Server {
     pub prefix: String,
     pub address: String,
     pub port: u16,
+    #[serde(alias = "external-url")]
+    pub external_url: Option<String>,
+    pub tls: Option<Tls>,
+}
+
+/// Serves `port` over HTTPS, picking a certificate from `hosts` by SNI, then
+/// from the ones `acme` issued, and falling back to `cert` and `key`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Tls {
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    #[serde(default)]
+    pub hosts: BTreeMap<String, Certificate>,
+    /// Plain HTTP port that redirects every request to HTTPS
+    #[serde(alias = "redirect-port")]
+    pub redirect_port: Option<u16>,
+    pub acme: Option<Acme>,
+}
+
+/// Requests certificates for every backend host from an ACME directory.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Acme {
+    #[serde(default = "default_acme_directory")]
+    pub directory: String,
+    pub email: Option<String>,
+    /// `http-01`, answered on `redirect_port`, or `tls-alpn-01`
+    #[serde(default = "default_acme_challenge")]
+    pub challenge: String,
+    /// Keeps the account key and issued certificates, defaults to `acme` in the static files dir
+    pub storage: Option<String>,
+    /// Extra root certificate to trust for the directory, such as Pebble's
+    #[serde(alias = "ca-cert")]
+    pub ca: Option<String>,
+    #[serde(default = "default_renew_days", alias = "renew-days")]
+    pub renew_days: i64,
+    /// Agrees to the directory's terms of service, which registering an account needs
+    #[serde(default, alias = "accept-terms")]
+    pub accept_terms: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Certificate {
+    pub cert: String,
+    pub key: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -63,6 +192,22 @@ pub struct Provider {
     pub auth_url: String,
     #[serde(alias = "token-url")]
     pub token_url: String,
+    #[serde(alias = "userinfo-url")]
+    pub userinfo_url: Option<String>,
+    /// Lists the account's addresses with a `verified` flag, for providers whose profile has none
+    #[serde(alias = "emails-url")]
+    pub emails_url: Option<String>,
+    #[serde(alias = "jwks-url")]
+    pub jwks_url: Option<String>,
+    #[serde(alias = "redirect-url")]
+    pub redirect_url: Option<String>,
+    #[serde(alias = "display-name")]
+    pub display_name: Option<String>,
+    pub issuer: Option<String>,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    #[serde(default, alias = "auto-provision")]
+    pub auto_provision: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -70,7 +215,250 @@ pub struct Location {
     #[serde(alias = "display-name")]
     pub display_name: String,
     pub providers: Vec<String>,
+    #[serde(default)]
+    pub address: String,
+    #[serde(default)]
+    pub port: u16,
+    pub tls: Option<bool>,
+    /// Replaces `address` and `port`, spreading requests over every entry
+    #[serde(default)]
+    pub upstreams: Vec<Upstream>,
+    /// `round-robin`, `least-connections` or `hash`
+    #[serde(default = "default_balance", alias = "load-balance")]
+    pub balance: String,
+    /// What `hash` keeps sticky: `user`, or `cookie:<name>`
+    #[serde(default = "default_hash_on", alias = "hash-on")]
+    pub hash_on: String,
+    #[serde(default)]
+    pub health: HealthCheck,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// Extra attempts for idempotent requests without a body, after connect
+    /// errors, timeouts and 502 to 504 responses
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Milliseconds before the first retry, doubled for every one after
+    #[serde(default = "default_retry_backoff", alias = "retry-backoff")]
+    pub retry_backoff: u64,
+    /// Connections each worker keeps open to an upstream
+    #[serde(default = "default_pool_size", alias = "pool-size")]
+    pub pool_size: usize,
+    /// Seconds an unused pooled connection stays open
+    #[serde(default = "default_pool_idle_timeout", alias = "pool-idle-timeout")]
+    pub pool_idle_timeout: u64,
+    #[serde(alias = "rate-limit")]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    #[serde(alias = "path-prefix")]
+    pub path_prefix: Option<String>,
+    #[serde(alias = "require-2fa")]
+    pub require_2fa: Option<bool>,
+    #[serde(default)]
+    pub assertion: bool,
+    #[serde(default, alias = "allow-headers")]
+    pub allow_headers: Vec<String>,
+    #[serde(default, alias = "deny-headers")]
+    pub deny_headers: Vec<String>,
+    /// Never read from or written to the file, set when services are merged
+    #[serde(skip)]
+    pub source: Source,
+}
+
+/// Where a backend is defined. Backends in the config file are read-only to the admin API.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    #[default]
+    File,
+    Db,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Upstream {
     pub address: String,
     pub port: u16,
+    /// Defaults to the backend's `tls`
     pub tls: Option<bool>,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+/// Upstreams failing `unhealthy_threshold` times in a row, to requests or to
+/// checks, are ejected until a probe after `cooldown` seconds succeeds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HealthCheck {
+    /// Requested on every upstream each `interval` seconds, no active checks when unset
+    pub path: Option<String>,
+    #[serde(default = "default_health_interval")]
+    pub interval: u64,
+    #[serde(default = "default_health_timeout")]
+    pub timeout: u64,
+    #[serde(default = "default_healthy_threshold", alias = "healthy-threshold")]
+    pub healthy_threshold: u32,
+    #[serde(default = "default_unhealthy_threshold", alias = "unhealthy-threshold")]
+    pub unhealthy_threshold: u32,
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+}
+
+/// A token bucket per client: `burst` requests at once, refilled at `rate`
+/// requests per second.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// What a client is: `user`, `ip` or `token`, the session or API token it sends
+    #[serde(default = "default_rate_limit_key")]
+    pub key: String,
+    pub rate: f64,
+    pub burst: u32,
+}
+
+/// How long to wait on an upstream, in seconds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Timeouts {
+    #[serde(default = "default_connect_timeout")]
+    pub connect: u64,
+    /// Longest wait for the response head, and between body chunks after it
+    #[serde(default = "default_read_timeout")]
+    pub read: u64,
+    /// Deadline for the response head, across every retry
+    #[serde(default = "default_total_timeout")]
+    pub total: u64,
+}
+
+impl Tls {
+    /// Every certificate and key file, in the order they are loaded.
+    pub fn paths(&self) -> Vec<&str> {
+        let hosts = self.hosts.values().flat_map(|certificate| [certificate.cert.as_str(), certificate.key.as_str()]);
+        [self.cert.as_deref(), self.key.as_deref()].into_iter().flatten().chain(hosts).collect()
+    }
 }
+
+impl Identity {
+    /// Every configured header name, keyed by the setting it came from.
+    pub fn fields(&self) -> [(&'static str, &str); 6] {
+        [
+            ("user", &self.user),
+            ("email", &self.email),
+            ("admin", &self.admin),
+            ("groups", &self.groups),
+            ("session", &self.session),
+            ("assertion", &self.assertion),
+        ]
+    }
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Self {
+            user: default_user_header(),
+            email: default_email_header(),
+            admin: default_admin_header(),
+            groups: default_groups_header(),
+            session: default_session_header(),
+            assertion: default_assertion_header(),
+        }
+    }
+}
+
+impl Default for LoginLimits {
+    fn default() -> Self {
+        Self {
+            window: default_login_window(),
+            max_failures: default_max_failures(),
+            max_per_ip: default_max_per_ip(),
+            lockout: default_lockout(),
+            delay: default_login_delay(),
+            max_delay: default_max_delay(),
+        }
+    }
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            path: None,
+            interval: default_health_interval(),
+            timeout: default_health_timeout(),
+            healthy_threshold: default_healthy_threshold(),
+            unhealthy_threshold: default_unhealthy_threshold(),
+            cooldown: default_cooldown(),
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: default_connect_timeout(),
+            read: default_read_timeout(),
+            total: default_total_timeout(),
+        }
+    }
+}
+
+fn default_user_header() -> String { "X-Auth-User".into() }
+
+fn default_email_header() -> String { "X-Auth-Email".into() }
+
+fn default_admin_header() -> String { "X-Auth-Admin".into() }
+
+fn default_groups_header() -> String { "X-Auth-Groups".into() }
+
+fn default_session_header() -> String { "X-Auth-Session".into() }
+
+fn default_assertion_header() -> String { "X-Auth-Assertion".into() }
+
+fn default_login_window() -> u64 { 900 }
+
+fn default_max_failures() -> u32 { 5 }
+
+fn default_max_per_ip() -> u32 { 50 }
+
+fn default_lockout() -> u64 { 900 }
+
+fn default_login_delay() -> u64 { 250 }
+
+fn default_max_delay() -> u64 { 4000 }
+
+fn default_rate_limit_store() -> String { "memory".into() }
+
+fn default_rate_limit_key() -> String { "user".into() }
+
+fn default_acme_directory() -> String { "https://acme-v02.api.letsencrypt.org/directory".into() }
+
+fn default_acme_challenge() -> String { "http-01".into() }
+
+fn default_renew_days() -> i64 { 30 }
+
+fn default_balance() -> String { "round-robin".into() }
+
+fn default_hash_on() -> String { "user".into() }
+
+fn default_weight() -> u32 { 1 }
+
+fn default_health_interval() -> u64 { 10 }
+
+fn default_health_timeout() -> u64 { 2 }
+
+fn default_healthy_threshold() -> u32 { 2 }
+
+fn default_unhealthy_threshold() -> u32 { 3 }
+
+fn default_cooldown() -> u64 { 30 }
+
+fn default_connect_timeout() -> u64 { 5 }
+
+fn default_read_timeout() -> u64 { 30 }
+
+fn default_total_timeout() -> u64 { 60 }
+
+fn default_retries() -> u32 { 2 }
+
+fn default_retry_backoff() -> u64 { 100 }
+
+fn default_pool_size() -> usize { 100 }
+
+fn default_pool_idle_timeout() -> u64 { 15 }
+
+fn default_scopes() -> Vec<String> { vec!["openid".into(), "email".into(), "profile".into()] }
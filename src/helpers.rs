@@ -39,5 +39,5 @@ pub fn build_hashmap(dir: &'static Dir) -> HashMap<&'static str, Resource> {
 
     flatten_into(&mut map, dir);
 
-    return map;
+    map
 }
@@ -1,117 +1,224 @@
+pub mod acme;
+pub mod address;
+pub mod balance;
 pub mod catch;
 pub mod errors;
+pub mod health;
+pub mod metrics;
+pub mod ratelimit;
+pub mod sanitize;
+pub mod tls;
 pub mod token;
+pub mod upstream;
 
 use actix_files as afs;
 use actix_web_static_files::ResourceFiles;
+use colored::Colorize;
 use errors::Error;
 use futures_util::StreamExt;
 use include_dir::{include_dir, Dir};
-use macros_rs::{clone, fmtstr, string};
+use macros_rs::{clone, crashln, fmtstr, string, ternary};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    app,
-    auth::{self, middleware},
-    config::{db::Pool, structs::Config},
+    admin, app,
+    auth::{self, assertion, middleware},
+    config::{
+        db::Pool,
+        live::{Live, Shared},
+        routes::Routes,
+        structs::Config,
+    },
     pages::create_templates,
 };
 
 use actix_web::{
-    dev::PeerAddr,
-    error::ErrorInternalServerError,
     guard,
-    http::StatusCode,
-    middleware::ErrorHandlers,
+    http::{header, StatusCode},
+    middleware::{from_fn, ErrorHandlers},
     web::{self, Data, Payload},
     App, HttpRequest, HttpResponse, HttpServer,
 };
 
 static ASSETS_DIR: Dir<'_> = include_dir!("src/pages/dist/assets_provider");
 
-async fn proxy(req: HttpRequest, payload: Payload, peer_addr: Option<PeerAddr>, config: Data<Config>) -> Result<HttpResponse, Error> {
+pub fn select_service<'a>(req: &HttpRequest, routes: &'a Routes) -> Option<&'a str> {
+    let host = req.headers().get(header::HOST).and_then(|host| host.to_str().ok()).or(req.uri().host());
+    let header = req.headers().get("SelectService").and_then(|name| name.to_str().ok());
+
+    routes.route(host, req.uri().path(), header)
+}
+
+async fn proxy(
+    req: HttpRequest,
+    payload: Payload,
+    config: Live<Config>,
+    routes: Live<Routes>,
+    balancer: Data<balance::Balancer>,
+    clients: Data<upstream::Clients>,
+    limiter: Data<ratelimit::Limiter>,
+) -> Result<HttpResponse, Error> {
     tracing::info!(method = string!(req.method()), "request '{}'", req.uri());
 
     let config = config.get_ref();
 
-    if let Some(name) = req.headers().get("SelectService") {
-        let name = name.to_str().unwrap_or("");
-        let (mut url, providers) = match config.backends().get(name) {
-            Some(item) => (clone!(item.url), clone!(item.providers)),
-            None => return Err(Error::NotFound { message: "Service not found" }),
+    let Some(name) = select_service(&req, &routes) else {
+        return Err(Error::NotFound {
+            message: "No service is configured for this address.".into(),
+        });
+    };
+
+    // every answer for the service is counted, ours as well as the upstream's
+    let started = Instant::now();
+    let result: Result<HttpResponse, Error> = async {
+        let backend = match routes.get(name) {
+            Some(item) => item,
+            None => return Err(Error::NotFound { message: "Service not found".into() }),
         };
 
-        for provider in providers {
-            if provider == "basic" {
-                continue;
-            }
+        auth::oauth::enforce_providers(&req, &backend.providers, config)?;
+        let quota = limiter.check(&req, name, backend.rate_limit.as_ref(), config)?;
 
-            config.providers.get(&provider);
-        }
+        let client = clients.http(name, backend)?;
+        let forward = |target: &url::Url| {
+            let mut url = clone!(target);
+            url.set_path(req.uri().path());
+            url.set_query(req.uri().query());
 
-        url.set_path(req.uri().path());
-        url.set_query(req.uri().query());
+            let mut forwarded_req = client.request(req.method().clone(), url.as_str()).no_decompress();
 
-        let client = awc::Client::builder().disable_redirects().finish();
-        let forwarded_req = client.request_from(url.as_str(), req.head()).no_decompress();
+            for header in sanitize::request_headers(&req, name, false, config) {
+                forwarded_req = forwarded_req.append_header(header);
+            }
+
+            for header in assertion::identity_headers(&req, name, config) {
+                forwarded_req = forwarded_req.insert_header(header);
+            }
 
-        let forwarded_req = match peer_addr {
-            Some(PeerAddr(addr)) => forwarded_req.insert_header(("x-forwarded-for", addr.ip().to_string())),
-            None => forwarded_req,
+            match address::client_ip(&req, config) {
+                Some(ip) => forwarded_req.insert_header(("x-forwarded-for", ip.to_string())),
+                None => forwarded_req,
+            }
         };
 
-        let res = catch::_try!(forwarded_req.send_stream(payload).await.map_err(ErrorInternalServerError));
+        let (res, lease) = upstream::send(&req, payload, name, backend, &balancer, forward).await?;
         let mut client_response = HttpResponse::build(res.status());
 
         match res.status().as_u16() {
             400 => {
                 return Err(Error::ConnectionRefused {
-                    message: fmtstr!("Sorry, this page could not be loaded from upstream."),
+                    message: "Sorry, this page could not be loaded from upstream.".into(),
                 })
             }
             404 => {
                 return Err(Error::NotFound {
-                    message: fmtstr!("Sorry, this page could not be found on upstream."),
+                    message: "Sorry, this page could not be found on upstream.".into(),
                 })
             }
             _ => {}
         }
 
-        for (header_name, header_value) in res.headers().iter().filter(|(h, _)| *h != "connection") {
+        for (header_name, header_value) in res.headers().iter().filter(|(h, _)| !sanitize::is_hop_by_hop(h)) {
             client_response.insert_header((header_name.clone(), header_value.clone()));
         }
 
-        tracing::info!(service = name, status = string!(res.status()), "responded");
-        Ok(client_response.streaming(res))
-    } else {
-        Err(Error::NotFound { message: "No service header" })
+        for header in quota.iter().flat_map(ratelimit::Quota::headers) {
+            client_response.insert_header(header);
+        }
+
+        tracing::info!(service = name, upstream = lease.url.as_str(), status = string!(res.status()), "responded");
+
+        // the lease ends with the response body, not with this handler
+        let read = Duration::from_secs(backend.timeouts.read);
+        Ok(client_response.streaming(upstream::body(res, lease, read)))
     }
+    .await;
+
+    metrics::METRICS.request(name, &result, started);
+    result
 }
 
-async fn proxy_ws(req: HttpRequest, client_stream: Payload, config: Data<Config>) -> Result<HttpResponse, Error> {
+async fn proxy_ws(
+    req: HttpRequest,
+    client_stream: Payload,
+    config: Live<Config>,
+    routes: Live<Routes>,
+    balancer: Data<balance::Balancer>,
+    clients: Data<upstream::Clients>,
+    limiter: Data<ratelimit::Limiter>,
+) -> Result<HttpResponse, Error> {
     tracing::info!(method = string!(req.method()), "websocket '{}'", req.uri());
 
     let config = config.get_ref();
 
-    if let Some(name) = req.headers().get("SelectService") {
-        let name = name.to_str().unwrap_or("");
-        let mut url = match config.backends().get(name) {
-            Some(item) => clone!(item.url),
-            None => return Err(Error::NotFound { message: "Service not found" }),
+    if let Some(name) = select_service(&req, &routes) {
+        let backend = match routes.get(name) {
+            Some(item) => item,
+            None => return Err(Error::NotFound { message: "Service not found".into() }),
         };
 
+        auth::oauth::enforce_providers(&req, &backend.providers, config)?;
+        limiter.check(&req, name, backend.rate_limit.as_ref(), config)?;
+
+        let lease = match balancer.pick(name, backend, &req) {
+            Some(lease) => lease,
+            None => {
+                return Err(Error::ConnectionRefused {
+                    message: "Sorry, this service is temporarily unavailable.".into(),
+                })
+            }
+        };
+
+        let mut url = clone!(lease.url);
         url.set_path(req.uri().path());
         url.set_query(req.uri().query());
 
-        let mut request = reqwest::Client::new().get(url);
-        for (key, value) in req.headers() {
+        let timeouts = &backend.timeouts;
+        let mut request = clients.websocket(name, backend)?.get(url);
+        for (key, value) in sanitize::request_headers(&req, name, true, config) {
             request = request.header(key, value);
         }
-        let target_response = request.send().await.unwrap();
+
+        for (key, value) in assertion::identity_headers(&req, name, config) {
+            request = request.header(key, value);
+        }
+        let wait = Duration::from_secs(timeouts.read.min(timeouts.total));
+        let target_response = match tokio::time::timeout(wait, request.send()).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(err)) => {
+                tracing::warn!(service = name, upstream = lease.url.as_str(), "upstream request failed: {err}");
+                balancer.health.record(name, &lease.url, false, &backend.health);
+                metrics::METRICS.upstream_error(name, ternary!(err.is_timeout(), "timeout", "connect"));
+
+                return Err(match err.is_timeout() {
+                    true => Error::Timeout {
+                        message: "Sorry, this service took too long to respond.".into(),
+                    },
+                    false => Error::ConnectionRefused {
+                        message: "Sorry, this service could not be reached.".into(),
+                    },
+                });
+            }
+            Err(_) => {
+                tracing::warn!(service = name, upstream = lease.url.as_str(), "upstream request failed: no response within {}ms", wait.as_millis());
+                balancer.health.record(name, &lease.url, false, &backend.health);
+                metrics::METRICS.upstream_error(name, "timeout");
+
+                return Err(Error::Timeout {
+                    message: "Sorry, this service took too long to respond.".into(),
+                });
+            }
+        };
 
         let status = target_response.status().as_u16();
+        balancer.health.record(name, &lease.url, !target_response.status().is_server_error(), &backend.health);
+
         if status != 101 {
             return Err(Error::ConnectionRefused {
-                message: "Target did not reply with 101 upgrade",
+                message: "Target did not reply with 101 upgrade".into(),
             });
         }
 
@@ -125,7 +232,7 @@ async fn proxy_ws(req: HttpRequest, client_stream: Payload, config: Data<Config>
         let (target_rx, mut target_tx) = tokio::io::split(target_upgrade);
 
         actix_web::rt::spawn(async move {
-            let mut client_stream = client_stream.map(|result| result.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err)));
+            let mut client_stream = client_stream.map(|result| result.map_err(std::io::Error::other));
             let mut client_read = tokio_util::io::StreamReader::new(&mut client_stream);
             let result = tokio::io::copy(&mut client_read, &mut target_tx).await;
             if let Err(err) = result {
@@ -134,35 +241,118 @@ async fn proxy_ws(req: HttpRequest, client_stream: Payload, config: Data<Config>
         });
 
         let target_stream = tokio_util::io::ReaderStream::new(target_rx);
-        tracing::info!(service = name, status, "connected");
+        tracing::info!(service = name, upstream = lease.url.as_str(), status, "connected");
 
-        Ok(client_response.streaming(target_stream))
+        let open = metrics::METRICS.websocket(name);
+        Ok(client_response.streaming(target_stream.map(move |chunk| {
+            let _held = (&lease, &open);
+            chunk
+        })))
     } else {
-        Err(Error::NotFound { message: "No service header" })
+        Err(Error::NotFound {
+            message: "No service is configured for this address.".into(),
+        })
     }
 }
 
-pub fn start(pool: Pool, cli: crate::Cli) -> actix_web::dev::Server {
-    let mut config = Config::new().set_path(&cli.config).read();
+pub fn start(pool: Pool, live: Data<Shared>, certs: Arc<tls::Certificates>, balancer: Arc<balance::Balancer>) -> actix_web::dev::Server {
+    let snapshot = live.load_full();
+    let config = &snapshot.config;
+
+    let certificates = Data::from(certs.clone());
+    let balancer = Data::from(balancer);
+    let limiter = Data::new(ratelimit::Limiter::new(pool.clone()));
 
+    // anything read here is fixed until the next restart, see `Snapshot::needs_restart`
     let app = move || {
-        let config = Config::new().set_path(&cli.config).create_dirs().read();
+        let snapshot = live.load();
+        let config = snapshot.config.create_dirs();
+
         let prefix = config.settings.server.prefix.clone();
         let files = crate::helpers::build_hashmap(&ASSETS_DIR);
 
+        // issued keys live under the static dir unless `acme.storage` says otherwise
+        let storage = match config.settings.server.tls.as_ref().and_then(|tls| tls.acme.as_ref()) {
+            Some(acme) if acme.storage.is_none() => Some(acme::STORAGE_DIR),
+            _ => None,
+        };
+
         App::new()
-            .app_data(Data::new(config.clone()))
+            .app_data(live.clone())
+            .app_data(certificates.clone())
+            .app_data(balancer.clone())
+            .app_data(limiter.clone())
+            .app_data(Data::new(upstream::Clients::default()))
             .app_data(Data::new(create_templates()))
             .app_data(Data::new(pool.clone()))
+            .route(fmtstr!("{}{{token}}", acme::CHALLENGE_PATH), web::get().to(acme::http_challenge))
             .route("/setup", web::get().guard(middleware::setup_guard).to(app::setup))
             .route("/setup", web::post().guard(middleware::setup_guard).to(app::setup_handler))
             .route(fmtstr!("/{prefix}/login"), web::get().guard(middleware::token_guard).to(auth::login))
             .route(fmtstr!("/{prefix}/logout"), web::get().to(auth::logout).wrap(middleware::Authentication))
             .route(fmtstr!("/{prefix}/app"), web::get().to(app::dashboard).wrap(middleware::Authentication))
             .route(fmtstr!("/{prefix}/api/login"), web::post().guard(middleware::token_guard).to(auth::login_handler))
+            .route(fmtstr!("/{prefix}/api/login/step-up"), web::post().guard(middleware::token_guard).to(auth::step_up))
+            .route(fmtstr!("/{prefix}/api/login/totp"), web::post().guard(middleware::token_guard).to(auth::totp::login_verify))
+            .route(fmtstr!("/{prefix}/api/login/totp/enroll"), web::post().guard(middleware::token_guard).to(auth::totp::login_enroll))
+            .route(
+                fmtstr!("/{prefix}/api/webauthn/login/start"),
+                web::post().guard(middleware::token_guard).to(auth::webauthn::login_start),
+            )
+            .route(
+                fmtstr!("/{prefix}/api/webauthn/login/finish"),
+                web::post().guard(middleware::token_guard).to(auth::webauthn::login_finish),
+            )
             .route(fmtstr!("/{prefix}/api/logout"), web::post().to(auth::logout_handler).wrap(middleware::Authentication))
+            .route(fmtstr!("/{prefix}/api/verify"), web::to(auth::forward::verify))
+            .route(fmtstr!("/{prefix}/api/jwks/{{service}}"), web::get().to(auth::assertion::jwks))
+            .route(fmtstr!("/{prefix}/metrics"), web::get().to(metrics::handler))
+            .route(fmtstr!("/{prefix}/api/providers"), web::get().to(auth::oauth::providers))
+            .route(fmtstr!("/{prefix}/oauth/{{provider}}"), web::get().to(auth::oauth::start))
+            .route(fmtstr!("/{prefix}/oauth/{{provider}}/callback"), web::get().to(auth::oauth::callback))
+            .service(
+                web::scope(fmtstr!("/{prefix}/api/totp"))
+                    .wrap(middleware::Authentication)
+                    .route("/enroll", web::post().to(auth::totp::enroll))
+                    .route("/confirm", web::post().to(auth::totp::confirm))
+                    .route("/recovery", web::post().to(auth::totp::regenerate))
+                    .route("/disable", web::post().to(auth::totp::disable)),
+            )
+            .service(
+                web::scope(fmtstr!("/{prefix}/api/webauthn"))
+                    .wrap(middleware::Authentication)
+                    .route("/register/start", web::post().to(auth::webauthn::register_start))
+                    .route("/register/finish", web::post().to(auth::webauthn::register_finish))
+                    .route("/credentials", web::get().to(auth::webauthn::list_credentials))
+                    .route("/credentials/{id}", web::delete().to(auth::webauthn::delete_credential)),
+            )
+            .service(
+                web::scope(fmtstr!("/{prefix}/api/admin"))
+                    .wrap(middleware::Authentication)
+                    .route("/users", web::get().to(admin::list_users))
+                    .route("/users", web::post().to(admin::create_user))
+                    .route("/users/{id}", web::get().to(admin::get_user))
+                    .route("/users/{id}", web::patch().to(admin::update_user))
+                    .route("/users/{id}", web::delete().to(admin::delete_user))
+                    .route("/users/{id}/disable", web::post().to(admin::disable_user))
+                    .route("/users/{id}/enable", web::post().to(admin::enable_user))
+                    .route("/users/{id}/totp", web::delete().to(admin::reset_totp))
+                    .route("/users/{id}/history", web::get().to(admin::user_history))
+                    .route("/backends", web::get().to(admin::list_backends))
+                    .route("/backends/health", web::get().to(admin::backend_health))
+                    .route("/backends/{name}", web::put().to(admin::save_service))
+                    .route("/backends/{name}", web::delete().to(admin::delete_service))
+                    .route("/history", web::get().to(admin::login_history))
+                    .route("/login-failures", web::get().to(admin::login_failures))
+                    .route("/lockouts", web::get().to(admin::list_lockouts))
+                    .route("/lockouts/{account}", web::delete().to(admin::clear_lockout)),
+            )
             .service(ResourceFiles::new(fmtstr!("/{prefix}/assets"), files))
-            .service(afs::Files::new(fmtstr!("/{prefix}/static"), config.get_static()).index_file("index.html"))
+            .service(
+                afs::Files::new(fmtstr!("/{prefix}/static"), config.get_static())
+                    .index_file("index.html")
+                    .path_filter(move |path, _| !storage.is_some_and(|storage| path.starts_with(storage))),
+            )
             .wrap(ErrorHandlers::new().handler(StatusCode::NOT_FOUND, errors::not_found))
             .service(
                 web::scope("{url:.*}")
@@ -171,16 +361,29 @@ pub fn start(pool: Pool, cli: crate::Cli) -> actix_web::dev::Server {
                     .wrap(middleware::Authentication),
             )
             .default_service(web::to(proxy).wrap(middleware::Authentication))
+            .wrap(from_fn(tls::redirect))
     };
 
-    if let Some(port) = cli.port {
-        config.override_port(port)
-    }
+    let server = match &config.settings.server.tls {
+        None => HttpServer::new(app).bind(config.get_address()).unwrap(),
+        Some(tls) => {
+            if let Err(err) = certs.reload(config) {
+                crashln!("Unable to load TLS certificates.\n{}", err.white());
+            }
 
-    if let Some(address) = cli.address {
-        config.override_address(address)
-    }
+            let server = HttpServer::new(app).bind_rustls_021(config.get_address(), certs.server_config(tls)).unwrap();
+            match tls.redirect_port {
+                Some(port) => server.bind((config.get_address().0, port)).unwrap(),
+                None => server,
+            }
+        }
+    };
 
-    tracing::info!(address = config.get_address().0, port = config.get_address().1, "server started");
-    HttpServer::new(app).bind(config.get_address()).unwrap().run()
+    tracing::info!(
+        address = config.get_address().0,
+        port = config.get_address().1,
+        tls = config.settings.server.tls.is_some(),
+        "server started"
+    );
+    server.run()
 }
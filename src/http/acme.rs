@@ -0,0 +1,411 @@
+use macros_rs::string;
+use rcgen::{CertificateParams, CustomExtension};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use instant_acme::{Account, AccountCredentials, AuthorizationStatus, ChallengeType, HttpClient, Identifier, KeyAuthorization, NewAccount, NewOrder, Order, OrderStatus};
+
+use super::tls::{certified_key, Certificates};
+use crate::config::structs::{Acme, Config};
+
+use actix_web::{
+    web::{Data, Path as UrlPath},
+    HttpRequest, HttpResponse,
+};
+
+pub const ALPN: &[u8] = b"acme-tls/1";
+pub const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Directory inside the static files dir used when `storage` is not set, never served.
+pub const STORAGE_DIR: &str = "acme";
+
+const RENEW_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const POLL_ATTEMPTS: u32 = 10;
+
+fn storage(config: &Config) -> PathBuf {
+    match config.settings.server.tls.as_ref().and_then(|tls| tls.acme.as_ref()).and_then(|acme| acme.storage.as_ref()) {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(&config.get_static()).join(STORAGE_DIR),
+    }
+}
+
+/// Every backend host a certificate can be issued for, wildcards need DNS-01 and are left out.
+pub fn hosts(config: &Config) -> Vec<String> {
+    let mut hosts: Vec<String> = config
+        .backends
+        .values()
+        .flat_map(|item| item.hosts.iter())
+        .filter(|host| !host.contains('*'))
+        .map(|host| host.to_lowercase())
+        .collect();
+
+    hosts.sort();
+    hosts.dedup();
+    hosts
+}
+
+/// Where the certificate chain and key issued for `host` are kept.
+pub fn paths(host: &str, config: &Config) -> (PathBuf, PathBuf) {
+    let dir = storage(config);
+    (dir.join(format!("{host}.crt")), dir.join(format!("{host}.key")))
+}
+
+fn temporary(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+fn stage(path: &Path, contents: &[u8]) -> Result<PathBuf, String> {
+    let temporary = temporary(path);
+    let error = |err: std::io::Error| format!("{}: {err}", temporary.display());
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temporary).map_err(error)?;
+
+    if let Err(err) = file.write_all(contents).and_then(|_| file.sync_all()) {
+        let _ = fs::remove_file(&temporary);
+        return Err(error(err));
+    }
+
+    Ok(temporary)
+}
+
+/// Writes files only the current user can read. Every file is written out
+/// next to its target before any is renamed into place, so a key is never
+/// swapped in without the certificate that goes with it.
+fn write_private(files: &[(&Path, &[u8])]) -> Result<(), String> {
+    let mut staged = vec![];
+
+    for (path, contents) in files {
+        match stage(path, contents) {
+            Ok(temporary) => staged.push((temporary, *path)),
+            Err(err) => {
+                for (temporary, _) in staged {
+                    let _ = fs::remove_file(temporary);
+                }
+                return Err(err);
+            }
+        }
+    }
+
+    for (temporary, path) in staged {
+        fs::rename(&temporary, path).map_err(|err| format!("{}: {err}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+fn needs_renewal(host: &str, acme: &Acme, config: &Config) -> bool {
+    let (cert, _) = paths(host, config);
+    let Ok(pem) = fs::read(cert) else {
+        return true;
+    };
+
+    let not_after = match x509_parser::pem::parse_x509_pem(&pem) {
+        Ok((_, pem)) => pem.parse_x509().map(|cert| cert.validity().not_after.timestamp()),
+        Err(_) => return true,
+    };
+
+    match not_after {
+        Ok(not_after) => not_after - chrono::Utc::now().timestamp() < acme.renew_days * 24 * 60 * 60,
+        Err(_) => true,
+    }
+}
+
+/// Trusts the usual web roots, plus `ca` for test directories like Pebble.
+fn http_client(acme: &Acme) -> Result<Box<dyn HttpClient>, String> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(
+        webpki_roots::TLS_SERVER_ROOTS
+            .iter()
+            .map(|anchor| rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)),
+    );
+
+    if let Some(ca) = &acme.ca {
+        let file = File::open(ca).map_err(|err| format!("{ca}: {err}"))?;
+        let certs = rustls_pemfile::certs(&mut BufReader::new(file)).map_err(|err| format!("{ca}: {err}"))?;
+        roots.add_parsable_certificates(&certs);
+    }
+
+    let tls = rustls::ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth();
+    let connector = hyper_rustls::HttpsConnectorBuilder::new().with_tls_config(tls).https_only().enable_http1().build();
+
+    Ok(Box::new(hyper::Client::builder().build(connector)))
+}
+
+async fn account(acme: &Acme, config: &Config) -> Result<Account, String> {
+    let path = storage(config).join("account.json");
+
+    if let Ok(contents) = fs::read(&path) {
+        let credentials: AccountCredentials = serde_json::from_slice(&contents).map_err(|err| format!("{}: {err}", path.display()))?;
+        return Account::from_credentials_and_http(credentials, http_client(acme)?).await.map_err(|err| err.to_string());
+    }
+
+    let contact: Vec<String> = acme.email.iter().map(|email| format!("mailto:{email}")).collect();
+    let contact: Vec<&str> = contact.iter().map(String::as_str).collect();
+    let new_account = NewAccount {
+        contact: &contact,
+        terms_of_service_agreed: acme.accept_terms,
+        only_return_existing: false,
+    };
+
+    let (account, credentials) = Account::create_with_http(&new_account, &acme.directory, None, http_client(acme)?)
+        .await
+        .map_err(|err| err.to_string())?;
+    write_private(&[(&path, &serde_json::to_vec(&credentials).map_err(|err| err.to_string())?)])?;
+
+    tracing::info!(directory = acme.directory, "registered acme account");
+    Ok(account)
+}
+
+/// Puts the response to a challenge where the validation request will look for it.
+fn present(host: &str, token: &str, kind: &ChallengeType, authorization: &KeyAuthorization, certs: &Certificates) -> Result<(), String> {
+    match kind {
+        ChallengeType::Http01 => {
+            certs.tokens.write().insert(token.to_string(), authorization.as_str().to_string());
+        }
+        _ => {
+            let mut params = CertificateParams::new(vec![host.to_string()]);
+            params.custom_extensions = vec![CustomExtension::new_acme_identifier(authorization.digest().as_ref())];
+
+            let cert = rcgen::Certificate::from_params(params).map_err(|err| err.to_string())?;
+            let key = certified_key(vec![cert.serialize_der().map_err(|err| err.to_string())?], cert.serialize_private_key_der())?;
+            certs.challenges.write().insert(host.to_string(), key);
+        }
+    }
+
+    Ok(())
+}
+
+async fn wait_ready(order: &mut Order, host: &str) -> Result<(), String> {
+    let mut delay = Duration::from_millis(500);
+
+    for _ in 0..POLL_ATTEMPTS {
+        tokio::time::sleep(delay).await;
+
+        match order.refresh().await.map_err(|err| err.to_string())?.status {
+            OrderStatus::Ready => return Ok(()),
+            OrderStatus::Invalid => return Err(format!("{host}: validation failed")),
+            _ => delay = (delay * 2).min(Duration::from_secs(10)),
+        }
+    }
+
+    Err(format!("{host}: validation timed out"))
+}
+
+async fn issue(account: &Account, host: &str, acme: &Acme, certs: &Certificates, config: &Config) -> Result<(), String> {
+    let kind = match acme.challenge.as_str() {
+        "tls-alpn-01" => ChallengeType::TlsAlpn01,
+        _ => ChallengeType::Http01,
+    };
+
+    let identifiers = [Identifier::Dns(host.to_string())];
+    let mut order = account.new_order(&NewOrder { identifiers: &identifiers }).await.map_err(|err| err.to_string())?;
+    let mut tokens = vec![];
+
+    for authorization in order.authorizations().await.map_err(|err| err.to_string())? {
+        if !matches!(authorization.status, AuthorizationStatus::Pending) {
+            continue;
+        }
+
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.r#type == kind)
+            .ok_or(format!("{host}: {} is not offered", acme.challenge))?;
+
+        present(host, &challenge.token, &kind, &order.key_authorization(challenge), certs)?;
+        order.set_challenge_ready(&challenge.url).await.map_err(|err| err.to_string())?;
+        tokens.push(challenge.token.clone());
+    }
+
+    let ready = wait_ready(&mut order, host).await;
+
+    for token in tokens {
+        certs.tokens.write().remove(&token);
+    }
+
+    certs.challenges.write().remove(host);
+    ready?;
+
+    let cert = rcgen::Certificate::from_params(CertificateParams::new(vec![host.to_string()])).map_err(|err| err.to_string())?;
+    order.finalize(&cert.serialize_request_der().map_err(|err| err.to_string())?).await.map_err(|err| err.to_string())?;
+
+    let mut chain = None;
+    for _ in 0..POLL_ATTEMPTS {
+        match order.certificate().await.map_err(|err| err.to_string())? {
+            Some(pem) => {
+                chain = Some(pem);
+                break;
+            }
+            None => tokio::time::sleep(Duration::from_secs(1)).await,
+        }
+    }
+
+    let chain = chain.ok_or(format!("{host}: certificate was not issued in time"))?;
+    let (cert_path, key_path) = paths(host, config);
+
+    write_private(&[(&key_path, cert.serialize_private_key_pem().as_bytes()), (&cert_path, chain.as_bytes())])
+}
+
+/// Issues or renews every certificate that is missing or close to expiring.
+async fn renew(certs: &Certificates, acme: &Acme, config: &Config) -> Result<usize, String> {
+    let pending: Vec<String> = hosts(config).into_iter().filter(|host| needs_renewal(host, acme, config)).collect();
+    if pending.is_empty() {
+        return Ok(0);
+    }
+
+    fs::create_dir_all(storage(config)).map_err(|err| format!("{}: {err}", storage(config).display()))?;
+    let account = account(acme, config).await?;
+    let mut issued = 0;
+
+    for host in pending {
+        match issue(&account, &host, acme, certs, config).await {
+            Ok(_) => {
+                tracing::info!(host, "issued certificate");
+                issued += 1;
+            }
+            Err(err) => tracing::error!(host, err, "unable to issue certificate"),
+        }
+    }
+
+    certs.reload(config)?;
+    Ok(issued)
+}
+
+/// Keeps the issued certificates current for as long as the server runs with this config.
+pub async fn run(certs: Arc<Certificates>, config: Config) {
+    let Some(acme) = config.settings.server.tls.as_ref().and_then(|tls| tls.acme.clone()) else {
+        return;
+    };
+
+    loop {
+        if let Err(err) = renew(&certs, &acme, &config).await {
+            tracing::error!(err, "acme renewal failed");
+        }
+
+        tokio::time::sleep(RENEW_INTERVAL).await;
+    }
+}
+
+pub async fn http_challenge(req: HttpRequest, token: UrlPath<String>, certs: Data<Certificates>) -> HttpResponse {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    match certs.tokens.read().get(token.as_str()) {
+        Some(authorization) => HttpResponse::Ok().content_type("application/octet-stream").body(authorization.clone()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::structs::{Location, Tls};
+    use std::os::unix::fs::PermissionsExt;
+
+    use actix_web::{web, App, HttpServer};
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zerotrust-acme-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn write_private_replaces_every_file() {
+        let dir = scratch("write");
+        let (key, cert) = (dir.join("host.key"), dir.join("host.crt"));
+
+        write_private(&[(&key, b"old key"), (&cert, b"old cert")]).unwrap();
+        write_private(&[(&key, b"new key"), (&cert, b"new cert")]).unwrap();
+
+        assert_eq!(fs::read(&key).unwrap(), b"new key");
+        assert_eq!(fs::read(&cert).unwrap(), b"new cert");
+        assert_eq!(fs::metadata(&key).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn write_private_keeps_the_old_pair_when_one_fails() {
+        let dir = scratch("partial");
+        let (key, cert) = (dir.join("host.key"), dir.join("host.crt"));
+        write_private(&[(&key, b"old key"), (&cert, b"old cert")]).unwrap();
+
+        let missing = dir.join("missing").join("host.crt");
+        assert!(write_private(&[(&key, b"new key"), (&missing, b"new cert")]).is_err());
+
+        assert_eq!(fs::read(&key).unwrap(), b"old key");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Issues a certificate for `localhost` from the Pebble directory named by
+    /// `ZEROTRUST_TEST_PEBBLE`, trusting `ZEROTRUST_TEST_PEBBLE_CA` and answering
+    /// HTTP-01 on Pebble's `httpPort`, 5002 unless `ZEROTRUST_TEST_PEBBLE_PORT` says otherwise.
+    #[actix_web::test]
+    async fn issues_from_pebble() {
+        let Ok(directory) = std::env::var("ZEROTRUST_TEST_PEBBLE") else {
+            eprintln!("skipped, ZEROTRUST_TEST_PEBBLE is not set");
+            return;
+        };
+
+        let port: u16 = std::env::var("ZEROTRUST_TEST_PEBBLE_PORT").map_or(5002, |port| port.parse().unwrap());
+        let dir = scratch("pebble");
+        let acme = Acme {
+            directory,
+            email: None,
+            challenge: string!("http-01"),
+            storage: Some(dir.to_string_lossy().into_owned()),
+            ca: std::env::var("ZEROTRUST_TEST_PEBBLE_CA").ok(),
+            renew_days: 30,
+            accept_terms: true,
+        };
+
+        let mut config = Config::new();
+        let backend = r#"
+            display_name = "App"
+            providers = []
+            address = "127.0.0.1"
+            port = 3000
+            hosts = ["localhost"]
+        "#;
+
+        config.backends.insert(string!("app"), toml::from_str::<Location>(backend).unwrap());
+        config.settings.server.tls = Some(Tls {
+            cert: None,
+            key: None,
+            hosts: Default::default(),
+            redirect_port: Some(port),
+            acme: Some(acme.clone()),
+        });
+
+        let certs = Data::new(Certificates::default());
+        let shared = certs.clone();
+        let server = HttpServer::new(move || App::new().app_data(shared.clone()).route(&format!("{CHALLENGE_PATH}{{token}}"), web::get().to(http_challenge)))
+            .bind(("0.0.0.0", port))
+            .unwrap()
+            .run();
+
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        assert_eq!(renew(&certs, &acme, &config).await, Ok(1));
+        assert!(!needs_renewal("localhost", &acme, &config));
+        assert!(certs.tokens.read().is_empty());
+
+        // a second run finds nothing to renew and reuses the stored account
+        assert_eq!(renew(&certs, &acme, &config).await, Ok(0));
+
+        handle.stop(true).await;
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
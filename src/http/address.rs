@@ -0,0 +1,105 @@
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::config::structs::Config;
+
+use actix_web::HttpRequest;
+
+/// Whether `ip` is one of `entries`, each an address or a CIDR range.
+pub fn listed(entries: &[String], ip: IpAddr) -> bool {
+    entries.iter().any(|entry| match entry.parse::<IpNet>() {
+        Ok(net) => net.contains(&ip),
+        Err(_) => entry.parse::<IpAddr>().is_ok_and(|addr| addr == ip),
+    })
+}
+
+/// The address a request comes from, used for sign in limits, rate limits,
+/// sessions and the audit log alike. `X-Forwarded-For` is only read while the
+/// hop that added to it is in `settings.trusted_proxies`, walking it from the
+/// right, so a client cannot pick its own address by sending the header.
+pub fn client_ip(req: &HttpRequest, config: &Config) -> Option<IpAddr> {
+    let mut ip = req.peer_addr()?.ip();
+    let trusted = &config.settings.trusted_proxies;
+
+    if trusted.is_empty() {
+        return Some(ip);
+    }
+
+    let forwarded: Vec<&str> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    for hop in forwarded.into_iter().rev() {
+        if !listed(trusted, ip) {
+            break;
+        }
+
+        match hop.parse::<IpAddr>().or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip())) {
+            Ok(next) => ip = next,
+            Err(_) => break,
+        }
+    }
+
+    Some(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use macros_rs::string;
+
+    fn config(trusted: &[&str]) -> Config {
+        let mut config = Config::new();
+        config.settings.trusted_proxies = trusted.iter().map(|entry| entry.to_string()).collect();
+        config
+    }
+
+    fn client(peer: &str, forwarded: Option<&str>, trusted: &[&str]) -> Option<String> {
+        let mut req = TestRequest::default().peer_addr(format!("{peer}:40000").parse().unwrap());
+        if let Some(forwarded) = forwarded {
+            req = req.insert_header(("X-Forwarded-For", forwarded));
+        }
+
+        client_ip(&req.to_http_request(), &config(trusted)).map(|ip| ip.to_string())
+    }
+
+    #[test]
+    fn untrusted_peers_are_taken_as_is() {
+        assert_eq!(client("203.0.113.7", Some("198.51.100.1"), &[]), Some(string!("203.0.113.7")));
+        assert_eq!(client("203.0.113.7", Some("198.51.100.1"), &["127.0.0.1"]), Some(string!("203.0.113.7")));
+    }
+
+    #[test]
+    fn trusted_proxies_are_looked_through() {
+        assert_eq!(client("127.0.0.1", Some("198.51.100.1"), &["127.0.0.1"]), Some(string!("198.51.100.1")));
+        assert_eq!(client("10.0.0.2", Some("198.51.100.1, 10.0.0.1"), &["10.0.0.0/8"]), Some(string!("198.51.100.1")));
+        assert_eq!(client("10.0.0.2", Some("198.51.100.1:5000"), &["10.0.0.0/8"]), Some(string!("198.51.100.1")));
+    }
+
+    #[test]
+    fn spoofed_entries_left_of_the_client_are_ignored() {
+        assert_eq!(client("127.0.0.1", Some("1.1.1.1, 198.51.100.1"), &["127.0.0.1"]), Some(string!("198.51.100.1")));
+    }
+
+    #[test]
+    fn garbage_stops_the_walk() {
+        assert_eq!(client("127.0.0.1", Some("198.51.100.1, unknown"), &["127.0.0.1"]), Some(string!("127.0.0.1")));
+        assert_eq!(client("127.0.0.1", None, &["127.0.0.1"]), Some(string!("127.0.0.1")));
+    }
+
+    #[test]
+    fn listed_matches_addresses_and_ranges() {
+        let entries = vec![string!("192.0.2.1"), string!("10.0.0.0/8"), string!("::1")];
+
+        assert!(listed(&entries, "192.0.2.1".parse().unwrap()));
+        assert!(listed(&entries, "10.1.2.3".parse().unwrap()));
+        assert!(listed(&entries, "::1".parse().unwrap()));
+        assert!(!listed(&entries, "192.0.2.2".parse().unwrap()));
+        assert!(!listed(&[string!("not an address")], "192.0.2.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,298 @@
+use parking_lot::Mutex;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use super::health::{Health, State};
+use crate::{
+    config::{
+        routes::Routes,
+        structs::{Backend, Target},
+    },
+    models::user::User,
+};
+
+use actix_web::{HttpMessage, HttpRequest};
+
+/// Spreads requests over the targets of every backend. Shared by all
+/// workers, so the counts hold for the whole server.
+#[derive(Default)]
+pub struct Balancer {
+    turns: Mutex<HashMap<String, usize>>,
+    active: Mutex<HashMap<String, Arc<AtomicUsize>>>,
+    pub health: Health,
+}
+
+#[derive(Serialize)]
+pub struct UpstreamReport {
+    url: String,
+    weight: u32,
+    state: State,
+    failures: u32,
+    active: usize,
+}
+
+/// The target picked for one request, counted as active until dropped.
+pub struct Lease {
+    pub url: Url,
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) { self.active.fetch_sub(1, Ordering::Relaxed); }
+}
+
+/// Weighted rendezvous hashing, a key keeps its target while that target is
+/// configured and only the keys of a removed target move.
+fn score(key: &str, target: &Target) -> f64 {
+    let digest = Sha256::digest(format!("{key}\0{}", target.url).as_bytes());
+    let hash = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    let unit = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+
+    -(target.weight as f64) / unit.ln()
+}
+
+fn hash_key(req: &HttpRequest, hash_on: &str) -> Option<String> {
+    match hash_on.strip_prefix("cookie:") {
+        Some(name) => req.cookie(name).map(|cookie| cookie.value().to_string()),
+        None => req.extensions().get::<User>().map(|user| user.id.to_string()),
+    }
+}
+
+impl Balancer {
+    fn turn(&self, name: &str) -> usize {
+        let mut turns = self.turns.lock();
+        let turn = turns.entry(name.to_string()).or_default();
+
+        *turn = turn.wrapping_add(1);
+        *turn
+    }
+
+    fn active(&self, target: &Target) -> Arc<AtomicUsize> { self.active.lock().entry(target.url.to_string()).or_default().clone() }
+
+    fn round_robin<'a>(&self, name: &str, targets: &[&'a Target]) -> &'a Target {
+        let total: usize = targets.iter().map(|target| target.weight as usize).sum();
+        let mut slot = self.turn(name) % total.max(1);
+
+        for target in targets {
+            match slot.checked_sub(target.weight as usize) {
+                Some(rest) => slot = rest,
+                None => return target,
+            }
+        }
+
+        targets[0]
+    }
+
+    /// Fewest active requests per unit of weight, ties rotate so an idle pool is still shared.
+    fn least_connections<'a>(&self, name: &str, targets: &[&'a Target]) -> &'a Target {
+        let offset = self.turn(name) % targets.len();
+        let load = |target: &Target| self.active(target).load(Ordering::Relaxed);
+
+        targets
+            .iter()
+            .cycle()
+            .skip(offset)
+            .take(targets.len())
+            .min_by(|a, b| (load(a) * b.weight as usize).cmp(&(load(b) * a.weight as usize)))
+            .copied()
+            .unwrap_or(targets[0])
+    }
+
+    /// Picks a healthy target for a request to `name`, `None` when every one is
+    /// ejected. `hash` falls back to round robin when the request has no user
+    /// or cookie to hash on.
+    pub fn pick(&self, name: &str, backend: &Backend, req: &HttpRequest) -> Option<Lease> {
+        let targets: Vec<&Target> = backend.targets.iter().filter(|target| self.health.selectable(name, &target.url)).collect();
+
+        let target = match backend.balance.as_str() {
+            _ if targets.len() <= 1 => *targets.first()?,
+            "least-connections" => self.least_connections(name, &targets),
+            "hash" => match hash_key(req, &backend.hash_on) {
+                Some(key) => targets.iter().copied().max_by(|a, b| score(&key, a).total_cmp(&score(&key, b))).unwrap_or(targets[0]),
+                None => self.round_robin(name, &targets),
+            },
+            _ => self.round_robin(name, &targets),
+        };
+
+        self.health.acquire(name, &target.url, &backend.health);
+
+        let active = self.active(target);
+        active.fetch_add(1, Ordering::Relaxed);
+
+        Some(Lease { url: target.url.clone(), active })
+    }
+
+    /// Drops the counts and circuit state of backends and upstreams `routes` no
+    /// longer has, so a reload does not leave them behind. Upstreams with
+    /// requests still in flight keep their count until those finish.
+    pub fn retain(&self, routes: &Routes) {
+        let configured = |name: &str, url: &str| routes.get(name).is_some_and(|backend| backend.targets.iter().any(|target| target.url.as_str() == url));
+
+        self.turns.lock().retain(|name, _| routes.get(name).is_some());
+        self.active
+            .lock()
+            .retain(|url, active| active.load(Ordering::Relaxed) > 0 || routes.iter().any(|(name, _)| configured(name, url)));
+        self.health.retain(configured);
+    }
+
+    /// Health and load of every upstream, by backend.
+    pub fn report(&self, routes: &Routes) -> BTreeMap<String, Vec<UpstreamReport>> {
+        let mut report = BTreeMap::new();
+
+        for (name, backend) in routes.iter() {
+            let upstreams = backend
+                .targets
+                .iter()
+                .map(|target| {
+                    let (state, failures) = self.health.state(name, &target.url);
+                    UpstreamReport {
+                        url: target.url.to_string(),
+                        weight: target.weight,
+                        state,
+                        failures,
+                        active: self.active(target).load(Ordering::Relaxed),
+                    }
+                })
+                .collect();
+
+            report.insert(name.clone(), upstreams);
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::structs::{Config, HealthCheck, Location};
+    use macros_rs::string;
+
+    fn target(host: &str, weight: u32) -> Target {
+        Target {
+            url: Url::parse(&format!("http://{host}:3000")).unwrap(),
+            weight,
+        }
+    }
+
+    fn pick<'a>(targets: &[&'a Target], key: &str) -> &'a Target { targets.iter().copied().max_by(|a, b| score(key, a).total_cmp(&score(key, b))).unwrap() }
+
+    #[test]
+    fn round_robin_follows_weights() {
+        let balancer = Balancer::default();
+        let (a, b) = (target("a", 3), target("b", 1));
+        let targets = [&a, &b];
+
+        let picked: String = (0..8).map(|_| balancer.round_robin("app", &targets).url.host_str().unwrap().to_string()).collect();
+        assert_eq!(picked, "aabaaaba");
+    }
+
+    #[test]
+    fn round_robin_turns_are_per_backend() {
+        let balancer = Balancer::default();
+        let (a, b) = (target("a", 1), target("b", 1));
+        let targets = [&a, &b];
+
+        let first = balancer.round_robin("one", &targets).url.clone();
+        assert_eq!(balancer.round_robin("two", &targets).url, first);
+        assert_ne!(balancer.round_robin("one", &targets).url, first);
+    }
+
+    #[test]
+    fn round_robin_survives_zero_weights() {
+        let balancer = Balancer::default();
+        let (a, b) = (target("a", 0), target("b", 0));
+
+        assert_eq!(balancer.round_robin("app", &[&a, &b]).url, a.url);
+    }
+
+    #[test]
+    fn least_connections_weighs_load() {
+        let balancer = Balancer::default();
+        let (a, b) = (target("a", 1), target("b", 2));
+        let targets = [&a, &b];
+
+        balancer.active(&a).fetch_add(1, Ordering::Relaxed);
+        balancer.active(&b).fetch_add(1, Ordering::Relaxed);
+        for _ in 0..4 {
+            assert_eq!(balancer.least_connections("app", &targets).url, b.url);
+        }
+
+        balancer.active(&b).fetch_add(2, Ordering::Relaxed);
+        assert_eq!(balancer.least_connections("app", &targets).url, a.url);
+    }
+
+    #[test]
+    fn hashing_is_stable_and_only_moves_removed_keys() {
+        let (a, b, c) = (target("a", 1), target("b", 1), target("c", 1));
+        let all = [&a, &b, &c];
+        let without_c = [&a, &b];
+
+        for key in (0..200).map(|key| key.to_string()) {
+            let before = pick(&all, &key);
+            assert_eq!(pick(&all, &key).url, before.url);
+
+            if before.url != c.url {
+                assert_eq!(pick(&without_c, &key).url, before.url, "key {key} moved");
+            }
+        }
+    }
+
+    #[test]
+    fn hashing_follows_weights() {
+        let (a, b) = (target("a", 1), target("b", 3));
+        let targets = [&a, &b];
+
+        let heavy = (0..4000).filter(|key| pick(&targets, &key.to_string()).url == b.url).count();
+        assert!((2700..3300).contains(&heavy), "{heavy} of 4000 keys on the weight 3 target");
+    }
+
+    #[test]
+    fn retain_forgets_what_a_reload_removed() {
+        let mut config = Config::new();
+        let backend = r#"
+            display_name = "App"
+            providers = []
+            address = "127.0.0.1"
+            port = 3000
+            hosts = ["app.test"]
+            upstreams = [{ address = "a", port = 3000 }]
+        "#;
+        config.backends.insert(string!("app"), toml::from_str::<Location>(backend).unwrap());
+        let routes = Routes::new(&config).unwrap();
+
+        let balancer = Balancer::default();
+        let (a, b, c) = (target("a", 1), target("b", 1), target("c", 1));
+        let check = HealthCheck::default();
+
+        balancer.turn("app");
+        balancer.turn("gone");
+        balancer.active(&a);
+        balancer.active(&b);
+        balancer.active(&c).fetch_add(1, Ordering::Relaxed);
+        balancer.health.record("app", &a.url, false, &check);
+        balancer.health.record("app", &b.url, false, &check);
+        balancer.health.record("gone", &a.url, false, &check);
+
+        balancer.retain(&routes);
+
+        assert_eq!(balancer.turns.lock().keys().collect::<Vec<_>>(), ["app"]);
+
+        let mut active: Vec<String> = balancer.active.lock().keys().cloned().collect();
+        active.sort();
+        assert_eq!(active, [a.url.to_string(), c.url.to_string()]);
+
+        assert_eq!(balancer.health.state("app", &a.url).1, 1);
+        assert_eq!(balancer.health.state("app", &b.url).1, 0);
+        assert_eq!(balancer.health.state("gone", &a.url).1, 0);
+    }
+}
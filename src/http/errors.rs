@@ -1,58 +1,59 @@
 #![allow(dead_code)]
 
-use super::catch::FromResidual;
+use super::{catch::FromResidual, ratelimit::Quota};
 use serde::Serialize;
 
-use crate::{
-    config::structs::Config,
-    pages::{create_templates, render},
-};
+use crate::pages::{create_templates, render};
 
 use actix_web::{
     dev, error,
-    http::{header::ContentType, StatusCode},
+    http::{
+        header::{self, ContentType},
+        StatusCode,
+    },
     middleware::ErrorHandlerResponse,
     HttpResponse,
 };
 
 use derive_more::{Display, Error};
-use macros_rs::str;
-use std::convert::Infallible;
+use std::{borrow::Cow, convert::Infallible};
 use tera::Context;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Display, Error)]
 pub(crate) enum Error {
     #[display(fmt = "{}", message)]
-    NotFound { message: &'static str },
+    NotFound { message: Cow<'static, str> },
     #[display(fmt = "{}", message)]
-    InternalError { message: &'static str },
+    InternalError { message: Cow<'static, str> },
     #[display(fmt = "{}", message)]
-    BadClientData { message: &'static str },
+    BadClientData { message: Cow<'static, str> },
     #[display(fmt = "{}", message)]
-    ConnectionRefused { message: &'static str },
+    ConnectionRefused { message: Cow<'static, str> },
     #[display(fmt = "{}", message)]
-    Timeout { message: &'static str },
+    Timeout { message: Cow<'static, str> },
     #[display(fmt = "{}", message)]
-    Unauthorized { message: &'static str },
+    Unauthorized { message: Cow<'static, str> },
     #[display(fmt = "{}", message)]
-    Ratelimit { message: &'static str },
+    Ratelimit { message: Cow<'static, str>, quota: Quota, retry_after: u64 },
     #[display(fmt = "{}", message)]
-    Generic { status: StatusCode, message: &'static str },
+    Generic { status: StatusCode, message: Cow<'static, str> },
+    #[display(fmt = "{}", location)]
+    Redirect { location: String },
 }
 
 #[derive(Debug, Display, Serialize, Error)]
 #[display(fmt = "{}", message)]
 pub(crate) struct JsonError {
     pub(crate) status: u16,
-    pub(crate) message: &'static str,
+    pub(crate) message: Cow<'static, str>,
 }
 
 pub fn create_error(code: StatusCode, msg: &str, custom: Option<&str>) -> String {
     let tera = create_templates();
     let mut page = Context::new();
 
-    let path = crate::CONFIG_PATH.get().unwrap();
-    let config = Config::new().set_path(path).read();
+    let snapshot = crate::CONFIG.get().unwrap().load();
 
     let name = match custom {
         Some(name) => Some(name),
@@ -64,22 +65,33 @@ pub fn create_error(code: StatusCode, msg: &str, custom: Option<&str>) -> String
     page.insert("error_message", msg);
     page.insert("error_code", &code.as_u16());
 
-    render("error", &tera.0, &mut page, &config)
+    render("error", &tera.0, &mut page, &snapshot.config)
 }
 
 impl error::ResponseError for JsonError {
     fn error_response(&self) -> HttpResponse {
         let mut res = HttpResponse::build(StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK));
-        return res.json(self);
+        res.json(self)
     }
 }
 
 impl error::ResponseError for Error {
     fn error_response(&self) -> HttpResponse {
+        if let Error::Redirect { location } = self {
+            return HttpResponse::build(self.status_code()).insert_header((header::LOCATION, location.as_str())).finish();
+        }
+
         let payload = create_error(self.status_code(), &self.to_string(), None);
         let mut res = HttpResponse::build(self.status_code());
 
-        return res.content_type(ContentType::html()).body(payload);
+        if let Error::Ratelimit { quota, retry_after, .. } = self {
+            res.insert_header((header::RETRY_AFTER, *retry_after));
+            for header in quota.headers() {
+                res.insert_header(header);
+            }
+        }
+
+        res.content_type(ContentType::html()).body(payload)
     }
 
     fn status_code(&self) -> StatusCode {
@@ -92,6 +104,7 @@ impl error::ResponseError for Error {
             Error::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
             Error::Ratelimit { .. } => StatusCode::TOO_MANY_REQUESTS,
             Error::Generic { status, .. } => status,
+            Error::Redirect { .. } => StatusCode::TEMPORARY_REDIRECT,
         }
     }
 }
@@ -99,20 +112,20 @@ impl error::ResponseError for Error {
 impl FromResidual<Result<Infallible, actix_web::Error>> for Result<HttpResponse, Error> {
     fn from_residual(residual: Result<Infallible, actix_web::Error>) -> Self {
         let err = residual.unwrap_err();
-        return Err(Error::Generic {
+        Err(Error::Generic {
             status: err.as_response_error().status_code(),
-            message: str!(err.as_response_error().to_string()),
-        });
+            message: err.as_response_error().to_string().into(),
+        })
     }
 }
 
 impl FromResidual<Result<Infallible, reqwest::Error>> for Result<HttpResponse, Error> {
     fn from_residual(residual: Result<Infallible, reqwest::Error>) -> Self {
         let err = residual.unwrap_err();
-        return Err(Error::Generic {
+        Err(Error::Generic {
             status: err.status().unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
-            message: str!(err.to_string()),
-        });
+            message: err.to_string().into(),
+        })
     }
 }
 
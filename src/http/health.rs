@@ -0,0 +1,157 @@
+use futures_util::future::join_all;
+use parking_lot::Mutex;
+use serde::Serialize;
+use url::Url;
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::balance::Balancer;
+use crate::config::structs::HealthCheck;
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+    Healthy,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Default)]
+struct Status {
+    failures: u32,
+    successes: u32,
+    /// Set once the circuit opens, no requests are sent before it passes
+    open_until: Option<Instant>,
+    /// A half-open probe request is in flight until then
+    probing_until: Option<Instant>,
+}
+
+impl Status {
+    fn state(&self, now: Instant) -> State {
+        match self.open_until {
+            None => State::Healthy,
+            Some(until) if now < until => State::Open,
+            Some(_) => State::HalfOpen,
+        }
+    }
+}
+
+/// Circuit breaker state for every upstream, by backend and url.
+#[derive(Default)]
+pub struct Health {
+    targets: Mutex<HashMap<(String, String), Status>>,
+}
+
+fn key(service: &str, url: &Url) -> (String, String) { (service.to_string(), url.to_string()) }
+
+impl Health {
+    pub fn state(&self, service: &str, url: &Url) -> (State, u32) {
+        match self.targets.lock().get(&key(service, url)) {
+            Some(status) => (status.state(Instant::now()), status.failures),
+            None => (State::Healthy, 0),
+        }
+    }
+
+    /// Whether requests may go to `url`, a half-open upstream takes one probe at a time.
+    pub fn selectable(&self, service: &str, url: &Url) -> bool {
+        let now = Instant::now();
+        let targets = self.targets.lock();
+
+        match targets.get(&key(service, url)) {
+            Some(status) => match status.state(now) {
+                State::Healthy => true,
+                State::Open => false,
+                State::HalfOpen => status.probing_until.is_none_or(|until| now >= until),
+            },
+            None => true,
+        }
+    }
+
+    /// Forgets every upstream `keep` turns down, once it is no longer configured.
+    pub fn retain(&self, keep: impl Fn(&str, &str) -> bool) { self.targets.lock().retain(|(service, url), _| keep(service, url)); }
+
+    /// Marks the request about to be sent to a half-open upstream as its probe.
+    pub fn acquire(&self, service: &str, url: &Url, check: &HealthCheck) {
+        let now = Instant::now();
+
+        if let Some(status) = self.targets.lock().get_mut(&key(service, url)).filter(|status| status.state(now) == State::HalfOpen) {
+            status.probing_until = Some(now + Duration::from_secs(check.cooldown));
+        }
+    }
+
+    /// Counts the outcome of a request or check, opening and closing the circuit as thresholds are reached.
+    pub fn record(&self, service: &str, url: &Url, ok: bool, check: &HealthCheck) {
+        let now = Instant::now();
+        let mut targets = self.targets.lock();
+        let status = targets.entry(key(service, url)).or_default();
+
+        status.probing_until = None;
+
+        if ok {
+            status.failures = 0;
+            status.successes = status.successes.saturating_add(1);
+
+            if status.open_until.is_some() && status.successes >= check.healthy_threshold {
+                *status = Status::default();
+                tracing::info!(service, upstream = url.as_str(), "upstream recovered");
+            }
+
+            return;
+        }
+
+        status.successes = 0;
+        status.failures = status.failures.saturating_add(1);
+
+        match status.state(now) {
+            State::Healthy if status.failures >= check.unhealthy_threshold => {
+                status.open_until = Some(now + Duration::from_secs(check.cooldown));
+                tracing::warn!(service, upstream = url.as_str(), failures = status.failures, "upstream ejected");
+            }
+            State::HalfOpen => {
+                status.open_until = Some(now + Duration::from_secs(check.cooldown));
+                tracing::warn!(service, upstream = url.as_str(), failures = status.failures, "upstream still failing");
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Requests `check.path` on every upstream of `service` each interval, until
+/// the task is aborted by a config reload.
+pub async fn check(balancer: Arc<Balancer>, service: String, targets: Vec<Url>, check: HealthCheck) {
+    let Some(path) = check.path.clone() else {
+        return;
+    };
+
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(check.timeout))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+    {
+        Ok(client) => client,
+        Err(err) => return tracing::error!(service, "unable to start health checks: {err}"),
+    };
+
+    let mut interval = tokio::time::interval(Duration::from_secs(check.interval));
+
+    loop {
+        interval.tick().await;
+
+        let results = join_all(targets.iter().map(|target| {
+            let mut url = target.clone();
+            url.set_path(&path);
+
+            let request = client.get(url).send();
+            async move { matches!(request.await, Ok(res) if res.status().is_success() || res.status().is_redirection()) }
+        }))
+        .await;
+
+        for (target, ok) in targets.iter().zip(results) {
+            balancer.health.record(&service, target, ok, &check);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use macros_rs::string;
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::time::Instant;
+
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+
+use super::{
+    address,
+    errors::{Error, JsonError},
+};
+use crate::{
+    config::{db::Pool, live::Live, structs::Config},
+    models::session::Session,
+};
+
+use actix_web::{http::header, web::Data, HttpRequest, HttpResponse, ResponseError};
+
+/// Every metric this process exports, shared by all workers.
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    upstream_errors: IntCounterVec,
+    websockets: IntGaugeVec,
+    logins: IntCounterVec,
+    reloads: IntCounterVec,
+    sessions: IntGauge,
+    connections: IntGaugeVec,
+}
+
+/// An open websocket, counted until it is dropped with the stream it belongs to.
+pub struct Websocket(IntGauge);
+
+impl Drop for Websocket {
+    fn drop(&mut self) { self.0.dec(); }
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some(string!("zerotrust")), None).expect("Invalid metrics prefix");
+
+        let metrics = Self {
+            requests: IntCounterVec::new(Opts::new("requests_total", "Proxied requests by backend and status"), &["service", "status"]).unwrap(),
+            duration: HistogramVec::new(
+                HistogramOpts::new("request_duration_seconds", "Time until the response head of proxied requests, by backend and status"),
+                &["service", "status"],
+            )
+            .unwrap(),
+            upstream_errors: IntCounterVec::new(
+                Opts::new("upstream_errors_total", "Failed attempts to reach an upstream, by backend and reason"),
+                &["service", "reason"],
+            )
+            .unwrap(),
+            websockets: IntGaugeVec::new(Opts::new("websocket_connections", "Websockets open to each backend"), &["service"]).unwrap(),
+            logins: IntCounterVec::new(Opts::new("logins_total", "Sign ins by method and result"), &["method", "result"]).unwrap(),
+            reloads: IntCounterVec::new(Opts::new("config_reloads_total", "Config reloads by result"), &["result"]).unwrap(),
+            sessions: IntGauge::new("sessions_active", "Sessions that have not expired").unwrap(),
+            connections: IntGaugeVec::new(Opts::new("db_connections", "Database pool connections by state"), &["state"]).unwrap(),
+            registry,
+        };
+
+        metrics.registry.register(Box::new(metrics.requests.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.duration.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.upstream_errors.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.websockets.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.logins.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.reloads.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.sessions.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.connections.clone())).unwrap();
+
+        metrics
+    }
+
+    /// Counts a proxied request by the status it was answered with, ours or the upstream's.
+    pub fn request(&self, service: &str, result: &Result<HttpResponse, Error>, started: Instant) {
+        let status = match result {
+            Ok(res) => res.status().as_u16().to_string(),
+            Err(err) => err.status_code().as_u16().to_string(),
+        };
+
+        self.requests.with_label_values(&[service, &status]).inc();
+        self.duration.with_label_values(&[service, &status]).observe(started.elapsed().as_secs_f64());
+    }
+
+    /// `reason` is `connect`, `timeout` or `status` for a 5xx answer that was retried.
+    pub fn upstream_error(&self, service: &str, reason: &str) { self.upstream_errors.with_label_values(&[service, reason]).inc(); }
+
+    pub fn websocket(&self, service: &str) -> Websocket {
+        let gauge = self.websockets.with_label_values(&[service]);
+        gauge.inc();
+        Websocket(gauge)
+    }
+
+    pub fn login(&self, method: &str, success: bool) {
+        let result = match success {
+            true => "success",
+            false => "failure",
+        };
+
+        self.logins.with_label_values(&[method, result]).inc();
+    }
+
+    pub fn reload(&self, success: bool) {
+        let result = match success {
+            true => "success",
+            false => "failure",
+        };
+
+        self.reloads.with_label_values(&[result]).inc();
+    }
+
+    /// Reads the gauges that are not kept up as things happen, then encodes everything.
+    fn render(&self, pool: &Pool) -> Result<String, prometheus::Error> {
+        let state = pool.state();
+        self.connections.with_label_values(&["idle"]).set(state.idle_connections as i64);
+        self.connections.with_label_values(&["in_use"]).set((state.connections - state.idle_connections) as i64);
+        self.connections.with_label_values(&["max"]).set(pool.max_size() as i64);
+
+        match pool.get().map(|mut conn| Session::count_active(&mut conn)) {
+            Ok(Ok(active)) => self.sessions.set(active),
+            Ok(Err(err)) => tracing::error!(err = err.to_string(), "unable to count sessions"),
+            Err(err) => tracing::error!(err = err.to_string(), "unable to count sessions"),
+        }
+
+        TextEncoder::new().encode_to_string(&self.registry.gather())
+    }
+}
+
+/// Whether the request may read metrics: from an address in `allow`, or with `token` as a bearer token.
+fn permitted(req: &HttpRequest, allow: &[String], token: Option<&str>, config: &Config) -> bool {
+    let allowed = address::client_ip(req, config).is_some_and(|ip| address::listed(allow, ip));
+
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    // digests keep the comparison from giving away how much of the token matched
+    let authorized = match (token, bearer) {
+        (Some(token), Some(bearer)) => Sha256::digest(token.as_bytes()) == Sha256::digest(bearer.trim().as_bytes()),
+        _ => false,
+    };
+
+    allowed || authorized
+}
+
+/// Prometheus text format, answered with 404 unless `settings.metrics` is set.
+pub async fn handler(req: HttpRequest, config: Live<Config>, pool: Data<Pool>) -> Result<HttpResponse, JsonError> {
+    tracing::info!(method = string!(req.method()), "internal '{}'", req.uri());
+
+    let config = config.get_ref();
+    let Some(settings) = &config.settings.metrics else {
+        return Err(JsonError {
+            status: 404,
+            message: "Metrics are not enabled.".into(),
+        });
+    };
+
+    if !permitted(&req, &settings.allow, settings.token.as_deref(), config) {
+        tracing::warn!(ip = address::client_ip(&req, config).map(|ip| ip.to_string()), "metrics denied");
+        return Err(JsonError {
+            status: 403,
+            message: "You are not allowed to read metrics.".into(),
+        });
+    }
+
+    match METRICS.render(&pool) {
+        Ok(body) => Ok(HttpResponse::Ok().content_type(prometheus::TEXT_FORMAT).body(body)),
+        Err(err) => {
+            tracing::error!(err = err.to_string(), "unable to encode metrics");
+            Err(JsonError {
+                status: 500,
+                message: "Unable to encode metrics.".into(),
+            })
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use super::{address, errors::Error};
+use crate::{
+    config::{
+        db::Pool,
+        structs::{Config, RateLimit},
+    },
+    models::{rate_limit::Bucket, token::UserToken, user::User},
+};
+
+use actix_web::{HttpMessage, HttpRequest};
+
+/// How often full buckets are dropped, a new one starts full all the same.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Stored buckets untouched for this many seconds are dropped, long after any sane rate refilled them.
+const STORED_IDLE: u64 = 86400;
+
+/// Token buckets for backends with a `rate_limit`. They live in this process,
+/// shared by every worker, unless `settings.rate_limit_store` is `database`
+/// and every node draws from the same ones.
+pub struct Limiter {
+    pool: Pool,
+    buckets: Mutex<HashMap<(String, String), Tokens>>,
+    pruned: Mutex<Instant>,
+}
+
+struct Tokens {
+    available: f64,
+    updated: Instant,
+    full_at: Instant,
+}
+
+/// What is left of a client's bucket, sent back as `RateLimit-*` headers.
+#[derive(Debug)]
+pub struct Quota {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again
+    pub reset: u64,
+}
+
+impl Quota {
+    fn new(limit: &RateLimit, tokens: f64) -> Self {
+        Self {
+            limit: limit.burst,
+            remaining: tokens.max(0.0) as u32,
+            reset: ((limit.burst as f64 - tokens) / limit.rate).ceil().max(0.0) as u64,
+        }
+    }
+
+    pub fn headers(&self) -> [(&'static str, String); 3] {
+        [
+            ("RateLimit-Limit", self.limit.to_string()),
+            ("RateLimit-Remaining", self.remaining.to_string()),
+            ("RateLimit-Reset", self.reset.to_string()),
+        ]
+    }
+}
+
+impl Limiter {
+    pub fn new(pool: Pool) -> Self {
+        Self {
+            pool,
+            buckets: Mutex::new(HashMap::new()),
+            pruned: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Takes a token for the request from the service's bucket. `None` when the
+    /// service has no limit, `Error::Ratelimit` when the bucket is empty. A
+    /// database that cannot be reached lets the request through.
+    pub fn check(&self, req: &HttpRequest, service: &str, limit: Option<&RateLimit>, config: &Config) -> Result<Option<Quota>, Error> {
+        let Some(limit) = limit else {
+            return Ok(None);
+        };
+
+        let client = client_key(req, &limit.key, config);
+        let (tokens, allowed) = match config.settings.rate_limit_store.as_str() {
+            "database" => match self.take_stored(service, &client, limit) {
+                Some(taken) => taken,
+                None => return Ok(None),
+            },
+            _ => self.take(service, &client, limit, Instant::now()),
+        };
+
+        let quota = Quota::new(limit, tokens);
+        match allowed {
+            true => Ok(Some(quota)),
+            false => {
+                let retry_after = ((1.0 - tokens) / limit.rate).ceil().max(1.0) as u64;
+                tracing::warn!(service, client, retry_after, "rate limited");
+
+                Err(Error::Ratelimit {
+                    message: "Too many requests, please slow down and try again shortly.".into(),
+                    quota,
+                    retry_after,
+                })
+            }
+        }
+    }
+
+    fn take(&self, service: &str, client: &str, limit: &RateLimit, now: Instant) -> (f64, bool) {
+        let burst = limit.burst as f64;
+        let mut buckets = self.buckets.lock();
+
+        let mut pruned = self.pruned.lock();
+        if now.duration_since(*pruned) >= PRUNE_INTERVAL {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+            *pruned = now;
+        }
+
+        let bucket = buckets.entry((service.to_string(), client.to_string())).or_insert(Tokens {
+            available: burst,
+            updated: now,
+            full_at: now,
+        });
+
+        bucket.available = (bucket.available + now.duration_since(bucket.updated).as_secs_f64() * limit.rate).min(burst);
+        bucket.updated = now;
+
+        let allowed = bucket.available >= 1.0;
+        if allowed {
+            bucket.available -= 1.0;
+        }
+
+        let refill = Duration::try_from_secs_f64((burst - bucket.available) / limit.rate).ok();
+        bucket.full_at = refill.and_then(|refill| now.checked_add(refill)).unwrap_or(now + PRUNE_INTERVAL);
+        (bucket.available, allowed)
+    }
+
+    fn take_stored(&self, service: &str, client: &str, limit: &RateLimit) -> Option<(f64, bool)> {
+        let conn = &mut self.pool.get().ok()?;
+
+        let mut pruned = self.pruned.lock();
+        if pruned.elapsed() >= PRUNE_INTERVAL {
+            *pruned = Instant::now();
+            if let Err(err) = Bucket::prune(STORED_IDLE, conn) {
+                tracing::error!(err = err.to_string(), "unable to prune rate limits");
+            }
+        }
+        drop(pruned);
+
+        match Bucket::take(service, client, limit.rate, limit.burst, conn) {
+            Ok(bucket) => Some((bucket.tokens, bucket.allowed)),
+            Err(err) => {
+                tracing::error!(err = err.to_string(), service, "unable to check rate limit");
+                None
+            }
+        }
+    }
+}
+
+/// Who the bucket belongs to. Requests without a user or token, or with
+/// `key = "ip"`, are counted by the address they come from.
+fn client_key(req: &HttpRequest, key: &str, config: &Config) -> String {
+    let extensions = req.extensions();
+    let client = match key {
+        "user" => extensions.get::<User>().map(|user| format!("user:{}", user.id)),
+        "token" => extensions.get::<UserToken>().map(|token| format!("token:{}", token.login_session)),
+        _ => None,
+    };
+
+    client.unwrap_or_else(|| format!("ip:{}", address::client_ip(req, config).map(|ip| ip.to_string()).unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::r2d2::ConnectionManager;
+    use macros_rs::string;
+
+    fn limiter() -> Limiter { Limiter::new(Pool::builder().build_unchecked(ConnectionManager::new("postgres://unused"))) }
+
+    fn limit(rate: f64, burst: u32) -> RateLimit { RateLimit { key: string!("ip"), rate, burst } }
+
+    #[test]
+    fn burst_is_available_at_once() {
+        let (limiter, limit, now) = (limiter(), limit(1.0, 3), Instant::now());
+
+        assert_eq!(limiter.take("app", "ip:a", &limit, now), (2.0, true));
+        assert_eq!(limiter.take("app", "ip:a", &limit, now), (1.0, true));
+        assert_eq!(limiter.take("app", "ip:a", &limit, now), (0.0, true));
+        assert_eq!(limiter.take("app", "ip:a", &limit, now), (0.0, false));
+    }
+
+    #[test]
+    fn tokens_refill_at_the_rate_up_to_the_burst() {
+        let (limiter, limit, now) = (limiter(), limit(2.0, 2), Instant::now());
+
+        limiter.take("app", "ip:a", &limit, now);
+        limiter.take("app", "ip:a", &limit, now);
+        assert!(!limiter.take("app", "ip:a", &limit, now).1);
+
+        let (tokens, allowed) = limiter.take("app", "ip:a", &limit, now + Duration::from_millis(250));
+        assert!(!allowed && (tokens - 0.5).abs() < 1e-9);
+        assert!(limiter.take("app", "ip:a", &limit, now + Duration::from_millis(500)).1);
+
+        let (tokens, allowed) = limiter.take("app", "ip:a", &limit, now + Duration::from_secs(60));
+        assert!(allowed && (tokens - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn buckets_are_per_service_and_client() {
+        let (limiter, limit, now) = (limiter(), limit(1.0, 1), Instant::now());
+
+        assert!(limiter.take("app", "ip:a", &limit, now).1);
+        assert!(!limiter.take("app", "ip:a", &limit, now).1);
+        assert!(limiter.take("app", "ip:b", &limit, now).1);
+        assert!(limiter.take("other", "ip:a", &limit, now).1);
+    }
+
+    #[test]
+    fn full_buckets_are_pruned() {
+        let (limiter, limit, now) = (limiter(), limit(1.0, 2), Instant::now());
+
+        limiter.take("app", "ip:a", &limit, now);
+        limiter.take("app", "ip:b", &limit, now + PRUNE_INTERVAL);
+        assert_eq!(limiter.buckets.lock().len(), 1);
+    }
+
+    #[test]
+    fn quota_reports_time_to_full() {
+        let quota = Quota::new(&limit(0.5, 10), 7.5);
+        assert_eq!((quota.limit, quota.remaining, quota.reset), (10, 7, 5));
+    }
+}
@@ -0,0 +1,196 @@
+use super::token;
+use crate::{
+    auth::{assertion, oauth, webauthn},
+    config::structs::{Config, Location},
+};
+
+use actix_web::{
+    http::header::{self, HeaderName, HeaderValue},
+    HttpRequest,
+};
+
+/// Cookies that only mean something to zerotrust and never reach a backend.
+const COOKIES: [&str; 3] = ["sp_token", oauth::STATE_COOKIE, webauthn::STATE_COOKIE];
+
+/// Headers that describe a single connection, see RFC 9110 section 7.6.1.
+const HOP_BY_HOP: [&str; 8] = ["connection", "keep-alive", "proxy-authenticate", "proxy-authorization", "te", "trailer", "transfer-encoding", "upgrade"];
+
+/// Headers the request cannot be understood without, forwarded regardless of the allow list.
+const ESSENTIAL: [&str; 4] = ["host", "content-length", "content-type", "content-encoding"];
+
+pub fn is_hop_by_hop(name: &HeaderName) -> bool { HOP_BY_HOP.contains(&name.as_str()) }
+
+/// Matches a header against a list entry, where a trailing `*` matches any suffix.
+fn matches(name: &str, pattern: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.len() >= prefix.len() && name[..prefix.len()].eq_ignore_ascii_case(prefix),
+        None => name.eq_ignore_ascii_case(pattern),
+    }
+}
+
+fn allowed(name: &str, location: Option<&Location>) -> bool {
+    let Some(location) = location else {
+        return true;
+    };
+
+    if location.deny_headers.iter().any(|pattern| matches(name, pattern)) {
+        return false;
+    }
+
+    ESSENTIAL.contains(&name) || location.allow_headers.is_empty() || location.allow_headers.iter().any(|pattern| matches(name, pattern))
+}
+
+/// Removes zerotrust's own cookies, leaving `None` when nothing else is left.
+fn strip_cookies(value: &HeaderValue) -> Option<HeaderValue> {
+    let cookies: Vec<&str> = value
+        .to_str()
+        .ok()?
+        .split(';')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty() && !COOKIES.contains(&pair.split('=').next().unwrap_or_default()))
+        .collect();
+
+    match cookies.is_empty() {
+        true => None,
+        false => HeaderValue::from_str(&cookies.join("; ")).ok(),
+    }
+}
+
+/// A bearer token is only stripped when zerotrust signed it, backends with
+/// their own tokens keep receiving them. Expired sessions are stripped too.
+fn is_session_bearer(value: &HeaderValue, config: &Config) -> bool {
+    match value.to_str().ok().and_then(|value| value.strip_prefix("Bearer ")) {
+        Some(bearer) => token::is_signed(bearer.trim(), config),
+        None => false,
+    }
+}
+
+/// Picks the client headers that may be forwarded to `service`. Websocket
+/// handshakes keep the `Connection` and `Upgrade` headers they depend on.
+pub fn request_headers(req: &HttpRequest, service: &str, upgrade: bool, config: &Config) -> Vec<(HeaderName, HeaderValue)> {
+    let location = config.backends.get(service);
+
+    // anything named in `Connection` is hop-by-hop as well
+    let listed: Vec<String> = req
+        .headers()
+        .get_all(header::CONNECTION)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !(upgrade && name == "upgrade"))
+        .collect();
+
+    let mut headers = vec![];
+    for (name, value) in req.headers().iter() {
+        let handshake = upgrade && (name == header::CONNECTION || name == header::UPGRADE);
+        let hop = !handshake && (is_hop_by_hop(name) || listed.iter().any(|listed| listed == name.as_str()));
+
+        if hop || assertion::is_identity_header(name.as_str(), config) || !allowed(name.as_str(), location) {
+            continue;
+        }
+
+        if name == header::AUTHORIZATION && is_session_bearer(value, config) {
+            continue;
+        }
+
+        match name == header::COOKIE {
+            true => headers.extend(strip_cookies(value).map(|value| (name.clone(), value))),
+            false => headers.push((name.clone(), value.clone())),
+        }
+    }
+
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::token::UserToken;
+    use actix_web::test::TestRequest;
+    use jsonwebtoken::{EncodingKey, Header};
+    use macros_rs::string;
+
+    fn bearer(exp: i64, secret: &str) -> HeaderValue {
+        let claims = UserToken {
+            iat: 0,
+            exp,
+            user: string!("alice"),
+            login_session: string!("session"),
+            method: string!("password"),
+            mfa: false,
+        };
+
+        let token = jsonwebtoken::encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap();
+        HeaderValue::from_str(&format!("Bearer {token}")).unwrap()
+    }
+
+    fn names(headers: Vec<(HeaderName, HeaderValue)>) -> Vec<String> { headers.into_iter().map(|(name, _)| name.to_string()).collect() }
+
+    #[test]
+    fn patterns_match_names_and_prefixes() {
+        assert!(matches("X-Keep", "x-keep"));
+        assert!(matches("accept-language", "accept*"));
+        assert!(matches("accept", "accept*"));
+        assert!(matches("anything", "*"));
+        assert!(!matches("acc", "accept*"));
+        assert!(!matches("x-keeper", "x-keep"));
+    }
+
+    #[test]
+    fn only_our_cookies_are_stripped() {
+        let strip = |value: &str| strip_cookies(&HeaderValue::from_str(value).unwrap()).map(|value| value.to_str().unwrap().to_string());
+
+        assert_eq!(strip("sp_token=abc; theme=dark"), Some(string!("theme=dark")));
+        assert_eq!(strip("theme=dark;sp_token=abc;;lang=en"), Some(string!("theme=dark; lang=en")));
+        assert_eq!(strip("sp_token_extra=1; xsp_token=2"), Some(string!("sp_token_extra=1; xsp_token=2")));
+        assert_eq!(strip("sp_token=abc"), None);
+        assert_eq!(strip(" ; "), None);
+    }
+
+    #[test]
+    fn bearers_we_signed_are_stripped_even_when_expired() {
+        let config = Config::new();
+        let secret = config.settings.secret.clone();
+
+        assert!(is_session_bearer(&bearer(chrono::Utc::now().timestamp() + 60, &secret), &config));
+        assert!(is_session_bearer(&bearer(1, &secret), &config));
+        assert!(!is_session_bearer(&bearer(1, "someone else"), &config));
+        assert!(!is_session_bearer(&HeaderValue::from_static("Bearer not-a-jwt"), &config));
+        assert!(!is_session_bearer(&HeaderValue::from_static("Basic dXNlcjpwYXNz"), &config));
+    }
+
+    #[test]
+    fn request_headers_drop_hop_by_hop_and_filtered_headers() {
+        let mut config = Config::new();
+        let backend = |extra: &str| {
+            let backend = format!("display_name = \"App\"\nproviders = []\naddress = \"127.0.0.1\"\nport = 3000\nhosts = []\ndeny_headers = [\"accept-secret\"]\n{extra}");
+            toml::from_str::<Location>(&backend).unwrap()
+        };
+
+        config.backends.insert(string!("app"), backend("allow_headers = [\"x-keep\", \"accept*\"]"));
+        config.backends.insert(string!("open"), backend(""));
+
+        let req = TestRequest::default()
+            .insert_header(("Connection", "keep-alive, x-keep"))
+            .insert_header(("X-Keep", "1"))
+            .insert_header(("Accept-Language", "en"))
+            .insert_header(("Accept-Secret", "1"))
+            .insert_header(("X-Other", "1"))
+            .insert_header(("Content-Type", "text/plain"))
+            .insert_header(("Upgrade", "websocket"))
+            .insert_header(("Authorization", bearer(1, &config.settings.secret)))
+            .to_http_request();
+
+        let mut forwarded = names(request_headers(&req, "app", false, &config));
+        forwarded.sort();
+        assert_eq!(forwarded, vec!["accept-language", "content-type"]);
+
+        let mut open = names(request_headers(&req, "open", false, &config));
+        open.sort();
+        assert_eq!(open, vec!["accept-language", "content-type", "x-other"]);
+
+        let mut upgraded = names(request_headers(&req, "open", true, &config));
+        upgraded.sort();
+        assert_eq!(upgraded, vec!["accept-language", "connection", "content-type", "upgrade", "x-other"]);
+    }
+}
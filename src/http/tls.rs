@@ -0,0 +1,160 @@
+use parking_lot::RwLock;
+use rustls_pemfile::Item;
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::Path, sync::Arc};
+
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    PrivateKey, ServerConfig,
+};
+
+use super::acme;
+use crate::config::{
+    live::Live,
+    structs::{Config, Tls},
+};
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    Error, HttpResponse,
+};
+
+#[derive(Default)]
+struct Loaded {
+    default: Option<Arc<CertifiedKey>>,
+    hosts: BTreeMap<String, Arc<CertifiedKey>>,
+}
+
+/// Certificates shared by every TLS listener, swapped in place when the
+/// files on disk change so open connections are left alone.
+#[derive(Default)]
+pub struct Certificates {
+    loaded: RwLock<Loaded>,
+    /// Key authorizations for pending HTTP-01 challenges, by token
+    pub(crate) tokens: RwLock<BTreeMap<String, String>>,
+    /// Certificates answering pending TLS-ALPN-01 challenges, by host
+    pub(crate) challenges: RwLock<BTreeMap<String, Arc<CertifiedKey>>>,
+}
+
+pub(crate) fn certified_key(chain: Vec<Vec<u8>>, key: Vec<u8>) -> Result<Arc<CertifiedKey>, String> {
+    let signing_key = sign::any_supported_type(&PrivateKey(key)).map_err(|err| err.to_string())?;
+    Ok(Arc::new(CertifiedKey::new(chain.into_iter().map(rustls::Certificate).collect(), signing_key)))
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, String> {
+    let file = File::open(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(|err| format!("{}: {err}", path.display()))
+}
+
+fn load(cert: &Path, key: &Path) -> Result<Arc<CertifiedKey>, String> {
+    let chain: Vec<Vec<u8>> = read_pem(cert)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(der),
+            _ => None,
+        })
+        .collect();
+
+    if chain.is_empty() {
+        return Err(format!("{}: no certificates found", cert.display()));
+    }
+
+    let key_der = read_pem(key)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(der),
+            _ => None,
+        })
+        .ok_or_else(|| format!("{}: no private key found", key.display()))?;
+
+    certified_key(chain, key_der).map_err(|err| format!("{}: {err}", key.display()))
+}
+
+impl Certificates {
+    /// Reads every certificate the config points at, keeping the current set when any of them fails.
+    pub fn reload(&self, config: &Config) -> Result<(), String> {
+        let Some(tls) = &config.settings.server.tls else {
+            return Ok(());
+        };
+
+        let mut loaded = Loaded::default();
+        if let (Some(cert), Some(key)) = (&tls.cert, &tls.key) {
+            loaded.default = Some(load(Path::new(cert), Path::new(key))?);
+        }
+
+        // issued certificates go first so hosts configured by hand win
+        if tls.acme.is_some() {
+            for host in acme::hosts(config) {
+                let (cert, key) = acme::paths(&host, config);
+                if cert.is_file() && key.is_file() {
+                    loaded.hosts.insert(host, load(&cert, &key)?);
+                }
+            }
+        }
+
+        for (host, certificate) in tls.hosts.iter() {
+            loaded.hosts.insert(host.to_lowercase(), load(Path::new(&certificate.cert), Path::new(&certificate.key))?);
+        }
+
+        *self.loaded.write() = loaded;
+        Ok(())
+    }
+
+    pub fn server_config(self: &Arc<Self>, tls: &Tls) -> ServerConfig {
+        let mut server_config = ServerConfig::builder().with_safe_defaults().with_no_client_auth().with_cert_resolver(self.clone());
+
+        if tls.acme.is_some() {
+            server_config.alpn_protocols.push(acme::ALPN.to_vec());
+        }
+
+        server_config
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let name = hello.server_name().map(str::to_lowercase);
+
+        if hello.alpn().is_some_and(|mut protocols| protocols.any(|protocol| protocol == acme::ALPN)) {
+            return name.and_then(|name| self.challenges.read().get(&name).cloned());
+        }
+
+        let loaded = self.loaded.read();
+        let by_name = name.and_then(|name| {
+            let wildcard = name.split_once('.').map(|(_, parent)| format!("*.{parent}"));
+            loaded.hosts.get(&name).or_else(|| wildcard.and_then(|wildcard| loaded.hosts.get(&wildcard)))
+        });
+
+        by_name.or(loaded.default.as_ref()).cloned()
+    }
+}
+
+/// Sends requests that arrived on the plain HTTP redirect listener to HTTPS.
+pub async fn redirect(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let config = Live::<Config>::from_req(req.request());
+
+    if req.app_config().secure() || config.settings.server.tls.is_none() || req.path().starts_with(acme::CHALLENGE_PATH) {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+
+    let port = match config.settings.server.port {
+        443 => String::new(),
+        port => format!(":{port}"),
+    };
+
+    let host = req.connection_info().host().to_string();
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => &host,
+    };
+
+    let location = format!("https://{host}{port}{}", req.uri().path_and_query().map_or("/", |path| path.as_str()));
+
+    let (request, _pl) = req.into_parts();
+    let response = HttpResponse::PermanentRedirect().insert_header((header::LOCATION, location)).finish();
+
+    Ok(ServiceResponse::new(request, response).map_into_right_body())
+}
@@ -1,20 +1,18 @@
-use actix_web::web::Data;
+use crate::{config::structs::Config, models::token::UserToken};
 use jsonwebtoken::{DecodingKey, TokenData, Validation};
 
-use crate::{
-    config::{db::Pool, structs::Config},
-    models::{token::UserToken, user::User},
-};
+/// Whether `token` carries a valid signature from `settings.secret`, however
+/// old it is or whatever it claims.
+pub fn is_signed(token: &str, config: &Config) -> bool {
+    let mut validation = Validation::default();
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    validation.required_spec_claims.clear();
 
-pub fn decode_token(token: String, config: &Config) -> jsonwebtoken::errors::Result<TokenData<UserToken>> {
-    let secret = config.settings.secret.as_bytes();
-    jsonwebtoken::decode::<UserToken>(&token, &DecodingKey::from_secret(&secret), &Validation::default())
+    jsonwebtoken::decode::<serde_json::Value>(token, &DecodingKey::from_secret(config.settings.secret.as_bytes()), &validation).is_ok()
 }
 
-pub fn verify_token(token_data: &TokenData<UserToken>, pool: &Data<Pool>) -> Result<String, String> {
-    if User::is_valid_login_session(&token_data.claims, &mut pool.get().unwrap()) {
-        Ok(token_data.claims.user.to_string())
-    } else {
-        Err("Invalid token".to_string())
-    }
+pub fn decode_token(token: String, config: &Config) -> jsonwebtoken::errors::Result<TokenData<UserToken>> {
+    let secret = config.settings.secret.as_bytes();
+    jsonwebtoken::decode::<UserToken>(&token, &DecodingKey::from_secret(secret), &Validation::default())
 }
@@ -0,0 +1,193 @@
+use futures_util::{stream, Stream, StreamExt};
+use macros_rs::ternary;
+use url::Url;
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io,
+    time::{Duration, Instant},
+};
+
+use awc::{
+    error::{ConnectError, PayloadError, SendRequestError},
+    ClientRequest, ClientResponse,
+};
+
+use super::{
+    balance::{Balancer, Lease},
+    errors::Error,
+    metrics,
+};
+
+use crate::config::structs::Backend;
+
+use actix_web::{
+    dev::{self, Decompress},
+    http::{header, Method},
+    web::{Bytes, Payload},
+    HttpRequest,
+};
+
+pub(crate) type Response = ClientResponse<Decompress<dev::Payload>>;
+
+/// Methods that can be sent twice without changing the outcome, see RFC 9110 section 9.2.2.
+fn is_idempotent(method: &Method) -> bool { matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE) }
+
+/// The body is streamed straight through, so only requests without one can be sent again.
+fn has_body(req: &HttpRequest) -> bool { req.headers().contains_key(header::TRANSFER_ENCODING) || req.headers().get(header::CONTENT_LENGTH).is_some_and(|length| length != "0") }
+
+/// Long-lived clients for every backend. awc clients cannot leave their
+/// worker, so each worker keeps its own set, built on first use and rebuilt
+/// once a reload changes the connection settings of a backend.
+#[derive(Default)]
+pub struct Clients {
+    backends: RefCell<HashMap<String, Pooled>>,
+}
+
+struct Pooled {
+    settings: (u64, usize, u64),
+    http: awc::Client,
+    websocket: reqwest::Client,
+}
+
+impl Pooled {
+    fn settings(backend: &Backend) -> (u64, usize, u64) { (backend.timeouts.connect, backend.pool_size, backend.pool_idle_timeout) }
+
+    fn new(backend: &Backend) -> Result<Self, reqwest::Error> {
+        let connect = Duration::from_secs(backend.timeouts.connect);
+        let idle = Duration::from_secs(backend.pool_idle_timeout);
+
+        // timeouts other than connecting are applied per attempt
+        let connector = awc::Connector::new().timeout(connect).limit(backend.pool_size).conn_keep_alive(idle);
+        let http = awc::Client::builder().connector(connector).disable_redirects().disable_timeout().finish();
+        let websocket = reqwest::Client::builder()
+            .connect_timeout(connect)
+            .pool_max_idle_per_host(backend.pool_size)
+            .pool_idle_timeout(idle)
+            .build()?;
+
+        Ok(Self {
+            settings: Self::settings(backend),
+            http,
+            websocket,
+        })
+    }
+}
+
+impl Clients {
+    fn with<T>(&self, name: &str, backend: &Backend, get: impl FnOnce(&Pooled) -> T) -> Result<T, Error> {
+        let mut backends = self.backends.borrow_mut();
+
+        if backends.get(name).is_none_or(|pooled| pooled.settings != Pooled::settings(backend)) {
+            match Pooled::new(backend) {
+                Ok(pooled) => backends.insert(name.to_string(), pooled),
+                Err(err) => {
+                    tracing::error!(service = name, "unable to create upstream client: {err}");
+                    return Err(Error::ConnectionRefused {
+                        message: "Sorry, this service could not be reached.".into(),
+                    });
+                }
+            };
+        }
+
+        Ok(get(&backends[name]))
+    }
+
+    pub fn http(&self, name: &str, backend: &Backend) -> Result<awc::Client, Error> { self.with(name, backend, |pooled| pooled.http.clone()) }
+
+    pub fn websocket(&self, name: &str, backend: &Backend) -> Result<reqwest::Client, Error> { self.with(name, backend, |pooled| pooled.websocket.clone()) }
+}
+
+/// Sends the request `build` makes for a target of `name`, picking a new target
+/// for every retry until `retries` or the total deadline runs out. The last
+/// 5xx response is passed on as it is.
+pub(crate) async fn send(req: &HttpRequest, payload: Payload, name: &str, backend: &Backend, balancer: &Balancer, build: impl Fn(&Url) -> ClientRequest) -> Result<(Response, Lease), Error> {
+    let timeouts = &backend.timeouts;
+    let deadline = Instant::now() + Duration::from_secs(timeouts.total);
+
+    let retries = match is_idempotent(req.method()) && !has_body(req) {
+        true => backend.retries,
+        false => 0,
+    };
+
+    let mut payload = Some(payload);
+    let mut attempt = 0;
+
+    loop {
+        let lease = balancer.pick(name, backend, req).ok_or(Error::ConnectionRefused {
+            message: "Sorry, this service is temporarily unavailable.".into(),
+        })?;
+
+        let request = build(&lease.url);
+        let sent = match payload.take() {
+            Some(payload) if retries == 0 => request.send_stream(payload),
+            _ => request.send(),
+        };
+
+        let wait = Duration::from_secs(timeouts.read).min(deadline.saturating_duration_since(Instant::now()));
+        let result = tokio::time::timeout(wait, sent).await;
+
+        let backoff = Duration::from_millis(backend.retry_backoff.saturating_mul(1 << attempt.min(16)));
+        let retry = attempt < retries && Instant::now() + backoff < deadline;
+
+        let (timed_out, reason) = match result {
+            Ok(Ok(res)) => {
+                balancer.health.record(name, &lease.url, !res.status().is_server_error(), &backend.health);
+
+                if !retry || !matches!(res.status().as_u16(), 502..=504) {
+                    return Ok((res, lease));
+                }
+
+                metrics::METRICS.upstream_error(name, "status");
+                (false, format!("responded with {}", res.status()))
+            }
+            Ok(Err(err)) => {
+                balancer.health.record(name, &lease.url, false, &backend.health);
+
+                let timed_out = matches!(err, SendRequestError::Timeout | SendRequestError::Connect(ConnectError::Timeout));
+                metrics::METRICS.upstream_error(name, ternary!(timed_out, "timeout", "connect"));
+                (timed_out, err.to_string())
+            }
+            Err(_) => {
+                balancer.health.record(name, &lease.url, false, &backend.health);
+                metrics::METRICS.upstream_error(name, "timeout");
+                (true, format!("no response within {}ms", wait.as_millis()))
+            }
+        };
+
+        tracing::warn!(service = name, upstream = lease.url.as_str(), attempt, retry, "upstream request failed: {reason}");
+
+        if !retry {
+            return Err(match timed_out {
+                true => Error::Timeout {
+                    message: "Sorry, this service took too long to respond.".into(),
+                },
+                false => Error::ConnectionRefused {
+                    message: "Sorry, this service could not be reached.".into(),
+                },
+            });
+        }
+
+        drop(lease);
+        tokio::time::sleep(backoff).await;
+        attempt += 1;
+    }
+}
+
+/// Streams a response body, ending it once the upstream goes quiet for
+/// `read`. The lease is held until the body is done.
+pub(crate) fn body(res: Response, lease: Lease, read: Duration) -> impl Stream<Item = Result<Bytes, PayloadError>> {
+    stream::unfold(Some((res, lease)), move |state| async move {
+        let (mut res, lease) = state?;
+
+        match tokio::time::timeout(read, res.next()).await {
+            Ok(Some(chunk)) => Some((chunk, Some((res, lease)))),
+            Ok(None) => None,
+            Err(_) => {
+                tracing::warn!(upstream = lease.url.as_str(), "upstream stopped sending the body");
+                Some((Err(PayloadError::Io(io::Error::new(io::ErrorKind::TimedOut, "upstream stopped sending"))), None))
+            }
+        }
+    })
+}
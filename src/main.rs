@@ -1,4 +1,6 @@
+mod admin;
 mod app;
+mod audit;
 mod auth;
 mod cli;
 mod config;
@@ -8,14 +10,25 @@ mod models;
 mod pages;
 mod schema;
 
+use actix_web::web::Data;
 use clap::Parser;
 use clap_verbosity_flag::{InfoLevel, Verbosity};
-use config::{db::Pool, structs::Config};
+use cli::Commands;
+use colored::Colorize;
+use config::{
+    db::Pool,
+    live::{Shared, Snapshot},
+    structs::Config,
+};
 use macros_rs::{crashln, file_exists, str};
-use notify_debouncer_mini::{new_debouncer, notify::RecursiveMode, DebounceEventResult};
+use notify_debouncer_mini::{
+    new_debouncer,
+    notify::{RecursiveMode, Watcher},
+    DebounceEventResult,
+};
 use once_cell::sync::OnceCell;
-use std::{path::Path, time::Duration};
-use tokio::sync::mpsc;
+use std::{fs, path::Path, sync::Arc, time::Duration};
+use tokio::{sync::mpsc, task::JoinHandle};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_subscriber::{filter::LevelFilter, prelude::*};
 
@@ -33,24 +46,38 @@ pub struct Cli {
     /// Override config port
     #[arg(short, long)]
     pub port: Option<u16>,
+    #[command(subcommand)]
+    pub command: Option<Commands>,
 }
 
 #[derive(Debug)]
-struct ConfigUpdated;
+pub enum Reload {
+    Config,
+    Certificates,
+}
 
 pub static POOL: OnceCell<Pool> = OnceCell::new();
+pub static CONFIG: OnceCell<Data<Shared>> = OnceCell::new();
 pub static CONFIG_PATH: OnceCell<String> = OnceCell::new();
+pub static RELOAD: OnceCell<mpsc::Sender<Reload>> = OnceCell::new();
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
     let (reload_tx, mut reload_rx) = mpsc::channel(1);
+    let services_tx = reload_tx.clone();
 
     let formatting_layer = BunyanFormattingLayer::new("server".into(), std::io::stdout)
         .skip_fields(vec!["file", "line"].into_iter())
         .expect("Unable to create logger");
 
-    let level = match cli.verbose.log_level_filter() as usize {
+    // keep subcommand output readable unless more verbosity was asked for
+    let verbosity = match (&cli.command, cli.verbose.log_level_filter() as usize) {
+        (Some(_), 3) => 2,
+        (_, verbosity) => verbosity,
+    };
+
+    let level = match verbosity {
         0 => Some(LevelFilter::OFF),
         1 => Some(LevelFilter::ERROR),
         2 => Some(LevelFilter::WARN),
@@ -66,17 +93,25 @@ async fn main() -> anyhow::Result<()> {
         .with(formatting_layer)
         .init();
 
+    // certificate directories are watched too, anything that is not the config is a certificate
+    let config_path = fs::canonicalize(&cli.config).ok();
     let mut notify = new_debouncer(Duration::from_millis(250), move |res: DebounceEventResult| match res {
-        Ok(_) => {
-            tracing::info!("config updated");
-            reload_tx.blocking_send(ConfigUpdated).unwrap();
-        }
+        Ok(events) => match events.iter().any(|event| fs::canonicalize(&event.path).ok() == config_path) {
+            true => {
+                tracing::info!("config updated");
+                reload_tx.blocking_send(Reload::Config).unwrap();
+            }
+            false => reload_tx.blocking_send(Reload::Certificates).unwrap(),
+        },
         Err(err) => tracing::error!("file watch error: {err}"),
     })
     .unwrap();
 
     if let Err(err) = CONFIG_PATH.set(cli.config.clone()) {
         crashln!("Failed to set config path!\n{:?}", err)
+    } else if let Some(command) = cli.command.clone() {
+        cli::run(command, &cli.config);
+        return Ok(());
     } else {
         if !file_exists!(&cli.config) {
             Config::new().set_path(&cli.config).write();
@@ -88,28 +123,117 @@ async fn main() -> anyhow::Result<()> {
     let pool = config::db::init_db(&cli.config.clone());
     config::db::run_migrations(&mut pool.get().unwrap());
 
+    if let Ok(purged) = models::session::Session::purge_expired(&mut pool.get().unwrap()) {
+        tracing::info!(purged, "removed expired sessions");
+    }
+
     if let Err(err) = POOL.set(pool.clone()) {
         crashln!("Failed to set pool!\n{:?}", err)
     };
 
     notify.watcher().watch(Path::new(&cli.config), RecursiveMode::NonRecursive).unwrap();
 
+    let live = match Snapshot::read(&cli, &pool, None) {
+        Ok(snapshot) => Data::new(Shared::from_pointee(snapshot)),
+        Err(problems) => crashln!("Unable to load {}.\n{}", cli.config, problems.join("\n").white()),
+    };
+
+    if CONFIG.set(live.clone()).is_err() || RELOAD.set(services_tx).is_err() {
+        crashln!("Failed to set config!")
+    };
+
+    let certs = Arc::new(http::tls::Certificates::default());
+    let balancer = Arc::new(http::balance::Balancer::default());
+
     loop {
-        let mut server = http::start(pool.clone(), cli.clone());
+        watch_certificates(notify.watcher(), &live.load().config);
+
+        let mut server = http::start(pool.clone(), live.clone(), certs.clone(), balancer.clone());
+        let mut tasks = spawn_tasks(&live.load(), &certs, &balancer);
         let handle = server.handle();
 
-        tokio::select! {
-            res = &mut server => {
-                res?;
-                break;
-            },
-            Some(_) = reload_rx.recv() => {
-                drop(handle.stop(true));
-                server.await?;
-                continue;
+        loop {
+            tokio::select! {
+                res = &mut server => return Ok(res?),
+                Some(reload) = reload_rx.recv() => match reload {
+                    Reload::Config => {
+                        // editors often replace the file, which ends the watch on the old one
+                        if let Err(err) = notify.watcher().watch(Path::new(&cli.config), RecursiveMode::NonRecursive) {
+                            tracing::warn!(path = cli.config, "unable to watch config: {err}");
+                        }
+
+                        // a config that does not load leaves the running one in place
+                        let next = match Snapshot::read(&cli, &pool, Some(&live.load())) {
+                            Ok(next) => next,
+                            Err(problems) => {
+                                tracing::error!(problems = problems.join(", "), "config not reloaded");
+                                http::metrics::METRICS.reload(false);
+                                continue;
+                            }
+                        };
+
+                        let restart = live.load().needs_restart(&next);
+                        balancer.retain(&next.routes);
+                        live.store(Arc::new(next));
+                        http::metrics::METRICS.reload(true);
+
+                        for task in tasks.drain(..) {
+                            task.abort();
+                        }
+
+                        if restart {
+                            tracing::info!("listener changed, restarting server");
+                            drop(handle.stop(true));
+                            server.await?;
+                            break;
+                        }
+
+                        watch_certificates(notify.watcher(), &live.load().config);
+                        reload_certificates(&certs, &live.load().config);
+                        tasks = spawn_tasks(&live.load(), &certs, &balancer);
+                        tracing::info!("config reloaded");
+                    }
+                    Reload::Certificates => reload_certificates(&certs, &live.load().config),
+                }
             }
         }
     }
+}
+
+/// Background work that follows the config, aborted and spawned again on every reload.
+fn spawn_tasks(snapshot: &Snapshot, certs: &Arc<http::tls::Certificates>, balancer: &Arc<http::balance::Balancer>) -> Vec<JoinHandle<()>> {
+    let mut tasks = vec![];
 
-    Ok(())
+    if snapshot.config.settings.server.tls.as_ref().is_some_and(|tls| tls.acme.is_some()) {
+        tasks.push(tokio::spawn(http::acme::run(certs.clone(), snapshot.config.clone())));
+    }
+
+    for (name, backend) in snapshot.routes.iter().filter(|(_, backend)| backend.health.path.is_some()) {
+        let targets = backend.targets.iter().map(|target| target.url.clone()).collect();
+        tasks.push(tokio::spawn(http::health::check(balancer.clone(), name.clone(), targets, backend.health.clone())));
+    }
+
+    tasks
+}
+
+fn watch_certificates(watcher: &mut dyn Watcher, config: &Config) {
+    if let Some(tls) = &config.settings.server.tls {
+        for path in tls.paths() {
+            let dir = Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            if let Err(err) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                tracing::warn!(path, "unable to watch certificate: {err}");
+            }
+        }
+    }
+}
+
+fn reload_certificates(certs: &http::tls::Certificates, config: &Config) {
+    if config.settings.server.tls.is_none() {
+        return;
+    }
+
+    match certs.reload(config) {
+        Ok(_) => tracing::info!("certificates reloaded"),
+        Err(err) => tracing::error!(err, "unable to reload certificates"),
+    }
 }
@@ -0,0 +1,125 @@
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use diesel::{dsl::count_star, prelude::*, upsert::excluded, Insertable, Queryable};
+use serde::Serialize;
+
+use crate::{
+    config::db::Connection,
+    schema::{
+        lockouts,
+        login_failures::{self, dsl::*},
+    },
+};
+
+/// A rejected password or second factor. `account` is the username when the
+/// attempt named a known user, otherwise what was typed, lowercased. Cleared
+/// failures are kept for the record but no longer count against the account.
+#[derive(Clone, Debug, Queryable, Serialize)]
+#[diesel(table_name = login_failures)]
+pub struct LoginFailure {
+    pub id: i32,
+    pub account: String,
+    pub ip: Option<String>,
+    pub cleared: bool,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = login_failures)]
+pub struct LoginFailureInsertableDTO {
+    pub account: String,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// An account that stopped accepting sign ins until `locked_until`.
+#[derive(Clone, Debug, Queryable, Insertable, Serialize)]
+#[diesel(table_name = lockouts)]
+pub struct Lockout {
+    pub account: String,
+    pub failures: i32,
+    pub locked_until: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+impl LoginFailure {
+    pub fn record(name: &str, from: Option<String>, conn: &mut Connection) -> QueryResult<usize> {
+        let record = LoginFailureInsertableDTO {
+            account: name.to_string(),
+            ip: from,
+            created_at: Utc::now().naive_utc(),
+        };
+
+        diesel::insert_into(login_failures).values(&record).execute(conn)
+    }
+
+    /// Uncleared failures for the account within the last `window` seconds.
+    pub fn count_account(name: &str, window: u64, conn: &mut Connection) -> QueryResult<i64> {
+        login_failures
+            .filter(account.eq(name))
+            .filter(cleared.eq(false))
+            .filter(created_at.gt(since(window)))
+            .select(count_star())
+            .get_result(conn)
+    }
+
+    /// Failures from the address within the last `window` seconds, across every account.
+    pub fn count_ip(from: &str, window: u64, conn: &mut Connection) -> QueryResult<i64> {
+        login_failures.filter(ip.eq(from)).filter(created_at.gt(since(window))).select(count_star()).get_result(conn)
+    }
+
+    pub fn find(name: Option<&str>, limit: i64, conn: &mut Connection) -> QueryResult<Vec<LoginFailure>> {
+        let mut query = login_failures.order(created_at.desc()).limit(limit).into_boxed();
+
+        if let Some(name) = name {
+            query = query.filter(account.eq(name.to_string()));
+        }
+
+        query.load::<LoginFailure>(conn)
+    }
+
+    /// Stops the account's failures counting, after a sign in or when an admin clears it.
+    pub fn clear(name: &str, conn: &mut Connection) -> QueryResult<usize> { diesel::update(login_failures.filter(account.eq(name)).filter(cleared.eq(false))).set(cleared.eq(true)).execute(conn) }
+}
+
+impl Lockout {
+    pub fn lock(name: &str, count: i32, seconds: u64, conn: &mut Connection) -> QueryResult<Lockout> {
+        let now = Utc::now().naive_utc();
+        let record = Lockout {
+            account: name.to_string(),
+            failures: count,
+            locked_until: now + TimeDelta::seconds(seconds as i64),
+            created_at: now,
+        };
+
+        diesel::insert_into(lockouts::table)
+            .values(&record)
+            .on_conflict(lockouts::account)
+            .do_update()
+            .set((
+                lockouts::failures.eq(excluded(lockouts::failures)),
+                lockouts::locked_until.eq(excluded(lockouts::locked_until)),
+                lockouts::created_at.eq(excluded(lockouts::created_at)),
+            ))
+            .get_result::<Lockout>(conn)
+    }
+
+    /// The lockout on the account, if it has not run out yet.
+    pub fn active(name: &str, conn: &mut Connection) -> QueryResult<Option<Lockout>> {
+        lockouts::table
+            .find(name)
+            .filter(lockouts::locked_until.gt(Utc::now().naive_utc()))
+            .get_result::<Lockout>(conn)
+            .optional()
+    }
+
+    pub fn all(conn: &mut Connection) -> QueryResult<Vec<Lockout>> {
+        lockouts::table
+            .filter(lockouts::locked_until.gt(Utc::now().naive_utc()))
+            .order(lockouts::locked_until.desc())
+            .load::<Lockout>(conn)
+    }
+
+    pub fn delete(name: &str, conn: &mut Connection) -> QueryResult<usize> { diesel::delete(lockouts::table.find(name)).execute(conn) }
+}
+
+fn since(window: u64) -> NaiveDateTime { Utc::now().naive_utc() - TimeDelta::seconds(window as i64) }
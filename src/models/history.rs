@@ -1,19 +1,33 @@
 use chrono::{NaiveDateTime, Utc};
 use diesel::{prelude::*, Associations, Identifiable, Insertable, Queryable};
+use serde::Serialize;
 
 use crate::{
     config::db::Connection,
-    models::user::User,
-    schema::login_history::{self, dsl::*},
+    models::{session::SessionInfo, user::User},
+    schema::{
+        login_history::{self, dsl::*},
+        users,
+    },
 };
 
-#[derive(Identifiable, Associations, Queryable)]
+/// A sign in attempt on a known account. `method` is what completed it:
+/// `password`, `totp`, `passkey` or the OAuth provider's name. Failed
+/// attempts carry a `reason` and no session. The history outlives the
+/// account, `user_id` is cleared when the user is deleted.
+#[derive(Identifiable, Associations, Queryable, Serialize)]
 #[diesel(belongs_to(User))]
 #[diesel(table_name = login_history)]
 pub struct LoginHistory {
     pub id: i32,
-    pub user_id: i32,
+    pub user_id: Option<i32>,
     pub login_timestamp: NaiveDateTime,
+    pub success: bool,
+    pub reason: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub method: String,
+    pub session_id: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -21,20 +35,66 @@ pub struct LoginHistory {
 pub struct LoginHistoryInsertableDTO {
     pub user_id: i32,
     pub login_timestamp: NaiveDateTime,
+    pub success: bool,
+    pub reason: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub method: String,
+    pub session_id: Option<String>,
 }
 
-impl LoginHistory {
-    pub fn create(un: &str, conn: &mut Connection) -> Option<LoginHistoryInsertableDTO> {
-        if let Ok(user) = User::find_user_by_username(un, conn) {
-            let now = Utc::now();
-            Some(LoginHistoryInsertableDTO {
-                user_id: user.id,
-                login_timestamp: now.naive_utc(),
-            })
-        } else {
-            None
+impl LoginHistoryInsertableDTO {
+    fn new(uid: i32, info: &SessionInfo) -> Self {
+        Self {
+            user_id: uid,
+            login_timestamp: Utc::now().naive_utc(),
+            success: false,
+            reason: None,
+            ip: info.ip.clone(),
+            user_agent: info.user_agent.clone(),
+            method: info.method.clone(),
+            session_id: None,
         }
     }
+}
+
+impl LoginHistory {
+    pub fn success(uid: i32, info: &SessionInfo, session: &str, conn: &mut Connection) -> QueryResult<usize> {
+        let record = LoginHistoryInsertableDTO {
+            success: true,
+            session_id: Some(session.to_string()),
+            ..LoginHistoryInsertableDTO::new(uid, info)
+        };
+
+        diesel::insert_into(login_history).values(&record).execute(conn)
+    }
+
+    pub fn failure(uid: i32, info: &SessionInfo, why: &str, conn: &mut Connection) -> QueryResult<usize> {
+        let record = LoginHistoryInsertableDTO {
+            reason: Some(why.to_string()),
+            ..LoginHistoryInsertableDTO::new(uid, info)
+        };
+
+        diesel::insert_into(login_history).values(&record).execute(conn)
+    }
+
+    /// The latest attempts with the username they were made on, none for deleted users, newest first.
+    pub fn find(uid: Option<i32>, succeeded: Option<bool>, limit: i64, conn: &mut Connection) -> QueryResult<Vec<(LoginHistory, Option<String>)>> {
+        let mut query = login_history
+            .left_join(users::table)
+            .select((login_history::all_columns, users::username.nullable()))
+            .order(login_timestamp.desc())
+            .limit(limit)
+            .into_boxed();
+
+        if let Some(uid) = uid {
+            query = query.filter(user_id.eq(uid));
+        }
+
+        if let Some(succeeded) = succeeded {
+            query = query.filter(success.eq(succeeded));
+        }
 
-    pub fn save_login_history(insert_record: LoginHistoryInsertableDTO, conn: &mut Connection) -> QueryResult<usize> { diesel::insert_into(login_history).values(&insert_record).execute(conn) }
+        query.load::<(LoginHistory, Option<String>)>(conn)
+    }
 }
@@ -1,3 +1,10 @@
+pub mod audit;
+pub mod failure;
 pub mod history;
+pub mod rate_limit;
+pub mod service;
+pub mod session;
 pub mod token;
+pub mod totp;
 pub mod user;
+pub mod webauthn;
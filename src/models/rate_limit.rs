@@ -0,0 +1,49 @@
+use chrono::{TimeDelta, Utc};
+use diesel::{
+    prelude::*,
+    sql_query,
+    sql_types::{Bool, Double, Text},
+    QueryableByName,
+};
+
+use crate::{config::db::Connection, schema::rate_limits};
+
+/// What is left of a shared bucket after taking from it.
+#[derive(QueryableByName)]
+pub struct Bucket {
+    #[diesel(sql_type = Double)]
+    pub tokens: f64,
+    #[diesel(sql_type = Bool)]
+    pub allowed: bool,
+}
+
+// refills by the time since the last request, then takes a token when a whole one is there,
+// on the database clock in UTC like every other timestamp, since nodes' clocks may drift apart
+const TAKE: &str = "
+    WITH clock AS (SELECT now() AT TIME ZONE 'UTC' AS now)
+    INSERT INTO rate_limits AS bucket (service, key, tokens, allowed, updated_at)
+    SELECT $1, $2, $4 - 1, TRUE, clock.now FROM clock
+    ON CONFLICT (service, key) DO UPDATE SET
+        allowed = LEAST($4, bucket.tokens + EXTRACT(EPOCH FROM excluded.updated_at - bucket.updated_at)::float8 * $3) >= 1,
+        tokens = LEAST($4, bucket.tokens + EXTRACT(EPOCH FROM excluded.updated_at - bucket.updated_at)::float8 * $3)
+            - CASE WHEN LEAST($4, bucket.tokens + EXTRACT(EPOCH FROM excluded.updated_at - bucket.updated_at)::float8 * $3) >= 1 THEN 1 ELSE 0 END,
+        updated_at = excluded.updated_at
+    RETURNING tokens, allowed";
+
+impl Bucket {
+    /// Takes a token from the client's bucket in one statement, so nodes
+    /// sharing the database never hand out the same token twice.
+    pub fn take(name: &str, client: &str, rate: f64, burst: u32, conn: &mut Connection) -> QueryResult<Bucket> {
+        sql_query(TAKE)
+            .bind::<Text, _>(name)
+            .bind::<Text, _>(client)
+            .bind::<Double, _>(rate)
+            .bind::<Double, _>(burst as f64)
+            .get_result::<Bucket>(conn)
+    }
+
+    /// Drops buckets untouched for `idle` seconds, which have long refilled.
+    pub fn prune(idle: u64, conn: &mut Connection) -> QueryResult<usize> {
+        diesel::delete(rate_limits::table.filter(rate_limits::updated_at.lt(Utc::now().naive_utc() - TimeDelta::seconds(idle as i64)))).execute(conn)
+    }
+}
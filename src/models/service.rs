@@ -0,0 +1,49 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::{prelude::*, upsert::excluded, Insertable, Queryable};
+use serde_json::Value;
+
+use crate::{
+    config::db::Connection,
+    schema::services::{self, dsl::*},
+};
+
+/// A backend added through the admin API, `location` holds the same fields
+/// as a `[backends.<name>]` table in the config file.
+#[derive(Clone, Debug, Queryable)]
+#[diesel(table_name = services)]
+pub struct Service {
+    pub name: String,
+    pub location: Value,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = services)]
+pub struct ServiceInsertableDTO {
+    pub name: String,
+    pub location: Value,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Service {
+    pub fn all(conn: &mut Connection) -> QueryResult<Vec<Service>> { services.order(name.asc()).load::<Service>(conn) }
+
+    /// Creates the service or replaces the one stored under the same name.
+    pub fn save(service: &str, item: Value, conn: &mut Connection) -> QueryResult<Service> {
+        let record = ServiceInsertableDTO {
+            name: service.to_string(),
+            location: item,
+            updated_at: Utc::now().naive_utc(),
+        };
+
+        diesel::insert_into(services)
+            .values(&record)
+            .on_conflict(name)
+            .do_update()
+            .set((location.eq(excluded(location)), updated_at.eq(excluded(updated_at))))
+            .get_result::<Service>(conn)
+    }
+
+    pub fn delete(service: &str, conn: &mut Connection) -> QueryResult<usize> { diesel::delete(services.find(service)).execute(conn) }
+}
@@ -0,0 +1,100 @@
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use diesel::{prelude::*, Associations, Identifiable, Insertable, Queryable};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    config::db::Connection,
+    models::user::User,
+    schema::{
+        sessions::{self, dsl::*},
+        users,
+    },
+};
+
+#[derive(Clone, Debug, Identifiable, Associations, Queryable, Serialize)]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = sessions)]
+pub struct Session {
+    pub id: String,
+    pub user_id: i32,
+    pub created_at: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = sessions)]
+pub struct SessionInsertableDTO {
+    pub id: String,
+    pub user_id: i32,
+    pub created_at: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct SessionInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub max_age: i64,
+    /// How the user signed in, recorded in the login history
+    pub method: String,
+}
+
+impl Session {
+    pub fn create(user: &User, info: &SessionInfo, conn: &mut Connection) -> QueryResult<Session> {
+        let now = Utc::now().naive_utc();
+        let record = SessionInsertableDTO {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id,
+            created_at: now,
+            last_seen: now,
+            expires_at: now + TimeDelta::seconds(info.max_age),
+            ip: info.ip.clone(),
+            user_agent: info.user_agent.clone(),
+        };
+
+        diesel::insert_into(sessions).values(&record).get_result::<Session>(conn)
+    }
+
+    pub fn find_by_user(uid: i32, conn: &mut Connection) -> QueryResult<Vec<Session>> {
+        sessions
+            .filter(user_id.eq(uid))
+            .filter(expires_at.gt(Utc::now().naive_utc()))
+            .order(last_seen.desc())
+            .load::<Session>(conn)
+    }
+
+    pub fn all_active(conn: &mut Connection) -> QueryResult<Vec<(Session, User)>> {
+        sessions
+            .inner_join(users::table)
+            .filter(expires_at.gt(Utc::now().naive_utc()))
+            .order(last_seen.desc())
+            .load::<(Session, User)>(conn)
+    }
+
+    pub fn count_active(conn: &mut Connection) -> QueryResult<i64> { sessions.filter(expires_at.gt(Utc::now().naive_utc())).count().get_result(conn) }
+
+    /// Only writes when the stored value is more than a minute old, so that
+    /// busy sessions don't turn every proxied request into an UPDATE.
+    pub fn touch(&self, conn: &mut Connection) {
+        let now = Utc::now().naive_utc();
+
+        if now - self.last_seen > TimeDelta::seconds(60) {
+            if let Err(err) = diesel::update(sessions.find(&self.id)).set(last_seen.eq(now)).execute(conn) {
+                tracing::error!(err = err.to_string(), "unable to update session");
+            }
+        }
+    }
+
+    pub fn revoke(session_id: &str, conn: &mut Connection) -> QueryResult<usize> { diesel::delete(sessions.find(session_id)).execute(conn) }
+
+    pub fn revoke_all(uid: i32, conn: &mut Connection) -> QueryResult<usize> { diesel::delete(sessions.filter(user_id.eq(uid))).execute(conn) }
+
+    pub fn purge_expired(conn: &mut Connection) -> QueryResult<usize> { diesel::delete(sessions.filter(expires_at.le(Utc::now().naive_utc()))).execute(conn) }
+}
@@ -3,14 +3,23 @@ use chrono::Utc;
 use jsonwebtoken::{EncodingKey, Header};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct UserToken {
     pub iat: i64,
     pub exp: i64,
     pub user: String,
     pub login_session: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub mfa: bool,
 }
 
+/// How a session signed in through the built in sign in, all of which count
+/// as the `basic` provider. Tokens from before methods were recorded say `basic`.
+const LOCAL_METHODS: [&str; 4] = ["basic", "password", "totp", "passkey"];
+
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize)]
 pub struct TokenBodyResponse {
     pub token: String,
@@ -18,7 +27,7 @@ pub struct TokenBodyResponse {
 }
 
 impl UserToken {
-    pub fn generate_token(login: &LoginInfoDTO, config: &Config) -> String {
+    pub fn generate_token(login: &LoginInfoDTO, method: &str, mfa: bool, config: &Config) -> String {
         let max_age = config.settings.max_age;
         let secret = config.settings.secret.as_bytes();
 
@@ -28,8 +37,24 @@ impl UserToken {
             exp: now + max_age,
             user: login.username.clone(),
             login_session: login.login_session.clone(),
+            method: method.to_string(),
+            mfa,
         };
 
-        jsonwebtoken::encode(&Header::default(), &payload, &EncodingKey::from_secret(&secret)).unwrap()
+        jsonwebtoken::encode(&Header::default(), &payload, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    /// The provider the session came from, `basic` for the built in sign in.
+    pub fn provider(&self) -> &str {
+        match LOCAL_METHODS.contains(&self.method.as_str()) {
+            true => "basic",
+            false => &self.method,
+        }
     }
+
+    /// Signed in with a password alone, so a second factor can still be asked
+    /// for. Provider sessions leave the second factor to the provider.
+    pub fn password_only(&self) -> bool { !self.mfa && self.provider() == "basic" }
 }
+
+fn default_method() -> String { "basic".into() }
@@ -0,0 +1,92 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::{prelude::*, Associations, Identifiable, Insertable, Queryable};
+
+use crate::{
+    config::db::Connection,
+    models::user::User,
+    schema::user_totp::{self, dsl::*},
+};
+
+#[derive(Clone, Debug, Identifiable, Associations, Queryable)]
+#[diesel(belongs_to(User))]
+#[diesel(primary_key(user_id))]
+#[diesel(table_name = user_totp)]
+pub struct Totp {
+    pub user_id: i32,
+    pub secret: String,
+    pub enabled: bool,
+    pub last_step: i64,
+    pub recovery_codes: Vec<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = user_totp)]
+pub struct TotpInsertableDTO {
+    pub user_id: i32,
+    pub secret: String,
+    pub enabled: bool,
+    pub last_step: i64,
+    pub recovery_codes: Vec<String>,
+    pub created_at: NaiveDateTime,
+}
+
+impl Totp {
+    pub fn find(uid: i32, conn: &mut Connection) -> QueryResult<Totp> { user_totp.find(uid).get_result::<Totp>(conn) }
+
+    pub fn is_enabled(uid: i32, conn: &mut Connection) -> bool { Self::find(uid, conn).map(|totp| totp.enabled).unwrap_or(false) }
+
+    /// Stores a new pending secret, replacing any earlier enrollment that was never confirmed.
+    pub fn begin(uid: i32, encrypted: String, conn: &mut Connection) -> QueryResult<Totp> {
+        let record = TotpInsertableDTO {
+            user_id: uid,
+            secret: encrypted,
+            enabled: false,
+            last_step: 0,
+            recovery_codes: vec![],
+            created_at: Utc::now().naive_utc(),
+        };
+
+        // an enabled row is left alone, so the insert fails on its primary key
+        diesel::delete(user_totp.find(uid).filter(enabled.eq(false))).execute(conn)?;
+        diesel::insert_into(user_totp).values(&record).get_result::<Totp>(conn)
+    }
+
+    pub fn enable(uid: i32, step: i64, codes: Vec<String>, conn: &mut Connection) -> QueryResult<Totp> {
+        diesel::update(user_totp.find(uid))
+            .set((enabled.eq(true), last_step.eq(step), recovery_codes.eq(codes)))
+            .get_result::<Totp>(conn)
+    }
+
+    /// Only advances forward, so a code can't be replayed by two concurrent requests.
+    pub fn use_step(uid: i32, step: i64, conn: &mut Connection) -> bool {
+        diesel::update(user_totp.find(uid).filter(last_step.lt(step)))
+            .set(last_step.eq(step))
+            .execute(conn)
+            .is_ok_and(|updated| updated == 1)
+    }
+
+    /// Removes a hashed recovery code, returning whether it was still unused.
+    pub fn use_recovery_code(uid: i32, hashed: &str, conn: &mut Connection) -> bool {
+        let totp = match Self::find(uid, conn) {
+            Ok(totp) => totp,
+            Err(_) => return false,
+        };
+
+        let remaining: Vec<String> = totp.recovery_codes.iter().filter(|code| *code != hashed).cloned().collect();
+        if remaining.len() == totp.recovery_codes.len() {
+            return false;
+        }
+
+        diesel::update(user_totp.find(uid).filter(recovery_codes.eq(&totp.recovery_codes)))
+            .set(recovery_codes.eq(remaining))
+            .execute(conn)
+            .is_ok_and(|updated| updated == 1)
+    }
+
+    pub fn replace_recovery_codes(uid: i32, codes: Vec<String>, conn: &mut Connection) -> QueryResult<Totp> {
+        diesel::update(user_totp.find(uid)).set(recovery_codes.eq(codes)).get_result::<Totp>(conn)
+    }
+
+    pub fn delete(uid: i32, conn: &mut Connection) -> QueryResult<usize> { diesel::delete(user_totp.find(uid)).execute(conn) }
+}
@@ -1,25 +1,35 @@
 use bcrypt::{hash, verify, DEFAULT_COST};
-use diesel::{prelude::*, Identifiable, Insertable, Queryable};
+use chrono::Utc;
+use derive_more::Display;
+use diesel::{prelude::*, result::Error as DieselError, Identifiable, Insertable, Queryable};
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
 
 use crate::{
     config::db::Connection,
-    models::{history::LoginHistory, token::UserToken},
-    schema::users::{self, dsl::*},
+    models::{
+        history::LoginHistory,
+        session::{Session, SessionInfo},
+        token::UserToken,
+    },
+    schema::{
+        sessions,
+        users::{self, dsl::*},
+    },
 };
 
-#[derive(Debug, Identifiable, Queryable, Serialize, Deserialize)]
+#[derive(Clone, Debug, Identifiable, Queryable, Serialize, Deserialize)]
 pub struct User {
     pub id: i32,
     pub admin: bool,
     pub username: String,
     pub email: String,
+    #[serde(skip_serializing)]
     pub password: String,
     pub providers: Vec<String>,
     pub services: Vec<String>,
+    #[serde(skip_serializing)]
     pub tokens: Vec<String>,
-    pub login_session: String,
+    pub disabled: bool,
 }
 
 #[derive(Insertable, Serialize, Deserialize)]
@@ -34,102 +44,224 @@ pub struct UserDTO {
     pub services: Vec<String>,
 }
 
+#[derive(Default, AsChangeset, Deserialize)]
+#[diesel(table_name = users)]
+pub struct UserUpdateDTO {
+    pub admin: Option<bool>,
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub password: Option<String>,
+    pub providers: Option<Vec<String>>,
+    pub services: Option<Vec<String>>,
+    pub disabled: Option<bool>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct LoginDTO {
     pub username_or_email: String,
     pub password: String,
 }
 
-#[derive(Insertable, Serialize, Deserialize)]
-#[diesel(table_name = users)]
+/// Why [`User::signup`] refused an account. Only the conflicts are meant for
+/// the person signing up, a database error is logged.
+#[derive(Debug, Display)]
+pub enum SignupError {
+    #[display(fmt = "User '{}' is already registered", _0)]
+    UsernameTaken(String),
+    #[display(fmt = "Email '{}' is already registered", _0)]
+    EmailTaken(String),
+    #[display(fmt = "{}", _0)]
+    Database(DieselError),
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct LoginInfoDTO {
     pub username: String,
     pub login_session: String,
 }
 
 impl User {
-    pub fn signup(new_user: UserDTO, conn: &mut Connection) -> Result<String, String> {
-        if Self::find_user_by_username(&new_user.username, conn).is_err() {
-            let new_user = UserDTO {
-                password: hash(&new_user.password, DEFAULT_COST).unwrap(),
-                ..new_user
-            };
-
-            match diesel::insert_into(users).values(new_user).execute(conn) {
-                Ok(_) => Ok("User added successfully".to_string()),
-                Err(err) => Err(err.to_string()),
-            }
-        } else {
-            Err(format!("User '{}' is already registered", &new_user.username))
+    pub fn signup(new_user: UserDTO, conn: &mut Connection) -> Result<User, SignupError> {
+        if Self::find_user_by_username(&new_user.username, conn).is_ok() {
+            return Err(SignupError::UsernameTaken(new_user.username));
         }
+
+        if Self::find_user_by_email(&new_user.email, conn).is_ok() {
+            return Err(SignupError::EmailTaken(new_user.email));
+        }
+
+        let new_user = UserDTO {
+            password: Self::hash_password(&new_user.password),
+            ..new_user
+        };
+
+        diesel::insert_into(users).values(new_user).get_result::<User>(conn).map_err(SignupError::Database)
     }
 
-    pub fn login(login: LoginDTO, conn: &mut Connection) -> Option<LoginInfoDTO> {
-        if let Ok(user_to_verify) = users
+    /// Checks a password login, returning `None` for unknown, disabled or password-less accounts.
+    pub fn authenticate(login: &LoginDTO, conn: &mut Connection) -> Option<User> {
+        let user = users
             .filter(username.eq(&login.username_or_email))
             .or_filter(email.eq(&login.username_or_email))
             .get_result::<User>(conn)
-        {
-            if !user_to_verify.password.is_empty() && verify(&login.password, &user_to_verify.password).unwrap() {
-                if let Some(login_history) = LoginHistory::create(&user_to_verify.username, conn) {
-                    if LoginHistory::save_login_history(login_history, conn).is_err() {
-                        return None;
-                    }
-                    let login_session_str = User::generate_login_session();
-                    if User::update_login_session_to_db(&user_to_verify.username, &login_session_str, conn) {
-                        return Some(LoginInfoDTO {
-                            username: user_to_verify.username,
-                            login_session: login_session_str,
-                        });
-                    }
+            .ok()?;
+
+        match !user.disabled && !user.password.is_empty() && verify(&login.password, &user.password).unwrap_or(false) {
+            true => Some(user),
+            false => None,
+        }
+    }
+
+    pub fn create_session(user: &User, info: &SessionInfo, conn: &mut Connection) -> Option<LoginInfoDTO> {
+        match Session::create(user, info, conn) {
+            Ok(session) => {
+                if let Err(err) = LoginHistory::success(user.id, info, &session.id, conn) {
+                    tracing::error!(err = err.to_string(), "unable to save login history");
                 }
-            } else {
-                return Some(LoginInfoDTO {
-                    username: user_to_verify.username,
-                    login_session: String::new(),
-                });
+
+                Some(LoginInfoDTO {
+                    username: user.username.clone(),
+                    login_session: session.id,
+                })
+            }
+            Err(err) => {
+                tracing::error!(err = err.to_string(), "unable to create session");
+                None
             }
         }
+    }
 
-        None
+    pub fn provision(un: &str, em: &str, provider: &str, conn: &mut Connection) -> QueryResult<User> {
+        let new_user = UserDTO {
+            admin: false,
+            username: un.to_lowercase(),
+            email: em.to_lowercase(),
+            password: String::new(),
+            tokens: vec![],
+            services: vec![],
+            providers: vec![provider.to_string()],
+        };
+
+        diesel::insert_into(users).values(new_user).get_result::<User>(conn)
     }
 
-    pub fn logout(user_id: i32, conn: &mut Connection) {
-        if let Ok(user) = users.find(user_id).get_result::<User>(conn) {
-            Self::update_login_session_to_db(&user.username, "", conn);
+    /// An empty password is stored as-is so the account can only sign in through a provider.
+    fn hash_password(pw: &str) -> String {
+        match pw.is_empty() {
+            true => String::new(),
+            false => hash(pw, DEFAULT_COST).unwrap(),
         }
     }
 
-    pub fn is_valid_login_session(user_token: &UserToken, conn: &mut Connection) -> bool {
-        users
-            .filter(username.eq(&user_token.user))
-            .filter(login_session.eq(&user_token.login_session))
-            .get_result::<User>(conn)
-            .is_ok()
+    pub fn all(conn: &mut Connection) -> QueryResult<Vec<User>> { users.order(id.asc()).load::<User>(conn) }
+
+    pub fn find(user_id: i32, conn: &mut Connection) -> QueryResult<User> { users.find(user_id).get_result::<User>(conn) }
+
+    pub fn update(user_id: i32, changes: UserUpdateDTO, conn: &mut Connection) -> QueryResult<User> {
+        let changes = UserUpdateDTO {
+            username: changes.username.map(|un| un.to_lowercase()),
+            email: changes.email.map(|em| em.to_lowercase()),
+            password: changes.password.map(|pw| Self::hash_password(&pw)),
+            ..changes
+        };
+
+        let user = diesel::update(users.find(user_id)).set(&changes).get_result::<User>(conn)?;
+
+        if user.disabled {
+            Session::revoke_all(user.id, conn)?;
+        }
+
+        Ok(user)
     }
 
-    pub fn find_login_info_by_token(user_token: &UserToken, conn: &mut Connection) -> Result<LoginInfoDTO, String> {
-        let user_result = users.filter(username.eq(&user_token.user)).filter(login_session.eq(&user_token.login_session)).get_result::<User>(conn);
+    pub fn set_disabled(user_id: i32, state: bool, conn: &mut Connection) -> QueryResult<User> {
+        let changes = UserUpdateDTO {
+            disabled: Some(state),
+            ..Default::default()
+        };
 
-        if let Ok(user) = user_result {
-            return Ok(LoginInfoDTO {
-                username: user.username,
-                login_session: user.login_session,
-            });
+        Self::update(user_id, changes, conn)
+    }
+
+    /// Removes the user, their login history stays without them.
+    pub fn delete(user_id: i32, conn: &mut Connection) -> QueryResult<usize> { diesel::delete(users.find(user_id)).execute(conn) }
+
+    pub fn logout(user_token: &UserToken, everywhere: bool, conn: &mut Connection) -> QueryResult<usize> {
+        match everywhere {
+            true => Session::revoke_all(Self::find_user_by_token(user_token, conn)?.id, conn),
+            false => Session::revoke(&user_token.login_session, conn),
         }
+    }
+
+    pub fn is_valid_login_session(user_token: &UserToken, conn: &mut Connection) -> bool { Self::find_user_by_token(user_token, conn).is_ok() }
 
-        Err("User not found!".to_string())
+    pub fn find_user_by_token(user_token: &UserToken, conn: &mut Connection) -> QueryResult<User> {
+        let (user, session) = users
+            .inner_join(sessions::table)
+            .filter(username.eq(&user_token.user))
+            .filter(disabled.eq(false))
+            .filter(sessions::id.eq(&user_token.login_session))
+            .filter(sessions::expires_at.gt(Utc::now().naive_utc()))
+            .get_result::<(User, Session)>(conn)?;
+
+        session.touch(conn);
+        Ok(user)
     }
 
-    pub fn generate_login_session() -> String { Uuid::new_v4().to_string() }
+    pub fn can_access(&self, service: &str) -> bool { self.admin || self.services.iter().any(|name| name == service) }
+
+    pub fn find_login_info_by_token(user_token: &UserToken, conn: &mut Connection) -> Result<LoginInfoDTO, String> {
+        match Self::find_user_by_token(user_token, conn) {
+            Ok(user) => Ok(LoginInfoDTO {
+                username: user.username,
+                login_session: user_token.login_session.clone(),
+            }),
+            Err(_) => Err("User not found!".to_string()),
+        }
+    }
 
     pub fn find_user_by_username(un: &str, conn: &mut Connection) -> QueryResult<User> { users.filter(username.eq(un)).get_result::<User>(conn) }
 
-    pub fn update_login_session_to_db(un: &str, login_session_str: &str, conn: &mut Connection) -> bool {
-        if let Ok(user) = User::find_user_by_username(un, conn) {
-            diesel::update(users.find(user.id)).set(login_session.eq(login_session_str.to_string())).execute(conn).is_ok()
-        } else {
-            false
+    pub fn find_user_by_email(em: &str, conn: &mut Connection) -> QueryResult<User> { users.filter(email.eq(em)).get_result::<User>(conn) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::db::{self, Pool};
+
+    use diesel::r2d2::ConnectionManager;
+    use uuid::Uuid;
+
+    fn user(name: &str, mail: &str) -> UserDTO {
+        UserDTO {
+            admin: false,
+            username: name.to_string(),
+            email: mail.to_string(),
+            password: String::from("unused"),
+            tokens: vec![],
+            services: vec![],
+            providers: vec![String::from("basic")],
         }
     }
+
+    #[test]
+    fn signup_refuses_taken_usernames_and_emails() {
+        let Ok(url) = std::env::var("ZEROTRUST_TEST_DATABASE") else {
+            eprintln!("skipped, ZEROTRUST_TEST_DATABASE is not set");
+            return;
+        };
+
+        let pool = Pool::builder().max_size(1).build(ConnectionManager::new(url)).unwrap();
+        let conn = &mut pool.get().unwrap();
+        db::try_run_migrations(conn).unwrap();
+
+        let name = format!("signup-{}", Uuid::new_v4().simple());
+        let mail = format!("{name}@localhost");
+        let created = User::signup(user(&name, &mail), conn).unwrap();
+
+        assert!(matches!(User::signup(user(&name, "other@localhost"), conn), Err(SignupError::UsernameTaken(_))));
+        assert!(matches!(User::signup(user(&format!("{name}-2"), &mail), conn), Err(SignupError::EmailTaken(_))));
+        User::delete(created.id, conn).unwrap();
+    }
 }
@@ -0,0 +1,107 @@
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use diesel::{prelude::*, Associations, Identifiable, Insertable, Queryable};
+use serde::Serialize;
+
+use crate::{
+    config::db::Connection,
+    models::user::User,
+    schema::{
+        webauthn_ceremonies,
+        webauthn_credentials::{self, dsl::*},
+    },
+};
+
+#[derive(Clone, Debug, Identifiable, Associations, Queryable, Serialize)]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = webauthn_credentials)]
+pub struct WebauthnCredential {
+    pub id: String,
+    pub user_id: i32,
+    #[serde(skip_serializing)]
+    pub user_handle: String,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub passkey: String,
+    pub created_at: NaiveDateTime,
+    pub last_used: Option<NaiveDateTime>,
+}
+
+/// A registration or sign in between its start and finish, kept here so the
+/// browser only holds its id and each one can be finished once.
+#[derive(Insertable)]
+#[diesel(table_name = webauthn_ceremonies)]
+pub struct WebauthnCeremony {
+    pub id: String,
+    pub ceremony: String,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = webauthn_credentials)]
+pub struct WebauthnCredentialInsertableDTO {
+    pub id: String,
+    pub user_id: i32,
+    pub user_handle: String,
+    pub name: String,
+    pub passkey: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl WebauthnCredential {
+    pub fn create(record: WebauthnCredentialInsertableDTO, conn: &mut Connection) -> QueryResult<WebauthnCredential> {
+        diesel::insert_into(webauthn_credentials).values(&record).get_result::<WebauthnCredential>(conn)
+    }
+
+    pub fn find_by_user(uid: i32, conn: &mut Connection) -> QueryResult<Vec<WebauthnCredential>> {
+        webauthn_credentials.filter(user_id.eq(uid)).order(created_at.asc()).load::<WebauthnCredential>(conn)
+    }
+
+    pub fn find_by_handle(handle: &str, conn: &mut Connection) -> QueryResult<Vec<WebauthnCredential>> { webauthn_credentials.filter(user_handle.eq(handle)).load::<WebauthnCredential>(conn) }
+
+    /// Every credential of a user shares one handle, which is what the
+    /// authenticator hands back during a passwordless sign in.
+    pub fn user_handle(uid: i32, conn: &mut Connection) -> Option<String> { webauthn_credentials.filter(user_id.eq(uid)).select(user_handle).first::<String>(conn).ok() }
+
+    pub fn exists(uid: i32, conn: &mut Connection) -> bool { Self::user_handle(uid, conn).is_some() }
+
+    pub fn used(credential_id: &str, updated: Option<String>, conn: &mut Connection) -> QueryResult<usize> {
+        let target = webauthn_credentials.find(credential_id);
+        let now = Utc::now().naive_utc();
+
+        match updated {
+            Some(value) => diesel::update(target).set((passkey.eq(value), last_used.eq(now))).execute(conn),
+            None => diesel::update(target).set(last_used.eq(now)).execute(conn),
+        }
+    }
+
+    pub fn delete(credential_id: &str, uid: i32, conn: &mut Connection) -> QueryResult<usize> { diesel::delete(webauthn_credentials.find(credential_id).filter(user_id.eq(uid))).execute(conn) }
+}
+
+impl WebauthnCeremony {
+    /// Stores a ceremony for `ttl` seconds, dropping any that were never finished.
+    pub fn save(ceremony_id: &str, value: String, ttl: i64, conn: &mut Connection) -> QueryResult<usize> {
+        use crate::schema::webauthn_ceremonies::dsl;
+
+        let now = Utc::now().naive_utc();
+        diesel::delete(dsl::webauthn_ceremonies.filter(dsl::expires_at.le(now))).execute(conn)?;
+
+        let record = WebauthnCeremony {
+            id: ceremony_id.to_string(),
+            ceremony: value,
+            expires_at: now + TimeDelta::seconds(ttl),
+        };
+
+        diesel::insert_into(dsl::webauthn_ceremonies).values(&record).execute(conn)
+    }
+
+    /// Removes the ceremony and returns it, unless it expired. Two requests
+    /// racing to finish the same one cannot both get it.
+    pub fn take(ceremony_id: &str, conn: &mut Connection) -> QueryResult<Option<String>> {
+        use crate::schema::webauthn_ceremonies::dsl;
+
+        diesel::delete(dsl::webauthn_ceremonies.find(ceremony_id).filter(dsl::expires_at.gt(Utc::now().naive_utc())))
+            .returning(dsl::ceremony)
+            .get_result::<String>(conn)
+            .optional()
+    }
+}
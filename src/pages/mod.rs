@@ -1,5 +1,4 @@
 use crate::config::structs::Config;
-use macros_rs::fmtstr;
 use tera::{Context, Tera};
 
 #[derive(Clone)]
@@ -17,7 +16,7 @@ pub fn create_templates() -> TeraState {
     ])
     .unwrap();
 
-    return TeraState(tera);
+    TeraState(tera)
 }
 
 pub fn render(name: &str, tmpl: &Tera, ctx: &mut Context, config: &Config) -> String {
@@ -35,12 +34,12 @@ pub fn render(name: &str, tmpl: &Tera, ctx: &mut Context, config: &Config) -> St
         None => ctx.insert("app_icon", &config.settings.app.logo),
     }
 
-    tmpl.render(name, &ctx).unwrap_or_else(|err| {
+    tmpl.render(name, ctx).unwrap_or_else(|err| {
         ctx.insert("error_code", &404);
         ctx.insert("error_name", "Template not found");
-        ctx.insert("error_message", fmtstr!("The template {name} could not be found."));
+        ctx.insert("error_message", &format!("The template {name} could not be found."));
 
         tracing::error!("{err:?}");
-        tmpl.render("error", &ctx).unwrap()
+        tmpl.render("error", ctx).unwrap()
     })
 }
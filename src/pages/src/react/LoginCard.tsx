@@ -1,51 +1,112 @@
 import { atob } from 'Base64';
-import { Github, Twitter } from './buttons';
+import { Provider } from './buttons';
+import * as passkey from './webauthn';
 import { Transition } from '@headlessui/react';
 import { XCircleIcon } from '@heroicons/react/24/solid';
 import { useEffect, useState, Fragment, ChangeEvent } from 'react';
 
-const LoginCard = (props: { app }) => {
+// `redirect` is checked by the server, a local path or a URL on one of the backends,
+// `stepUp` is set when a password only session needs a second factor to continue
+const LoginCard = (props: { app; redirect: string; stepUp: string }) => {
 	const [loading, setLoading] = useState(false);
 	const params = new URLSearchParams(window.location.search);
 	const cleaned = new URLSearchParams(window.location.search);
 	const [loginFailed, setLoginFailed] = useState({ state: false, msg: '' });
 	const [loginForm, setLoginForm] = useState({ email: '', password: '', remember: false });
+	const [providers, setProviders] = useState<{ name: string; display_name: string }[]>([]);
+	const [challenge, setChallenge] = useState<{ second_factor: string; methods: string[]; ticket: string } | null>(null);
+	const [enrollment, setEnrollment] = useState<{ secret: string; uri: string } | null>(null);
+	const [recoveryCodes, setRecoveryCodes] = useState<string[] | null>(null);
+	const [code, setCode] = useState('');
 
 	const handleChange = (event: ChangeEvent<HTMLInputElement>) => {
 		const { name, value } = event.target;
 		setLoginForm((form) => ({ ...form, [name]: value }));
 	};
 
-	const submitDetails = (data) => {
-		setLoading(true);
+	const finishLogin = () => {
+		const target = new URL(props.redirect || '/', window.location.origin);
+		cleaned.delete('auth');
+		cleaned.delete('redirect');
+		cleaned.forEach((value, key) => target.searchParams.append(key, value));
 
-		fetch(`/${props.app.prefix}/api/login`, {
+		window.location.href = target.toString();
+	};
+
+	const showError = (msg: string) => {
+		setLoading(false);
+		setLoginFailed({ state: true, msg });
+		setTimeout(() => setLoginFailed((data) => ({ ...data, state: false })), 3500);
+	};
+
+	const post = (path: string, data) =>
+		fetch(`/${props.app.prefix}/api/${path}`, {
 			method: 'POST',
 			body: JSON.stringify(data),
 			headers: { 'Content-Type': 'application/json' }
-		})
+		});
+
+	const startSecondFactor = async (body: { second_factor: string; methods: string[]; ticket: string }) => {
+		if (body.second_factor === 'enroll') {
+			const response = await post('login/totp/enroll', { ticket: body.ticket });
+			if (response.status !== 200) return showError((await response.json()).message);
+			setEnrollment(await response.json());
+		}
+
+		setChallenge(body);
+		setLoading(false);
+	};
+
+	const submitDetails = (data) => {
+		setLoading(true);
+
+		post('login', data)
 			.then(async (response) => {
 				if (response.status === 200) {
-					cleaned.delete('auth');
-					cleaned.delete('redirect');
-
-					if (params.get('redirect')) {
-						window.location.href = `${params.get('redirect')}?` + cleaned.toString();
-					} else {
-						window.location.href = '/?' + cleaned.toString();
-					}
+					finishLogin();
+				} else if (response.status === 202) {
+					await startSecondFactor(await response.json());
 				} else {
-					const body = await response.json();
+					showError((await response.json()).message);
+				}
+			})
+			.catch(() => showError('Unknown error occured.'));
+	};
+
+	const submitCode = (event: any) => {
+		event.preventDefault();
+		setLoading(true);
+
+		post('login/totp', { ticket: challenge.ticket, code })
+			.then(async (response) => {
+				if (response.status !== 200) {
+					setCode('');
+					return showError((await response.json()).message);
+				}
+
+				if (response.headers.get('Content-Type')?.includes('application/json')) {
+					setRecoveryCodes((await response.json()).recovery_codes);
 					setLoading(false);
-					setLoginFailed({ state: true, msg: body.message });
-					setTimeout(() => setLoginFailed((data) => ({ ...data, state: false })), 3500);
+				} else {
+					finishLogin();
+				}
+			})
+			.catch(() => showError('Unknown error occured.'));
+	};
+
+	const signInWithPasskey = (ticket?: string) => {
+		setLoading(true);
+
+		passkey
+			.login(props.app.prefix, ticket ? { ticket } : { remember: loginForm.remember })
+			.then(async (response) => {
+				if (response.status === 200) {
+					finishLogin();
+				} else {
+					showError((await response.json()).message);
 				}
 			})
-			.catch(() => {
-				setLoading(false);
-				setLoginFailed({ state: true, msg: 'Unknown error occured.' });
-				setTimeout(() => setLoginFailed((data) => ({ ...data, state: false })), 3500);
-			});
+			.catch(() => showError('Passkey sign in was cancelled.'));
 	};
 
 	const handleSubmit = (event: any) => {
@@ -54,7 +115,23 @@ const LoginCard = (props: { app }) => {
 	};
 
 	useEffect(() => {
-		if (params.get('auth')) {
+		fetch(`/${props.app.prefix}/api/providers`)
+			.then((response) => response.json())
+			.then(setProviders)
+			.catch(() => setProviders([]));
+
+		if (props.stepUp === 'true') {
+			setLoading(true);
+			post('login/step-up', {})
+				.then(async (response) => {
+					if (response.status === 202) {
+						await startSecondFactor(await response.json());
+					} else {
+						showError((await response.json()).message);
+					}
+				})
+				.catch(() => showError('Unknown error occured.'));
+		} else if (params.get('auth')) {
 			const auth = JSON.parse(atob(params.get('auth')));
 			submitDetails(auth.remember ? auth : { ...auth, remember: false });
 		}
@@ -63,88 +140,174 @@ const LoginCard = (props: { app }) => {
 	return (
 		<Fragment>
 			<div className="mt-5">
-				<form className="space-y-6" onSubmit={handleSubmit}>
-					<div>
-						<label for="email" className="block text-sm font-medium leading-6 text-zinc-900">
-							Email address
-						</label>
-						<div className="mt-1">
-							<input
-								required
-								id="email"
-								name="email"
-								type="email"
-								disabled={loading}
-								value={loginForm.email}
-								onChange={handleChange}
-								placeholder="james@bond.com"
-								autoComplete="email"
-								className={`transition block w-full rounded-md border-0 py-1.5 text-zinc-900 shadow-sm ring-1 ring-inset ring-zinc-300 placeholder:text-zinc-400 focus:ring-2 focus:ring-inset focus:ring-${props.app.accent}-600 sm:text-sm sm:leading-6 disabled:opacity-70`}
-							/>
-						</div>
+				{recoveryCodes ? (
+					<div className="space-y-6">
+						<p className="text-sm text-zinc-700">
+							Two-factor authentication is enabled. Save these recovery codes somewhere safe, each one can be used once if you lose access to your authenticator.
+						</p>
+						<ul className="grid grid-cols-2 gap-2 rounded-md bg-zinc-50 p-4 font-mono text-sm text-zinc-900 ring-1 ring-inset ring-zinc-200">
+							{recoveryCodes.map((recovery) => (
+								<li key={recovery}>{recovery}</li>
+							))}
+						</ul>
+						<button
+							type="button"
+							onClick={finishLogin}
+							className={`transition flex w-full justify-center rounded-md bg-${props.app.accent}-600 px-3 py-1.5 text-sm font-semibold leading-6 text-white shadow-sm hover:bg-${props.app.accent}-500`}>
+							Continue
+						</button>
 					</div>
-					<div>
-						<label for="password" className="block text-sm font-medium leading-6 text-zinc-900">
-							Password
-						</label>
-						<div className="mt-1">
-							<input
-								required
-								id="password"
-								name="password"
-								type="password"
+				) : challenge ? (
+					<div className="space-y-6">
+						{challenge.methods.includes('webauthn') && (
+							<button
+								type="button"
 								disabled={loading}
-								value={loginForm.password}
-								onChange={handleChange}
-								placeholder="••••••••"
-								autoComplete="current-password"
-								className={`transition block w-full rounded-md border-0 py-1.5 text-zinc-900 shadow-sm ring-1 ring-inset ring-zinc-300 placeholder:text-zinc-400 focus:ring-2 focus:ring-inset focus:ring-${props.app.accent}-600 sm:text-sm sm:leading-6 disabled:opacity-70`}
-							/>
-						</div>
+								onClick={() => signInWithPasskey(challenge.ticket)}
+								className={`transition flex w-full justify-center rounded-md bg-${props.app.accent}-600 px-3 py-1.5 text-sm font-semibold leading-6 text-white shadow-sm hover:bg-${props.app.accent}-500 disabled:cursor-default disabled:opacity-80`}>
+								{loading ? 'Waiting for passkey...' : 'Use a passkey'}
+							</button>
+						)}
+						{challenge.second_factor === 'enroll' || challenge.methods.includes('totp') ? (
+							<form className="space-y-6" onSubmit={submitCode}>
+								{enrollment && (
+									<div className="space-y-2 text-sm text-zinc-700">
+										<p>
+											Two-factor authentication is required. Add this account to your{' '}
+											<a href={enrollment.uri} className={`font-semibold text-${props.app.accent}-600`}>
+												authenticator app
+											</a>{' '}
+											using the key below, then enter the code it shows.
+										</p>
+										<p className="break-all rounded-md bg-zinc-50 p-2 font-mono text-zinc-900 ring-1 ring-inset ring-zinc-200">{enrollment.secret}</p>
+									</div>
+								)}
+								<div>
+									<label for="code" className="block text-sm font-medium leading-6 text-zinc-900">
+										{enrollment ? 'Authentication code' : 'Authentication code or recovery code'}
+									</label>
+									<div className="mt-1">
+										<input
+											required
+											autoFocus
+											id="code"
+											name="code"
+											type="text"
+											inputMode={enrollment ? 'numeric' : 'text'}
+											disabled={loading}
+											value={code}
+											onChange={(event) => setCode(event.target.value)}
+											placeholder="123456"
+											autoComplete="one-time-code"
+											className={`transition block w-full rounded-md border-0 py-1.5 text-zinc-900 shadow-sm ring-1 ring-inset ring-zinc-300 placeholder:text-zinc-400 focus:ring-2 focus:ring-inset focus:ring-${props.app.accent}-600 sm:text-sm sm:leading-6 disabled:opacity-70`}
+										/>
+									</div>
+								</div>
+								<button
+									type="submit"
+									disabled={loading}
+									className={`transition flex w-full justify-center rounded-md bg-${props.app.accent}-600 px-3 py-1.5 text-sm font-semibold leading-6 text-white shadow-sm hover:bg-${props.app.accent}-500 focus-visible:outline focus-visible:outline-2 focus-visible:outline-offset-2 focus-visible:outline-${props.app.accent}-600 disabled:cursor-default disabled:bg-${props.app.accent}-500 disabled:opacity-80 disabled:hover:opacity-80`}>
+									{loading ? 'Verifying...' : 'Verify'}
+								</button>
+							</form>
+						) : null}
 					</div>
-
-					<div className="flex items-center justify-between">
-						<div className="flex items-center">
-							<input
-								id="remember"
-								name="remember"
-								type="checkbox"
-								disabled={loading}
-								checked={loginForm.remember}
-								onChange={(event) => setLoginForm((form) => ({ ...form, remember: !form.remember }))}
-								className={`transition h-4 w-4 rounded border-zinc-300 text-${props.app.accent}-600 focus:ring-${props.app.accent}-600 disabled:opacity-70`}
-							/>
-							<label for="remember" className="ml-3 block text-sm leading-6 text-zinc-900">
-								Remember me
+				) : (
+					<form className="space-y-6" onSubmit={handleSubmit}>
+						<div>
+							<label for="email" className="block text-sm font-medium leading-6 text-zinc-900">
+								Email address
 							</label>
+							<div className="mt-1">
+								<input
+									required
+									id="email"
+									name="email"
+									type="email"
+									disabled={loading}
+									value={loginForm.email}
+									onChange={handleChange}
+									placeholder="james@bond.com"
+									autoComplete="email"
+									className={`transition block w-full rounded-md border-0 py-1.5 text-zinc-900 shadow-sm ring-1 ring-inset ring-zinc-300 placeholder:text-zinc-400 focus:ring-2 focus:ring-inset focus:ring-${props.app.accent}-600 sm:text-sm sm:leading-6 disabled:opacity-70`}
+								/>
+							</div>
+						</div>
+						<div>
+							<label for="password" className="block text-sm font-medium leading-6 text-zinc-900">
+								Password
+							</label>
+							<div className="mt-1">
+								<input
+									required
+									id="password"
+									name="password"
+									type="password"
+									disabled={loading}
+									value={loginForm.password}
+									onChange={handleChange}
+									placeholder="••••••••"
+									autoComplete="current-password"
+									className={`transition block w-full rounded-md border-0 py-1.5 text-zinc-900 shadow-sm ring-1 ring-inset ring-zinc-300 placeholder:text-zinc-400 focus:ring-2 focus:ring-inset focus:ring-${props.app.accent}-600 sm:text-sm sm:leading-6 disabled:opacity-70`}
+								/>
+							</div>
 						</div>
-					</div>
 
-					<div>
-						<button
-							type="submit"
-							disabled={loading}
-							className={`transition flex w-full justify-center rounded-md bg-${props.app.accent}-600 px-3 py-1.5 text-sm font-semibold leading-6 text-white shadow-sm hover:bg-${props.app.accent}-500 focus-visible:outline focus-visible:outline-2 focus-visible:outline-offset-2 focus-visible:outline-${props.app.accent}-600 disabled:cursor-default disabled:bg-${props.app.accent}-500 disabled:opacity-80 disabled:hover:opacity-80`}>
-							{loading ? 'Logging in...' : 'Sign in'}
-						</button>
-					</div>
-				</form>
+						<div className="flex items-center justify-between">
+							<div className="flex items-center">
+								<input
+									id="remember"
+									name="remember"
+									type="checkbox"
+									disabled={loading}
+									checked={loginForm.remember}
+									onChange={(event) => setLoginForm((form) => ({ ...form, remember: !form.remember }))}
+									className={`transition h-4 w-4 rounded border-zinc-300 text-${props.app.accent}-600 focus:ring-${props.app.accent}-600 disabled:opacity-70`}
+								/>
+								<label for="remember" className="ml-3 block text-sm leading-6 text-zinc-900">
+									Remember me
+								</label>
+							</div>
+						</div>
 
-				<div>
-					<div className="relative mt-10">
-						<div className="absolute inset-0 flex items-center" aria-hidden="true">
-							<div className="w-full border-t border-zinc-200"></div>
+						<div>
+							<button
+								type="submit"
+								disabled={loading}
+								className={`transition flex w-full justify-center rounded-md bg-${props.app.accent}-600 px-3 py-1.5 text-sm font-semibold leading-6 text-white shadow-sm hover:bg-${props.app.accent}-500 focus-visible:outline focus-visible:outline-2 focus-visible:outline-offset-2 focus-visible:outline-${props.app.accent}-600 disabled:cursor-default disabled:bg-${props.app.accent}-500 disabled:opacity-80 disabled:hover:opacity-80`}>
+								{loading ? 'Logging in...' : 'Sign in'}
+							</button>
 						</div>
-						<div className="relative flex justify-center text-sm font-medium leading-6">
-							<span className="bg-white px-6 text-zinc-900">Or continue with</span>
+						{passkey.supported() && (
+							<button
+								type="button"
+								disabled={loading}
+								onClick={() => signInWithPasskey()}
+								className="transition flex w-full justify-center rounded-md bg-white px-3 py-1.5 text-sm font-semibold leading-6 text-zinc-900 shadow-sm ring-1 ring-inset ring-zinc-300 hover:bg-zinc-50 disabled:opacity-70">
+								Sign in with a passkey
+							</button>
+						)}
+					</form>
+				)}
+
+				{!challenge && providers.length > 0 && (
+					<div>
+						<div className="relative mt-10">
+							<div className="absolute inset-0 flex items-center" aria-hidden="true">
+								<div className="w-full border-t border-zinc-200"></div>
+							</div>
+							<div className="relative flex justify-center text-sm font-medium leading-6">
+								<span className="bg-white px-6 text-zinc-900">Or continue with</span>
+							</div>
 						</div>
-					</div>
 
-					<div className="mt-6 grid grid-cols-2 gap-4">
-						<Twitter />
-						<Github />
+						<div className="mt-6 grid grid-cols-2 gap-4">
+							{providers.map((provider) => (
+								<Provider key={provider.name} app={props.app} redirect={props.redirect} provider={provider} />
+							))}
+						</div>
 					</div>
-				</div>
+				)}
 			</div>
 			<div aria-live="assertive" className="pointer-events-none fixed inset-0 flex items-end px-4 py-6 sm:items-start sm:p-6">
 				<div className="flex w-full flex-col items-center space-y-4 sm:items-end">
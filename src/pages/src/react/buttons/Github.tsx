@@ -1,6 +1,6 @@
-export default () => (
+export default (props: { href?: string }) => (
 	<a
-		href="#"
+		href={props.href ?? '#'}
 		className="flex w-full items-center justify-center gap-3 rounded-md bg-[#24292F] px-3 py-1.5 text-white focus-visible:outline focus-visible:outline-2 focus-visible:outline-offset-2 focus-visible:outline-[#24292F]">
 		<svg className="h-5 w-5" fill="currentColor" viewBox="0 0 20 20" aria-hidden="true">
 			<path
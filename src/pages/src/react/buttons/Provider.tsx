@@ -0,0 +1,19 @@
+import Github from './Github';
+import Twitter from './Twitter';
+
+const branded = { github: Github, twitter: Twitter };
+
+export default (props: { app; redirect: string; provider: { name: string; display_name: string } }) => {
+	const href = `/${props.app.prefix}/oauth/${props.provider.name}?redirect=${encodeURIComponent(props.redirect || '/')}`;
+	const Branded = branded[props.provider.name];
+
+	if (Branded) return <Branded href={href} />;
+
+	return (
+		<a
+			href={href}
+			className="flex w-full items-center justify-center gap-3 rounded-md bg-white px-3 py-1.5 text-zinc-900 ring-1 ring-inset ring-zinc-300 hover:bg-zinc-50 focus-visible:outline focus-visible:outline-2 focus-visible:outline-offset-2">
+			<span className="text-sm font-semibold leading-6">{props.provider.display_name}</span>
+		</a>
+	);
+};
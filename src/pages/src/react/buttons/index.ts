@@ -1,2 +1,3 @@
 export { default as Github } from './Github';
 export { default as Twitter } from './Twitter';
+export { default as Provider } from './Provider';
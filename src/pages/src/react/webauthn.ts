@@ -0,0 +1,76 @@
+const toBuffer = (value: string) => Uint8Array.from(window.atob(value.replace(/-/g, '+').replace(/_/g, '/')), (c) => c.charCodeAt(0));
+
+const toBase64 = (buffer: ArrayBuffer | null) =>
+	buffer
+		? window
+				.btoa(String.fromCharCode(...new Uint8Array(buffer)))
+				.replace(/\+/g, '-')
+				.replace(/\//g, '_')
+				.replace(/=+$/, '')
+		: null;
+
+const post = (prefix: string, path: string, body) =>
+	fetch(`/${prefix}/api/webauthn/${path}`, {
+		method: 'POST',
+		body: JSON.stringify(body),
+		headers: { 'Content-Type': 'application/json' }
+	});
+
+export const supported = () => typeof window.PublicKeyCredential !== 'undefined';
+
+/** Signs in with a passkey; pass the ticket from `/api/login` to use it as a second factor. */
+export const login = async (prefix: string, body: { ticket?: string; remember?: boolean }) => {
+	const start = await post(prefix, 'login/start', body);
+	if (start.status !== 200) return start;
+
+	const { publicKey } = await start.json();
+	const credential = (await navigator.credentials.get({
+		publicKey: {
+			...publicKey,
+			challenge: toBuffer(publicKey.challenge),
+			allowCredentials: publicKey.allowCredentials.map((allowed) => ({ ...allowed, id: toBuffer(allowed.id) }))
+		}
+	})) as PublicKeyCredential;
+
+	const response = credential.response as AuthenticatorAssertionResponse;
+	return post(prefix, 'login/finish', {
+		id: credential.id,
+		rawId: toBase64(credential.rawId),
+		type: credential.type,
+		extensions: credential.getClientExtensionResults(),
+		response: {
+			authenticatorData: toBase64(response.authenticatorData),
+			clientDataJSON: toBase64(response.clientDataJSON),
+			signature: toBase64(response.signature),
+			userHandle: toBase64(response.userHandle)
+		}
+	});
+};
+
+/** Registers a new passkey for the signed in user. */
+export const register = async (prefix: string, name?: string) => {
+	const start = await post(prefix, 'register/start', { name });
+	if (start.status !== 200) return start;
+
+	const { publicKey } = await start.json();
+	const credential = (await navigator.credentials.create({
+		publicKey: {
+			...publicKey,
+			challenge: toBuffer(publicKey.challenge),
+			user: { ...publicKey.user, id: toBuffer(publicKey.user.id) },
+			excludeCredentials: (publicKey.excludeCredentials ?? []).map((excluded) => ({ ...excluded, id: toBuffer(excluded.id) }))
+		}
+	})) as PublicKeyCredential;
+
+	const response = credential.response as AuthenticatorAttestationResponse;
+	return post(prefix, 'register/finish', {
+		id: credential.id,
+		rawId: toBase64(credential.rawId),
+		type: credential.type,
+		extensions: credential.getClientExtensionResults(),
+		response: {
+			attestationObject: toBase64(response.attestationObject),
+			clientDataJSON: toBase64(response.clientDataJSON)
+		}
+	});
+};
@@ -1,8 +1,89 @@
+diesel::table! {
+    audit_events (id) {
+        id -> BigInt,
+        created_at -> Timestamp,
+        actor -> Nullable<Text>,
+        action -> Text,
+        target -> Nullable<Text>,
+        ip -> Nullable<Text>,
+        details -> Jsonb,
+        prev_hash -> Text,
+        hash -> Text,
+    }
+}
+
+diesel::table! {
+    lockouts (account) {
+        account -> Text,
+        failures -> Integer,
+        locked_until -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    login_failures (id) {
+        id -> Integer,
+        account -> Text,
+        ip -> Nullable<Text>,
+        cleared -> Bool,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     login_history (id) {
         id -> Integer,
-        user_id -> Integer,
+        user_id -> Nullable<Integer>,
         login_timestamp -> Timestamp,
+        success -> Bool,
+        reason -> Nullable<Text>,
+        ip -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        method -> Text,
+        session_id -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    rate_limits (service, key) {
+        service -> Text,
+        key -> Text,
+        tokens -> Double,
+        allowed -> Bool,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    services (name) {
+        name -> Text,
+        location -> Jsonb,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Text,
+        user_id -> Integer,
+        created_at -> Timestamp,
+        last_seen -> Timestamp,
+        expires_at -> Timestamp,
+        ip -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    user_totp (user_id) {
+        user_id -> Integer,
+        secret -> Text,
+        enabled -> Bool,
+        last_step -> BigInt,
+        recovery_codes -> Array<Text>,
+        created_at -> Timestamp,
     }
 }
 
@@ -16,9 +97,32 @@ diesel::table! {
         providers -> Array<Text>,
         services -> Array<Text>,
         tokens -> Array<Text>,
-        login_session -> Text,
+        disabled -> Bool,
+    }
+}
+
+diesel::table! {
+    webauthn_ceremonies (id) {
+        id -> Text,
+        ceremony -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    webauthn_credentials (id) {
+        id -> Text,
+        user_id -> Integer,
+        user_handle -> Text,
+        name -> Text,
+        passkey -> Text,
+        created_at -> Timestamp,
+        last_used -> Nullable<Timestamp>,
     }
 }
 
 diesel::joinable!(login_history -> users (user_id));
-diesel::allow_tables_to_appear_in_same_query!(login_history, users,);
+diesel::joinable!(sessions -> users (user_id));
+diesel::joinable!(user_totp -> users (user_id));
+diesel::joinable!(webauthn_credentials -> users (user_id));
+diesel::allow_tables_to_appear_in_same_query!(audit_events, lockouts, login_failures, login_history, rate_limits, services, sessions, user_totp, users, webauthn_ceremonies, webauthn_credentials,);